@@ -0,0 +1,445 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix::Addr;
+use actix_web::{web, web::Bytes, HttpRequest, HttpResponse};
+use futures::{stream, StreamExt as _};
+
+use crate::{
+    approvals::{ApprovalError, Approvals, Decide},
+    build_log::{BuildLogs, LogEvent},
+    deployments::Deployments,
+    freeze::{self, Freezes, Override, Submit},
+    http::{self, ApiConfig, Authorized, EventsAccess, Principal},
+    pins::{GetPin, Pin, PinError, PinTarget, Pins, UnpinTarget, Unpinned},
+    repos::{deserialize_opt_duration, ReposConfig},
+    runner::{BranchSpec, Queue, Reason, Target, TaskId},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("invalid task ID")]
+    InvalidTaskId,
+    #[error("build log not found")]
+    LogNotFound,
+    #[error("task not found or already finished")]
+    TaskNotFound,
+    #[error("failed to read build log")]
+    ReadError,
+    #[error("environment {0} isn't configured")]
+    EnvironmentNotFound(String),
+    #[error("nothing was deployed into {0} yet")]
+    NothingDeployed(String),
+    #[error("last deploy into {environment} didn't succeed: {status}")]
+    SourceFailed { environment: String, status: String },
+    #[error("failed to queue build task")]
+    SendError,
+    #[error(transparent)]
+    Approval(#[from] ApprovalError),
+    #[error("failed to override freeze")]
+    OverrideError,
+    #[error("exactly one of branch and environment must be given")]
+    InvalidTarget,
+    #[error(transparent)]
+    Pin(#[from] PinError),
+    #[error("API token is not specified")]
+    NoApiToken,
+}
+
+impl actix_web::ResponseError for ApiError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            ApiError::InvalidTaskId | ApiError::InvalidTarget => {
+                actix_web::http::StatusCode::BAD_REQUEST
+            },
+            ApiError::LogNotFound | ApiError::TaskNotFound | ApiError::EnvironmentNotFound(_) => {
+                actix_web::http::StatusCode::NOT_FOUND
+            },
+            ApiError::NothingDeployed(_) | ApiError::SourceFailed { .. } => {
+                actix_web::http::StatusCode::CONFLICT
+            },
+            ApiError::ReadError
+            | ApiError::SendError
+            | ApiError::OverrideError
+            | ApiError::NoApiToken => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Approval(err) => err.status_code(),
+            ApiError::Pin(err) => err.status_code(),
+        }
+    }
+}
+
+pub async fn build_log(
+    _: Authorized,
+    task_id: web::Path<String>,
+    logs: web::Data<Arc<BuildLogs>>,
+) -> Result<HttpResponse, ApiError> {
+    let task_id: TaskId = task_id.parse().map_err(|_| ApiError::InvalidTaskId)?;
+    let path = logs.path(task_id);
+
+    match web::block(move || std::fs::read(path)).await {
+        Ok(content) => Ok(HttpResponse::Ok()
+            .content_type("text/plain; charset=utf-8")
+            .body(content)),
+        Err(actix_web::error::BlockingError::Error(err))
+            if err.kind() == std::io::ErrorKind::NotFound =>
+        {
+            Err(ApiError::LogNotFound)
+        },
+        Err(err) => {
+            tracing::error!("Failed to read build log for task {}: {}", task_id, err);
+            Err(ApiError::ReadError)
+        },
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EventsToken {
+    token: String,
+    expires_at: u64,
+}
+
+/// Issues a short-lived token for [`build_events`] of one build, to be passed
+/// as the `token` query parameter.
+pub async fn events_token(
+    _: Authorized,
+    task_id: web::Path<String>,
+    req: HttpRequest,
+) -> Result<web::Json<EventsToken>, ApiError> {
+    let task_id: TaskId = task_id.parse().map_err(|_| ApiError::InvalidTaskId)?;
+    let expires_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+        + http::EVENTS_TOKEN_TTL.as_secs();
+    let token = req
+        .app_data::<ApiConfig>()
+        .and_then(|config| config.events_token(task_id, expires_at))
+        .ok_or(ApiError::NoApiToken)?;
+    Ok(web::Json(EventsToken { token, expires_at }))
+}
+
+fn sse_event(event: &LogEvent) -> Bytes {
+    let (name, data) = match event {
+        LogEvent::Line(line) => ("line", line.as_str()),
+        LogEvent::End(status) => ("end", status.as_str()),
+    };
+    let mut buf = format!("event: {name}\n");
+    for line in data.lines() {
+        buf.push_str("data: ");
+        buf.push_str(line);
+        buf.push('\n');
+    }
+    buf.push('\n');
+    Bytes::from(buf)
+}
+
+fn history_events(history: &[u8]) -> Vec<LogEvent> {
+    String::from_utf8_lossy(history)
+        .lines()
+        .map(|line| LogEvent::Line(line.to_owned()))
+        .collect()
+}
+
+/// Streams a build log as Server-Sent Events. For running builds the stream
+/// follows the log until the build finishes; for finished builds it just
+/// replays the log.
+pub async fn build_events(
+    _: EventsAccess,
+    task_id: web::Path<String>,
+    logs: web::Data<Arc<BuildLogs>>,
+) -> Result<HttpResponse, ApiError> {
+    let task_id: TaskId = task_id.parse().map_err(|_| ApiError::InvalidTaskId)?;
+
+    let logs = logs.get_ref().clone();
+    let subscription = web::block(move || logs.subscribe(task_id))
+        .await
+        .map_err(|err| {
+            tracing::error!("Failed to read build log for task {}: {}", task_id, err);
+            ApiError::ReadError
+        })?
+        .ok_or(ApiError::LogNotFound)?;
+
+    let events = stream::iter(history_events(&subscription.history))
+        .chain(subscription.events)
+        .map(|event| Ok::<_, actix_web::Error>(sse_event(&event)));
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(events))
+}
+
+/// Cancels a queued or running build.
+pub async fn cancel_build(
+    _: Authorized,
+    task_id: web::Path<String>,
+    queue: web::Data<Queue>,
+) -> Result<String, ApiError> {
+    let task_id: TaskId = task_id.parse().map_err(|_| ApiError::InvalidTaskId)?;
+    if queue.tasks().cancel(task_id) {
+        tracing::info!("Cancelled task {}", task_id);
+        Ok("OK".into())
+    } else {
+        Err(ApiError::TaskNotFound)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PromoteRequest {
+    pub from: String,
+    pub to: String,
+}
+
+/// Deploys the commit that's currently running in one environment into
+/// another one.
+pub async fn promote(
+    _: Authorized,
+    path: web::Path<(String, String)>,
+    request: web::Json<PromoteRequest>,
+    repos: web::Data<Arc<ReposConfig>>,
+    deployments: web::Data<Arc<Deployments>>,
+    pins: web::Data<Addr<Pins>>,
+) -> Result<String, ApiError> {
+    let (owner, repo) = path.into_inner();
+    let PromoteRequest { from, to } = request.into_inner();
+    let repo_config = repos.repo(&owner, &repo);
+    for name in [&from, &to] {
+        if repo_config.environment(name).is_none() {
+            return Err(ApiError::EnvironmentNotFound(name.clone()));
+        }
+    }
+
+    let source = deployments
+        .get(&Target::Environment {
+            owner: owner.clone(),
+            repo: repo.clone(),
+            name: from.clone(),
+        })
+        .ok_or_else(|| ApiError::NothingDeployed(from.clone()))?;
+    if !source.last_succeeded {
+        return Err(ApiError::SourceFailed {
+            environment: from,
+            status: source.last_status,
+        });
+    }
+    let deployed = source
+        .current
+        .ok_or_else(|| ApiError::NothingDeployed(from.clone()))?;
+
+    tracing::info!(
+        "Promoting {} of {}/{} from {} to {}",
+        deployed.commit_hash,
+        owner,
+        repo,
+        from,
+        to,
+    );
+    let target = Target::Environment {
+        owner,
+        repo,
+        name: to,
+    };
+    let task = deployed.task(&target, Reason::Promotion { from });
+    match pins.send(Submit(task)).await {
+        Ok(Ok(id)) => Ok(id.to_string()),
+        err => {
+            tracing::error!("Failed to send task: {:?}", err);
+            Err(ApiError::SendError)
+        },
+    }
+}
+
+async fn decide(
+    task_id: &str,
+    approver: Principal,
+    approve: bool,
+    approvals: &Addr<Approvals>,
+) -> Result<String, ApiError> {
+    let id: TaskId = task_id.parse().map_err(|_| ApiError::InvalidTaskId)?;
+    approvals
+        .send(Decide {
+            id,
+            approver,
+            approve,
+        })
+        .await
+        .map_err(|_| ApiError::SendError)??;
+    Ok("OK".into())
+}
+
+/// Queues a task that waits for approval.
+pub async fn approve(
+    Authorized(approver): Authorized,
+    task_id: web::Path<String>,
+    approvals: web::Data<Addr<Approvals>>,
+) -> Result<String, ApiError> {
+    decide(&task_id, approver, true, &approvals).await
+}
+
+/// Drops a task that waits for approval.
+pub async fn reject(
+    Authorized(approver): Authorized,
+    task_id: web::Path<String>,
+    approvals: web::Data<Addr<Approvals>>,
+) -> Result<String, ApiError> {
+    decide(&task_id, approver, false, &approvals).await
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OverrideRequest {
+    /// Freezes of branches that aren't deployed into an environment are
+    /// overridden if unset.
+    pub environment: Option<String>,
+    pub reason: String,
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub duration: Option<Duration>,
+}
+
+/// Lifts deploy freezes for an emergency. Every override is written to the
+/// audit log.
+pub async fn override_freeze(
+    Authorized(by): Authorized,
+    path: web::Path<(String, String)>,
+    request: web::Json<OverrideRequest>,
+    repos: web::Data<Arc<ReposConfig>>,
+    freezes: web::Data<Addr<Freezes>>,
+) -> Result<String, ApiError> {
+    let (owner, repo) = path.into_inner();
+    let OverrideRequest {
+        environment,
+        reason,
+        duration,
+    } = request.into_inner();
+    if let Some(name) = &environment {
+        if repos.repo(&owner, &repo).environment(name).is_none() {
+            return Err(ApiError::EnvironmentNotFound(name.clone()));
+        }
+    }
+
+    let released = freezes
+        .send(Override {
+            owner,
+            repo,
+            environment,
+            by: by.to_string(),
+            reason,
+            duration: duration.unwrap_or(freeze::DEFAULT_OVERRIDE_DURATION),
+        })
+        .await
+        .map_err(|_| ApiError::SendError)?
+        .map_err(|err| {
+            tracing::error!("Failed to override freeze: {:#}", err);
+            ApiError::OverrideError
+        })?;
+    Ok(format!("released {released} held deploys"))
+}
+
+/// Branch or environment of a repo, exactly one of which must be given.
+#[derive(Debug, serde::Deserialize)]
+pub struct TargetQuery {
+    pub branch: Option<String>,
+    pub environment: Option<String>,
+}
+
+impl TargetQuery {
+    fn resolve(self, owner: String, repo: String, repos: &ReposConfig) -> Result<Target, ApiError> {
+        match (self.branch, self.environment) {
+            (Some(branch), None) => Ok(Target::Branch(BranchSpec {
+                owner,
+                repo,
+                branch,
+            })),
+            (None, Some(name)) => {
+                if repos.repo(&owner, &repo).environment(&name).is_none() {
+                    return Err(ApiError::EnvironmentNotFound(name));
+                }
+                Ok(Target::Environment { owner, repo, name })
+            },
+            _ => Err(ApiError::InvalidTarget),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PinRequest {
+    #[serde(flatten)]
+    pub target: TargetQuery,
+    /// The currently deployed commit is pinned if not set.
+    pub commit: Option<String>,
+    pub reason: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub expires: Option<Duration>,
+}
+
+/// Keeps a branch or environment on one commit, deploying it if needed.
+/// Returns ID of the deploy task, or nothing if the commit already runs.
+pub async fn pin(
+    Authorized(by): Authorized,
+    path: web::Path<(String, String)>,
+    request: web::Json<PinRequest>,
+    repos: web::Data<Arc<ReposConfig>>,
+    pins: web::Data<Addr<Pins>>,
+) -> Result<String, ApiError> {
+    let (owner, repo) = path.into_inner();
+    let PinRequest {
+        target,
+        commit,
+        reason,
+        expires,
+    } = request.into_inner();
+    let target = target.resolve(owner, repo, &repos)?;
+    let task_id = pins
+        .send(PinTarget {
+            target,
+            commit,
+            reason,
+            by: by.to_string(),
+            expires,
+        })
+        .await
+        .map_err(|_| ApiError::SendError)??;
+    Ok(task_id.map(|id| id.to_string()).unwrap_or_default())
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct UnpinRequest {
+    #[serde(flatten)]
+    pub target: TargetQuery,
+    /// Whether to deploy the latest commit pushed while pinned.
+    #[serde(default)]
+    pub deploy: bool,
+}
+
+pub async fn unpin(
+    _: Authorized,
+    path: web::Path<(String, String)>,
+    request: web::Json<UnpinRequest>,
+    repos: web::Data<Arc<ReposConfig>>,
+    pins: web::Data<Addr<Pins>>,
+) -> Result<web::Json<Unpinned>, ApiError> {
+    let (owner, repo) = path.into_inner();
+    let UnpinRequest { target, deploy } = request.into_inner();
+    let target = target.resolve(owner, repo, &repos)?;
+    let unpinned = pins
+        .send(UnpinTarget { target, deploy })
+        .await
+        .map_err(|_| ApiError::SendError)??;
+    Ok(web::Json(unpinned))
+}
+
+pub async fn get_pin(
+    _: Authorized,
+    path: web::Path<(String, String)>,
+    query: web::Query<TargetQuery>,
+    repos: web::Data<Arc<ReposConfig>>,
+    pins: web::Data<Addr<Pins>>,
+) -> Result<web::Json<Pin>, ApiError> {
+    let (owner, repo) = path.into_inner();
+    let target = query.into_inner().resolve(owner, repo, &repos)?;
+    pins.send(GetPin(target.clone()))
+        .await
+        .map_err(|_| ApiError::SendError)?
+        .map(web::Json)
+        .ok_or_else(|| PinError::NotPinned(target).into())
+}
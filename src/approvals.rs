@@ -0,0 +1,311 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix::prelude::*;
+use color_eyre::eyre::{self, WrapErr as _};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    deployments,
+    http::Principal,
+    notifier::{Notification, Notifier, Status},
+    repos::{Protection, ReposConfig},
+    runner::{Queue, SubmitError, Task, TaskId},
+};
+
+const DEFAULT_APPROVAL_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApprovalError {
+    #[error("there's no pending approval for this task")]
+    NotFound,
+    #[error("{0} isn't allowed to approve this deploy")]
+    NotApprover(String),
+    #[error("failed to queue approved task: {0}")]
+    Submit(#[from] SubmitError),
+}
+
+impl actix_web::ResponseError for ApprovalError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            ApprovalError::NotFound => actix_web::http::StatusCode::NOT_FOUND,
+            ApprovalError::NotApprover(_) => actix_web::http::StatusCode::FORBIDDEN,
+            ApprovalError::Submit(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Pending {
+    task: Task,
+    approvers: Option<Vec<String>>,
+    /// Unix timestamp of when the request expires.
+    expires_at: u64,
+}
+
+impl Pending {
+    /// Whether `principal` may approve or reject the task. Only users can if
+    /// approvers are restricted, not the shared API token.
+    fn allows(&self, principal: &Principal) -> bool {
+        match (&self.approvers, principal) {
+            (None, _) => true,
+            (Some(approvers), Principal::User(name)) => approvers.contains(name),
+            (Some(_), Principal::Admin) => false,
+        }
+    }
+}
+
+/// Holds tasks for protected targets until they're approved, rejected or
+/// expire. Pending tasks are persisted in the state directory, so that
+/// they survive restarts.
+#[derive(Debug)]
+pub struct Approvals {
+    path: PathBuf,
+    queue: Queue,
+    notifier: Addr<Notifier>,
+    repos: Arc<ReposConfig>,
+    pending: HashMap<TaskId, Pending>,
+    expiry: HashMap<TaskId, SpawnHandle>,
+}
+
+impl Approvals {
+    pub fn load(
+        path: PathBuf,
+        queue: Queue,
+        notifier: Addr<Notifier>,
+        repos: Arc<ReposConfig>,
+    ) -> eyre::Result<Self> {
+        let pending = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice::<Vec<Pending>>(&content)
+                .wrap_err_with(|| format!("failed to parse {}", path.display()))?
+                .into_iter()
+                .map(|pending| (pending.task.id, pending))
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read {}", path.display()))
+            },
+        };
+        Ok(Self {
+            path,
+            queue,
+            notifier,
+            repos,
+            pending,
+            expiry: HashMap::new(),
+        })
+    }
+
+    fn save(&self) {
+        let pending: Vec<_> = self.pending.values().collect();
+        if let Err(err) = deployments::write_json(&self.path, &pending) {
+            tracing::error!("Failed to save pending approvals: {:#}", err);
+        }
+    }
+
+    fn notify(&self, task: Task, status: Status) {
+        let notification = Notification::new(task, status, &self.repos);
+        if let Err(err) = self.notifier.try_send(notification) {
+            tracing::error!("Failed to send notification: {}", err);
+        }
+    }
+
+    /// Drops the task once its request expires. Requests that expired while
+    /// adm wasn't running are dropped right away.
+    fn schedule_expiry(&mut self, id: TaskId, ctx: &mut Context<Self>) {
+        let Some(expires_at) = self.pending.get(&id).map(|pending| pending.expires_at) else {
+            return;
+        };
+        let wait = Duration::from_secs(expires_at.saturating_sub(now()));
+        let handle = ctx.run_later(wait, move |act, _ctx| {
+            act.expiry.remove(&id);
+            if let Some(pending) = act.pending.remove(&id) {
+                tracing::info!("Approval request for task {} expired", id);
+                act.save();
+                act.notify(pending.task, Status::ApprovalExpired);
+            }
+        });
+        self.expiry.insert(id, handle);
+    }
+}
+
+impl Actor for Approvals {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let ids: Vec<_> = self.pending.keys().copied().collect();
+        for id in ids {
+            self.schedule_expiry(id, ctx);
+        }
+    }
+}
+
+/// Submits the task, unless `protection` requires approving it first.
+pub fn submit(
+    queue: &Queue,
+    approvals: &Addr<Approvals>,
+    protection: &Protection,
+    task: Task,
+) -> Result<TaskId, SubmitError> {
+    if !protection.require_approval {
+        return queue.submit(task);
+    }
+    let id = task.id;
+    approvals
+        .try_send(RequestApproval {
+            task,
+            approvers: protection.approvers.clone(),
+            timeout: protection
+                .approval_timeout
+                .unwrap_or(DEFAULT_APPROVAL_TIMEOUT),
+        })
+        .map_err(|err| match err {
+            SendError::Full(_) => SubmitError::QueueFull,
+            SendError::Closed(_) => SubmitError::Closed,
+        })?;
+    Ok(id)
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "()")]
+pub struct RequestApproval {
+    pub task: Task,
+    pub approvers: Option<Vec<String>>,
+    pub timeout: Duration,
+}
+
+impl Handler<RequestApproval> for Approvals {
+    type Result = ();
+
+    fn handle(&mut self, msg: RequestApproval, ctx: &mut Self::Context) -> Self::Result {
+        let RequestApproval {
+            task,
+            approvers,
+            timeout,
+        } = msg;
+        let id = task.id;
+        tracing::info!(
+            "Task {} for {}/{} ({}) awaits approval",
+            id,
+            task.branch_spec.owner,
+            task.branch_spec.repo,
+            task.commit_hash,
+        );
+        self.notify(task.clone(), Status::PendingApproval(timeout));
+        self.pending.insert(id, Pending {
+            task,
+            approvers,
+            expires_at: now() + timeout.as_secs(),
+        });
+        self.save();
+        self.schedule_expiry(id, ctx);
+    }
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "Result<(), ApprovalError>")]
+pub struct Decide {
+    pub id: TaskId,
+    pub approver: Principal,
+    pub approve: bool,
+}
+
+impl Handler<Decide> for Approvals {
+    type Result = Result<(), ApprovalError>;
+
+    fn handle(&mut self, msg: Decide, ctx: &mut Self::Context) -> Self::Result {
+        let Decide {
+            id,
+            approver,
+            approve,
+        } = msg;
+        let pending = self.pending.get(&id).ok_or(ApprovalError::NotFound)?;
+        if !pending.allows(&approver) {
+            tracing::warn!(
+                "{} tried to decide on task {} without permission",
+                approver,
+                id
+            );
+            return Err(ApprovalError::NotApprover(approver.to_string()));
+        }
+
+        let Pending { mut task, .. } = self.pending.remove(&id).ok_or(ApprovalError::NotFound)?;
+        if let Some(handle) = self.expiry.remove(&id) {
+            ctx.cancel_future(handle);
+        }
+        self.save();
+        if approve {
+            tracing::info!("Task {} approved by {}", id, approver);
+            task.approved_by = Some(approver.to_string());
+            self.queue.submit(task)?;
+        } else {
+            tracing::info!("Task {} rejected by {}", id, approver);
+            self.notify(task, Status::Rejected(approver.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(approvers: Option<&[&str]>) -> Pending {
+        Pending {
+            task: serde_json::from_value(serde_json::json!({
+                "id": 42,
+                "branch_spec": {"owner": "me", "repo": "app", "branch": "master"},
+                "reference": "refs/heads/master",
+                "environment": "prod",
+                "sender": "alice",
+                "approved_by": null,
+                "commit_hash": "0123456789abcdef0123456789abcdef01234567",
+                "url": "",
+                "clone_url": "",
+                "reason": {"kind": "promotion", "from": "staging"},
+            }))
+            .unwrap(),
+            approvers: approvers.map(|names| names.iter().map(|&name| name.to_owned()).collect()),
+            expires_at: 0,
+        }
+    }
+
+    #[test]
+    fn approvers() {
+        let alice = Principal::User("alice".to_owned());
+        let bob = Principal::User("bob".to_owned());
+
+        assert!(pending(None).allows(&alice));
+        assert!(pending(None).allows(&Principal::Admin));
+
+        let restricted = pending(Some(&["alice"]));
+        assert!(restricted.allows(&alice));
+        assert!(!restricted.allows(&bob));
+        assert!(!restricted.allows(&Principal::Admin));
+    }
+
+    #[test]
+    fn pending_tasks_round_trip() {
+        let pending = pending(Some(&["alice"]));
+        let saved = serde_json::to_vec(&pending).unwrap();
+        let loaded: Pending = serde_json::from_slice(&saved).unwrap();
+        assert_eq!(loaded.task.id, pending.task.id);
+        assert_eq!(loaded.task.target(), pending.task.target());
+        assert!(matches!(
+            loaded.task.reason,
+            crate::runner::Reason::Promotion { ref from } if from == "staging"
+        ));
+        assert_eq!(loaded.approvers, pending.approvers);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use dashmap::DashMap;
+use futures::channel::mpsc;
+
+use crate::runner::TaskId;
+
+#[derive(Debug, Clone)]
+pub enum LogEvent {
+    Line(String),
+    End(String),
+}
+
+/// A live view of a build log: everything written so far plus a stream of
+/// further events.
+#[derive(Debug)]
+pub struct Subscription {
+    pub history: Vec<u8>,
+    pub events: mpsc::UnboundedReceiver<LogEvent>,
+}
+
+#[derive(Debug)]
+struct LiveLog {
+    file: File,
+    subscribers: Vec<mpsc::UnboundedSender<LogEvent>>,
+}
+
+impl LiveLog {
+    fn publish(&mut self, event: &LogEvent) {
+        self.subscribers
+            .retain(|tx| tx.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+#[derive(Debug)]
+pub struct BuildLogs {
+    dir: PathBuf,
+    retention: usize,
+    live: DashMap<TaskId, Arc<Mutex<LiveLog>>>,
+}
+
+impl BuildLogs {
+    pub fn new(dir: PathBuf, retention: usize) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            retention,
+            live: DashMap::new(),
+        })
+    }
+
+    pub fn path(&self, id: TaskId) -> PathBuf {
+        self.dir.join(format!("{id}.log"))
+    }
+
+    pub fn create(self: &Arc<Self>, id: TaskId) -> io::Result<BuildLog> {
+        let path = self.path(id);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let live = Arc::new(Mutex::new(LiveLog {
+            file,
+            subscribers: Vec::new(),
+        }));
+        self.live.insert(id, live.clone());
+        Ok(BuildLog {
+            id,
+            path,
+            live,
+            logs: self.clone(),
+        })
+    }
+
+    /// Subscribes to a build log. For finished builds the event stream
+    /// contains only the end event. Returns `Ok(None)` if there's no such log.
+    pub fn subscribe(&self, id: TaskId) -> io::Result<Option<Subscription>> {
+        let (tx, events) = mpsc::unbounded();
+        let live = self.live.get(&id).map(|live| live.clone());
+        if let Some(live) = live {
+            // Holding the lock while reading guarantees that no line is
+            // either lost or duplicated between the history and the stream.
+            let mut live = live.lock().unwrap();
+            let history = fs::read(self.path(id))?;
+            live.subscribers.push(tx);
+            return Ok(Some(Subscription { history, events }));
+        }
+
+        match fs::read(self.path(id)) {
+            Ok(history) => {
+                let _ = tx.unbounded_send(LogEvent::End("finished".into()));
+                Ok(Some(Subscription { history, events }))
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Removes the oldest logs, keeping at most `retention` of them.
+    pub fn prune(&self) -> io::Result<()> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension() == Some("log".as_ref()) {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<TaskId>().ok())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+
+        if ids.len() <= self.retention {
+            return Ok(());
+        }
+        ids.sort_unstable();
+        let excess = ids.len() - self.retention;
+        for id in ids.into_iter().take(excess) {
+            if self.live.contains_key(&id) {
+                continue;
+            }
+            let path = self.path(id);
+            if let Err(err) = fs::remove_file(&path) {
+                tracing::warn!("Failed to remove old build log {:?}: {}", path, err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct BuildLog {
+    id: TaskId,
+    path: PathBuf,
+    live: Arc<Mutex<LiveLog>>,
+    logs: Arc<BuildLogs>,
+}
+
+impl BuildLog {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, line: &str) {
+        let mut state = self.live.lock().unwrap();
+        if let Err(err) = writeln!(state.file, "{line}") {
+            tracing::warn!("Failed to write to build log {:?}: {}", self.path, err);
+        }
+        state.publish(&LogEvent::Line(line.to_owned()));
+    }
+
+    /// Writes a single line of adm's own output (as opposed to the output
+    /// of the commands it runs).
+    pub fn line(&self, line: &str) {
+        self.write(&format!("[adm] {line}"));
+    }
+
+    /// Writes a single line of a command's output.
+    pub fn output(&self, line: &str) {
+        self.write(line);
+    }
+
+    /// Notifies subscribers that the build is finished.
+    pub fn finish(self, status: String) {
+        self.live.lock().unwrap().publish(&LogEvent::End(status));
+    }
+}
+
+impl Drop for BuildLog {
+    fn drop(&mut self) {
+        self.logs.live.remove(&self.id);
+    }
+}
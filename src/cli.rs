@@ -0,0 +1,394 @@
+use std::{
+    io::{IsTerminal as _, Read as _, Write as _},
+    path::PathBuf,
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use secstr::SecUtf8;
+use structopt::StructOpt;
+
+use crate::{
+    pins,
+    secrets::{self, SecretStore, Secrets},
+};
+
+/// Automatic deployment manager.
+#[derive(Debug, StructOpt)]
+pub struct Opt {
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Runs the webhook server. This is the default.
+    Serve,
+    /// Deploys only repos that are polled, without running the webhook and
+    /// API server.
+    Poll,
+    /// Manages encrypted deploy secrets.
+    Secrets {
+        /// State directory of the server.
+        #[structopt(long, env = "ADM_STATE_DIR", default_value = "/var/lib/adm")]
+        state_dir: PathBuf,
+        #[structopt(subcommand)]
+        command: SecretsCommand,
+    },
+    /// Deploys the commit running in one environment into another one.
+    Promote {
+        #[structopt(flatten)]
+        server: Server,
+        /// Repository as `owner/repo`.
+        repo: String,
+        /// Environment the commit is taken from.
+        #[structopt(long)]
+        from: String,
+        /// Environment the commit is deployed into.
+        #[structopt(long)]
+        to: String,
+    },
+    /// Lifts deploy freezes for an emergency and releases held deploys.
+    OverrideFreeze(OverrideFreeze),
+    /// Keeps a branch or environment on one commit. Pushes are recorded but
+    /// not deployed until it's unpinned.
+    Pin(Pin),
+    /// Lifts a pin, offering to deploy the latest commit pushed meanwhile.
+    Unpin(Unpin),
+}
+
+/// Branch or environment of a repo.
+#[derive(Debug, StructOpt)]
+pub struct PinnedTarget {
+    /// Repository as `owner/repo`.
+    repo: String,
+    #[structopt(long, required_unless = "env", conflicts_with = "env")]
+    branch: Option<String>,
+    #[structopt(long)]
+    env: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Pin {
+    #[structopt(flatten)]
+    server: Server,
+    #[structopt(flatten)]
+    target: PinnedTarget,
+    /// Commit to pin, the currently deployed one if not set.
+    #[structopt(long)]
+    commit: Option<String>,
+    #[structopt(long)]
+    reason: Option<String>,
+    /// How long the pin lasts, e.g. `6h`. Pins last until lifted if not set.
+    #[structopt(long)]
+    expires: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Unpin {
+    #[structopt(flatten)]
+    server: Server,
+    #[structopt(flatten)]
+    target: PinnedTarget,
+    /// Deploy the latest commit pushed while pinned without asking.
+    #[structopt(long, conflicts_with = "no-deploy")]
+    deploy: bool,
+    /// Don't deploy the latest commit pushed while pinned.
+    #[structopt(long)]
+    no_deploy: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct OverrideFreeze {
+    #[structopt(flatten)]
+    server: Server,
+    /// Repository as `owner/repo`.
+    repo: String,
+    /// Environment whose freezes are lifted. Freezes of plain branches are
+    /// lifted if not set.
+    #[structopt(long)]
+    env: Option<String>,
+    /// Why the freeze is overridden, recorded in the audit log.
+    #[structopt(long)]
+    reason: String,
+    /// How long the override lasts, e.g. `30m`.
+    #[structopt(long)]
+    duration: Option<String>,
+}
+
+/// How to reach a running adm server.
+#[derive(Debug, StructOpt)]
+pub struct Server {
+    /// Base URL of the server.
+    #[structopt(long, env = "ADM_URL", default_value = "http://127.0.0.1:4677")]
+    url: String,
+    /// Either the shared API token or your own one, which freeze overrides
+    /// are attributed to.
+    #[structopt(long, env = "ADM_API_TOKEN", hide_env_values = true)]
+    token: String,
+}
+
+impl Server {
+    /// Sends a JSON request to the API and returns the response body.
+    async fn post(&self, path: &str, body: &serde_json::Value) -> eyre::Result<String> {
+        let client = awc::Client::new();
+        self.send(client.post(self.url(path)), Some(body)).await
+    }
+
+    async fn get<Q: serde::Serialize>(&self, path: &str, query: &Q) -> eyre::Result<String> {
+        let request = awc::Client::new()
+            .get(self.url(path))
+            .query(query)
+            .wrap_err("failed to encode query")?;
+        self.send(request, None).await
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+
+    async fn send(
+        &self,
+        request: awc::ClientRequest,
+        body: Option<&serde_json::Value>,
+    ) -> eyre::Result<String> {
+        let url = request.get_uri().to_string();
+        let request = request.bearer_auth(&self.token);
+        let mut resp = match body {
+            Some(body) => request.send_json(body),
+            None => request.send(),
+        }
+        .await
+        .map_err(|err| eyre::eyre!("failed to send request to {}: {}", url, err))?;
+        let body = resp
+            .body()
+            .await
+            .map_err(|err| eyre::eyre!("failed to read response from {}: {}", url, err))?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+        eyre::ensure!(
+            resp.status().is_success(),
+            "server returned {}: {}",
+            resp.status(),
+            body,
+        );
+        Ok(body)
+    }
+}
+
+fn split_repo(repo: &str) -> eyre::Result<(&str, &str)> {
+    match repo.split_once('/') {
+        Some((owner, repo)) if !owner.is_empty() && !repo.is_empty() => Ok((owner, repo)),
+        _ => eyre::bail!("repository must be specified as `owner/repo`"),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum SecretsCommand {
+    /// Lists names of the secrets.
+    List(SecretsTarget),
+    /// Adds a new secret, reading its value from stdin.
+    Add(SecretName),
+    /// Replaces the value of an existing secret, reading it from stdin.
+    Rotate(SecretName),
+    /// Removes a secret.
+    Remove(SecretName),
+    /// Re-encrypts all secrets with a newly generated key.
+    RotateKey,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SecretsTarget {
+    /// Repository as `owner/repo`.
+    repo: String,
+    /// Environment the secrets are specific to. Secrets without an
+    /// environment apply to the whole repo.
+    #[structopt(long)]
+    env: Option<String>,
+}
+
+impl SecretsTarget {
+    fn split(&self) -> eyre::Result<(&str, &str)> {
+        split_repo(&self.repo)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SecretName {
+    #[structopt(flatten)]
+    target: SecretsTarget,
+    /// Name of the environment variable the secret is passed in.
+    name: String,
+}
+
+fn read_value() -> eyre::Result<SecUtf8> {
+    let mut value = String::new();
+    std::io::stdin()
+        .read_to_string(&mut value)
+        .wrap_err("failed to read secret value from stdin")?;
+    let len = value.trim_end_matches(&['\r', '\n'][..]).len();
+    value.truncate(len);
+    Ok(SecUtf8::from(value))
+}
+
+fn update_secrets<F>(store: &SecretStore, secret: &SecretName, update: F) -> eyre::Result<()>
+where
+    F: FnOnce(&mut Secrets, &str) -> eyre::Result<()>,
+{
+    secrets::validate_name(&secret.name)?;
+    let (owner, repo) = secret.target.split()?;
+    let environment = secret.target.env.as_deref();
+    let mut secrets = store.load(owner, repo, environment)?;
+    update(&mut secrets, &secret.name)?;
+    store.save(owner, repo, environment, &secrets)
+}
+
+pub fn secrets(store: &SecretStore, command: SecretsCommand) -> eyre::Result<()> {
+    match command {
+        SecretsCommand::List(target) => {
+            let (owner, repo) = target.split()?;
+            for name in store.load(owner, repo, target.env.as_deref())?.keys() {
+                println!("{name}");
+            }
+            Ok(())
+        },
+        SecretsCommand::Add(secret) => update_secrets(store, &secret, |secrets, name| {
+            eyre::ensure!(
+                !secrets.contains_key(name),
+                "secret {} already exists, use `rotate` to replace it",
+                name,
+            );
+            secrets.insert(name.to_owned(), read_value()?);
+            Ok(())
+        }),
+        SecretsCommand::Rotate(secret) => update_secrets(store, &secret, |secrets, name| {
+            eyre::ensure!(secrets.contains_key(name), "there's no secret {}", name);
+            secrets.insert(name.to_owned(), read_value()?);
+            Ok(())
+        }),
+        SecretsCommand::Remove(secret) => update_secrets(store, &secret, |secrets, name| {
+            eyre::ensure!(secrets.remove(name).is_some(), "there's no secret {}", name);
+            Ok(())
+        }),
+        SecretsCommand::RotateKey => {
+            let count = store.rotate_key()?;
+            println!("Re-encrypted {count} secret files with a new key");
+            Ok(())
+        },
+    }
+}
+
+pub async fn promote(server: &Server, repo: &str, from: &str, to: &str) -> eyre::Result<()> {
+    let (owner, repo) = split_repo(repo)?;
+    let id = server
+        .post(
+            &format!("/repos/{owner}/{repo}/promote"),
+            &serde_json::json!({ "from": from, "to": to }),
+        )
+        .await?;
+    println!("Queued task {id}");
+    Ok(())
+}
+
+pub async fn override_freeze(args: OverrideFreeze) -> eyre::Result<()> {
+    let (owner, repo) = split_repo(&args.repo)?;
+    let response = args
+        .server
+        .post(
+            &format!("/repos/{owner}/{repo}/freeze-override"),
+            &serde_json::json!({
+                "environment": args.env,
+                "reason": args.reason,
+                "duration": args.duration,
+            }),
+        )
+        .await?;
+    println!("Freeze overridden, {response}");
+    Ok(())
+}
+
+pub async fn pin(args: Pin) -> eyre::Result<()> {
+    let (owner, repo) = split_repo(&args.target.repo)?;
+    let id = args
+        .server
+        .post(
+            &format!("/repos/{owner}/{repo}/pin"),
+            &serde_json::json!({
+                "branch": args.target.branch,
+                "environment": args.target.env,
+                "commit": args.commit,
+                "reason": args.reason,
+                "expires": args.expires,
+            }),
+        )
+        .await?;
+    if id.is_empty() {
+        println!("Pinned, the commit is already deployed");
+    } else {
+        println!("Pinned, queued task {id} to deploy the commit");
+    }
+    Ok(())
+}
+
+/// Asks whether to deploy the pending commit if there's anyone to ask.
+fn confirm_deploy(pin: &pins::Pin) -> eyre::Result<bool> {
+    let Some(pending) = &pin.pending else {
+        return Ok(false);
+    };
+    if !std::io::stdin().is_terminal() {
+        println!(
+            "Not deploying commit {} pushed while pinned, pass --deploy to deploy it",
+            pending.commit_hash,
+        );
+        return Ok(false);
+    }
+    print!(
+        "Commit {} was pushed by {} while pinned. Deploy it? [y/N] ",
+        pending.commit_hash,
+        pending.sender.as_deref().unwrap_or("unknown"),
+    );
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .read_line(&mut answer)
+        .wrap_err("failed to read answer")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+pub async fn unpin(args: Unpin) -> eyre::Result<()> {
+    let (owner, repo) = split_repo(&args.target.repo)?;
+    let deploy = if args.deploy || args.no_deploy {
+        args.deploy
+    } else {
+        let pin = args
+            .server
+            .get(&format!("/repos/{owner}/{repo}/pin"), &[
+                ("branch", &args.target.branch),
+                ("environment", &args.target.env),
+            ])
+            .await?;
+        confirm_deploy(&serde_json::from_str(&pin).wrap_err("failed to parse pin")?)?
+    };
+
+    let unpinned = args
+        .server
+        .post(
+            &format!("/repos/{owner}/{repo}/unpin"),
+            &serde_json::json!({
+                "branch": args.target.branch,
+                "environment": args.target.env,
+                "deploy": deploy,
+            }),
+        )
+        .await?;
+    let unpinned: pins::Unpinned =
+        serde_json::from_str(&unpinned).wrap_err("failed to parse response")?;
+    match (unpinned.pending, unpinned.task_id) {
+        (_, Some(id)) => {
+            println!("Unpinned, queued task {id} to deploy the pending commit");
+        },
+        (Some(commit), None) => {
+            println!("Unpinned, pending commit {commit} wasn't deployed");
+        },
+        _ => println!("Unpinned, nothing was pushed meanwhile"),
+    }
+    Ok(())
+}
@@ -8,12 +8,23 @@ pub struct Config {
     #[serde(default = "default_port")]
     pub port: u16,
     pub repo_root: std::path::PathBuf,
-    #[serde(deserialize_with = "deserialize_secutf8")]
-    pub webhook_secret: SecUtf8,
+    pub repos_file: Option<std::path::PathBuf>,
+    #[serde(default = "default_state_dir")]
+    pub state_dir: std::path::PathBuf,
+    #[serde(default = "default_log_retention")]
+    pub log_retention: usize,
+    /// Only required when serving webhooks, `adm poll` doesn't.
+    #[serde(default, deserialize_with = "deserialize_opt_secutf8")]
+    pub webhook_secret: Option<SecUtf8>,
     #[serde(deserialize_with = "deserialize_opt_secutf8")]
     pub telegram_token: Option<SecUtf8>,
     pub telegram_groups: Option<Vec<i64>>,
     pub parallel_builds: u8,
+    #[serde(default, deserialize_with = "deserialize_opt_secutf8")]
+    pub api_token: Option<SecUtf8>,
+    /// Comma-separated names of variables passed to deploy commands.
+    #[serde(default)]
+    pub pass_env: Vec<String>,
 }
 
 fn default_host() -> String {
@@ -24,14 +35,22 @@ fn default_port() -> u16 {
     4677
 }
 
-fn deserialize_secutf8<'de, D>(de: D) -> Result<SecUtf8, D::Error>
+fn default_state_dir() -> std::path::PathBuf {
+    "/var/lib/adm".into()
+}
+
+fn default_log_retention() -> usize {
+    100
+}
+
+pub fn deserialize_secutf8<'de, D>(de: D) -> Result<SecUtf8, D::Error>
 where
     D: Deserializer<'de>,
 {
     String::deserialize(de).map(SecUtf8::from)
 }
 
-fn deserialize_opt_secutf8<'de, D>(de: D) -> Result<Option<SecUtf8>, D::Error>
+pub fn deserialize_opt_secutf8<'de, D>(de: D) -> Result<Option<SecUtf8>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -0,0 +1,310 @@
+//! Cron expressions in the classic five-field format: minute, hour, day of
+//! month, month and day of week.
+//!
+//! Every field is `*` or a comma-separated list of values and `a-b` ranges,
+//! each optionally followed by `/step`. Months and days of week may also be
+//! given by their three-letter English names, and both 0 and 7 are Sunday.
+//! As in cron, if both day fields are restricted, a time matches if either
+//! of them does. Times are matched in the server's local timezone.
+
+use std::{fmt, str::FromStr};
+
+use chrono::{Datelike as _, Duration, NaiveDateTime, Timelike as _};
+
+/// Upper bound on how far searches for matching times go, so that
+/// expressions like `0 0 30 2 *` that never match can't loop forever.
+const SEARCH_LIMIT_MINUTES: i64 = 60 * 24 * 366 * 4;
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("invalid cron expression {expression:?}: {reason}")]
+pub struct ParseError {
+    expression: String,
+    reason: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    /// Bit `n` is set if value `n` matches.
+    bits: u64,
+    /// Whether the field is `*`, which matters for the day fields.
+    any: bool,
+}
+
+impl Field {
+    fn contains(self, value: u32) -> bool {
+        self.bits & (1 << value) != 0
+    }
+
+    fn parse(field: &str, min: u32, max: u32, names: &[&str]) -> Result<Self, String> {
+        let value = |s: &str| -> Result<u32, String> {
+            let lower = s.to_ascii_lowercase();
+            // Names start at the minimum value: `jan` is 1, `sun` is 0.
+            let value = match names.iter().zip(min..).find(|(name, _)| **name == lower) {
+                Some((_, value)) => value,
+                None => s.parse().map_err(|_| format!("{s:?} isn't a number"))?,
+            };
+            if value < min || value > max {
+                return Err(format!("{value} isn't in range {min}-{max}"));
+            }
+            Ok(value)
+        };
+
+        let mut bits = 0;
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (
+                    range,
+                    step.parse::<u32>()
+                        .ok()
+                        .filter(|step| *step > 0)
+                        .ok_or_else(|| format!("invalid step {step:?}"))?,
+                ),
+                None => (part, 1),
+            };
+            let (start, end) = match range.split_once('-') {
+                _ if range == "*" => (min, max),
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `5/15` means every 15 starting from 5.
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            };
+            if start > end {
+                return Err(format!("range {range:?} is empty"));
+            }
+            for value in (start..=end).step_by(step as usize) {
+                bits |= 1 << value;
+            }
+        }
+        Ok(Self {
+            bits,
+            any: field == "*",
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Schedule {
+    expression: String,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl fmt::Debug for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Schedule").field(&self.expression).finish()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason: String| ParseError {
+            expression: s.to_owned(),
+            reason,
+        };
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(error(format!("expected 5 fields, got {}", fields.len())));
+        };
+        let mut weekdays = Field::parse(weekdays, 0, 7, WEEKDAYS).map_err(error)?;
+        // 7 is another name for Sunday.
+        if weekdays.contains(7) {
+            weekdays.bits |= 1;
+        }
+        Ok(Self {
+            expression: s.to_owned(),
+            minutes: Field::parse(minutes, 0, 59, &[]).map_err(error)?,
+            hours: Field::parse(hours, 0, 23, &[]).map_err(error)?,
+            days: Field::parse(days, 1, 31, &[]).map_err(error)?,
+            months: Field::parse(months, 1, 12, MONTHS).map_err(error)?,
+            weekdays,
+        })
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Schedule {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(de)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Schedule {
+    /// Whether the minute that `time` falls into matches.
+    pub fn matches(&self, time: NaiveDateTime) -> bool {
+        let day = self.days.contains(time.day());
+        let weekday = self
+            .weekdays
+            .contains(time.weekday().num_days_from_sunday());
+        let day_matches = match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        day_matches
+            && self.minutes.contains(time.minute())
+            && self.hours.contains(time.hour())
+            && self.months.contains(time.month())
+    }
+
+    /// First matching minute strictly after `time`.
+    pub fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = truncate(time);
+        (1..=SEARCH_LIMIT_MINUTES)
+            .map(|minutes| start + Duration::minutes(minutes))
+            .find(|&time| self.matches(time))
+    }
+
+    /// Last matching minute at or before `time`, looking back no further
+    /// than `limit`.
+    pub fn last_before(&self, time: NaiveDateTime, limit: Duration) -> Option<NaiveDateTime> {
+        let start = truncate(time);
+        (0..=limit.num_minutes().min(SEARCH_LIMIT_MINUTES))
+            .map(|minutes| start - Duration::minutes(minutes))
+            .find(|&time| self.matches(time))
+    }
+
+    /// First minute after `time` that doesn't match. Schedules like
+    /// `* * * * *` never stop matching, then the end of the search range is
+    /// returned.
+    pub fn end_of_match(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let start = truncate(time);
+        (1..=SEARCH_LIMIT_MINUTES)
+            .map(|minutes| start + Duration::minutes(minutes))
+            .find(|&time| !self.matches(time))
+            .unwrap_or_else(|| start + Duration::minutes(SEARCH_LIMIT_MINUTES))
+    }
+}
+
+fn truncate(time: NaiveDateTime) -> NaiveDateTime {
+    time.date().and_hms(time.hour(), time.minute(), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn values(field: Field, max: u32) -> Vec<u32> {
+        (0..=max).filter(|&value| field.contains(value)).collect()
+    }
+
+    fn parse(field: &str, min: u32, max: u32, names: &[&str]) -> Vec<u32> {
+        values(Field::parse(field, min, max, names).unwrap(), max)
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(parse("*", 0, 5, &[]), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(parse("1,3-4", 0, 5, &[]), [1, 3, 4]);
+        assert!(Field::parse("*", 0, 5, &[]).unwrap().any);
+        assert!(!Field::parse("0-5", 0, 5, &[]).unwrap().any);
+    }
+
+    #[test]
+    fn steps() {
+        assert_eq!(parse("*/15", 0, 59, &[]), [0, 15, 30, 45]);
+        assert_eq!(parse("10-30/10", 0, 59, &[]), [10, 20, 30]);
+        // `5/15` means every 15 starting from 5.
+        assert_eq!(parse("5/15", 0, 59, &[]), [5, 20, 35, 50]);
+        assert_eq!(parse("5/1", 0, 59, &[]), [5]);
+        assert!(Field::parse("*/0", 0, 59, &[]).is_err());
+        assert!(Field::parse("*/x", 0, 59, &[]).is_err());
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(parse("jan,Mar-may", 1, 12, MONTHS), [1, 3, 4, 5]);
+        assert_eq!(parse("sun,FRI", 0, 7, WEEKDAYS), [0, 5]);
+        assert_eq!(parse("mon-fri/2", 0, 7, WEEKDAYS), [1, 3, 5]);
+        assert!(Field::parse("foo", 1, 12, MONTHS).is_err());
+        assert!(Field::parse("mon", 0, 59, &[]).is_err());
+    }
+
+    #[test]
+    fn invalid_fields() {
+        assert!(Field::parse("5-1", 0, 59, &[]).is_err());
+        assert!(Field::parse("60", 0, 59, &[]).is_err());
+        assert!(Field::parse("0", 1, 31, &[]).is_err());
+        assert!(Field::parse("", 0, 59, &[]).is_err());
+        assert!(Field::parse("1,", 0, 59, &[]).is_err());
+        assert!("* * * *".parse::<Schedule>().is_err());
+        assert!("* * * * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn sunday_is_0_and_7() {
+        let sunday = at("2024-03-03 12:00");
+        let monday = at("2024-03-04 12:00");
+        for expression in ["* * * * 0", "* * * * 7", "* * * * sun", "* * * * 5-7"] {
+            let schedule: Schedule = expression.parse().unwrap();
+            assert!(schedule.matches(sunday), "{}", expression);
+            assert!(!schedule.matches(monday), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn day_of_month_or_week() {
+        // Both restricted: either matches.
+        let schedule: Schedule = "0 12 13 * fri".parse().unwrap();
+        assert!(schedule.matches(at("2024-03-13 12:00")));
+        assert!(schedule.matches(at("2024-03-15 12:00")));
+        assert!(!schedule.matches(at("2024-03-16 12:00")));
+        assert!(!schedule.matches(at("2024-03-15 12:01")));
+
+        // Only one restricted: it has to match.
+        let schedule: Schedule = "0 12 13 * *".parse().unwrap();
+        assert!(schedule.matches(at("2024-03-13 12:00")));
+        assert!(!schedule.matches(at("2024-03-15 12:00")));
+        let schedule: Schedule = "0 12 * * fri".parse().unwrap();
+        assert!(!schedule.matches(at("2024-03-13 12:00")));
+        assert!(schedule.matches(at("2024-03-15 12:00")));
+    }
+
+    #[test]
+    fn searches() {
+        let schedule: Schedule = "30 9 * * mon-fri".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at("2024-03-01 09:30")),
+            Some(at("2024-03-04 09:30"))
+        );
+        assert_eq!(
+            schedule.last_before(at("2024-03-04 09:29"), Duration::days(7)),
+            Some(at("2024-03-01 09:30"))
+        );
+        assert_eq!(
+            schedule.last_before(at("2024-03-04 09:29"), Duration::days(1)),
+            None
+        );
+
+        let schedule: Schedule = "* 17-18 * * *".parse().unwrap();
+        assert_eq!(
+            schedule.end_of_match(at("2024-03-01 17:15")),
+            at("2024-03-01 19:00")
+        );
+
+        let never: Schedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(never.next_after(at("2024-03-01 00:00")), None);
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    notifier::Status,
+    runner::{BranchSpec, Reason, Target, Task, TaskId},
+};
+
+/// A commit that was deployed into a target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deployed {
+    pub task_id: TaskId,
+    pub branch: String,
+    pub reference: String,
+    pub commit_hash: String,
+    pub url: String,
+    pub clone_url: String,
+    #[serde(default)]
+    pub sender: Option<String>,
+    /// Unix timestamp of when the deploy finished.
+    pub finished_at: u64,
+}
+
+impl Deployed {
+    pub fn new(task: &Task) -> Self {
+        Self {
+            task_id: task.id,
+            branch: task.branch_spec.branch.clone(),
+            reference: task.reference.clone(),
+            commit_hash: task.commit_hash.clone(),
+            url: task.url.clone(),
+            clone_url: task.clone_url.clone(),
+            sender: task.sender.clone(),
+            finished_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        }
+    }
+
+    /// New task that deploys the same commit into `target`.
+    pub fn task(&self, target: &Target, reason: Reason) -> Task {
+        Task {
+            id: TaskId::generate(),
+            branch_spec: BranchSpec {
+                owner: target.owner().to_owned(),
+                repo: target.repo().to_owned(),
+                branch: self.branch.clone(),
+            },
+            reference: self.reference.clone(),
+            environment: target.environment().map(str::to_owned),
+            sender: self.sender.clone(),
+            approved_by: None,
+            reason,
+            url: self.url.clone(),
+            clone_url: self.clone_url.clone(),
+            commit_hash: self.commit_hash.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deployment {
+    /// Last successfully deployed commit, which is what's running now.
+    pub current: Option<Deployed>,
+    /// Last attempted deploy, successful or not.
+    pub last: Deployed,
+    pub last_status: String,
+    pub last_succeeded: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    target: Target,
+    #[serde(flatten)]
+    deployment: Deployment,
+}
+
+/// Outcomes of the last deploys into every target, persisted in the state
+/// directory so that they survive restarts.
+#[derive(Debug)]
+pub struct Deployments {
+    path: PathBuf,
+    state: Mutex<HashMap<Target, Deployment>>,
+}
+
+impl Deployments {
+    pub fn load(path: PathBuf) -> eyre::Result<Self> {
+        let state = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice::<Vec<Record>>(&content)
+                .wrap_err_with(|| format!("failed to parse {}", path.display()))?
+                .into_iter()
+                .map(|record| (record.target, record.deployment))
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read {}", path.display()))
+            },
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn get(&self, target: &Target) -> Option<Deployment> {
+        self.state.lock().unwrap().get(target).cloned()
+    }
+
+    /// Records the outcome of a finished task.
+    pub fn record(&self, task: &Task, status: &Status) -> eyre::Result<()> {
+        let deployed = Deployed::new(task);
+        let succeeded = matches!(status, Status::Success);
+        let mut state = self.state.lock().unwrap();
+        let current = state
+            .get(&task.target())
+            .and_then(|deployment| deployment.current.clone());
+        state.insert(task.target(), Deployment {
+            current: if succeeded {
+                Some(deployed.clone())
+            } else {
+                current
+            },
+            last: deployed,
+            last_status: status.to_string(),
+            last_succeeded: succeeded,
+        });
+        self.save(&state)
+    }
+
+    fn save(&self, state: &HashMap<Target, Deployment>) -> eyre::Result<()> {
+        let records: Vec<_> = state
+            .iter()
+            .map(|(target, deployment)| Record {
+                target: target.clone(),
+                deployment: deployment.clone(),
+            })
+            .collect();
+        write_json(&self.path, &records)
+    }
+}
+
+/// Written next to the file and moved into place, so that a crash never
+/// leaves a truncated file behind.
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> eyre::Result<()> {
+    let content = serde_json::to_vec_pretty(value)?;
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, content).wrap_err_with(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .wrap_err_with(|| format!("failed to move {} to {}", tmp.display(), path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(name: &str) -> Target {
+        Target::Environment {
+            owner: "me".to_owned(),
+            repo: "app".to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn task(environment: &str, commit_hash: &str) -> Task {
+        Task {
+            id: TaskId::generate(),
+            branch_spec: BranchSpec {
+                owner: "me".to_owned(),
+                repo: "app".to_owned(),
+                branch: "master".to_owned(),
+            },
+            reference: "refs/heads/master".to_owned(),
+            environment: Some(environment.to_owned()),
+            sender: Some("alice".to_owned()),
+            approved_by: None,
+            commit_hash: commit_hash.to_owned(),
+            url: "https://github.com/me/app".to_owned(),
+            clone_url: "https://github.com/me/app.git".to_owned(),
+            reason: Reason::Push,
+        }
+    }
+
+    #[test]
+    fn failures_keep_the_running_commit() {
+        let path =
+            std::env::temp_dir().join(format!("adm-test-deployments-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let deployments = Deployments::load(path.clone()).unwrap();
+        assert!(deployments.get(&environment("staging")).is_none());
+
+        let good = task("staging", "good");
+        deployments.record(&good, &Status::Success).unwrap();
+        deployments
+            .record(&task("staging", "bad"), &Status::Fail(eyre::eyre!("boom")))
+            .unwrap();
+
+        // Survives a restart.
+        let deployments = Deployments::load(path.clone()).unwrap();
+        let staging = deployments.get(&environment("staging")).unwrap();
+        assert_eq!(staging.current.unwrap().task_id, good.id);
+        assert_eq!(staging.last.commit_hash, "bad");
+        assert_eq!(
+            staging.last_status,
+            Status::Fail(eyre::eyre!("boom")).to_string()
+        );
+        assert!(!staging.last_succeeded);
+        assert!(deployments.get(&environment("prod")).is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn promotion_deploys_the_same_commit() {
+        let staging = task("staging", "0123456789abcdef0123456789abcdef01234567");
+        let deployed = Deployed::new(&staging);
+        let prod = environment("prod");
+        let promoted = deployed.task(&prod, Reason::Promotion {
+            from: "staging".to_owned(),
+        });
+        assert_ne!(promoted.id, staging.id);
+        assert_eq!(promoted.target(), prod);
+        assert_eq!(promoted.commit_hash, staging.commit_hash);
+        assert_eq!(promoted.reference, staging.reference);
+        assert_eq!(promoted.branch_spec, staging.branch_spec);
+        assert_eq!(promoted.clone_url, staging.clone_url);
+        assert_eq!(promoted.sender.as_deref(), Some("alice"));
+        assert!(promoted.reason.is_explicit());
+        assert_eq!(promoted.reason.to_string(), "promotion");
+    }
+}
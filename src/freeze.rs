@@ -0,0 +1,483 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::Write as _,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix::prelude::*;
+use chrono::NaiveDateTime;
+use color_eyre::eyre::{self, WrapErr as _};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    approvals::{self, Approvals},
+    deployments,
+    notifier::{Notification, Notifier, Status},
+    repos::{Freeze, FreezeAction, ReposConfig},
+    runner::{Queue, SubmitError, Target, Task, TaskId},
+};
+
+pub const DEFAULT_OVERRIDE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Returns when the freeze ends if it's active at `now`.
+fn frozen_until(freeze: &Freeze, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    let schedule = &freeze.schedule;
+    let Some(duration) = freeze.duration else {
+        return schedule.matches(now).then(|| schedule.end_of_match(now));
+    };
+    let duration = chrono::Duration::from_std(duration).ok()?;
+    let mut until = schedule.last_before(now, duration)? + duration;
+    if until <= now {
+        return None;
+    }
+    // Windows that start before this one ends extend it.
+    let mut from = now;
+    while let Some(next) = schedule.next_after(from).filter(|next| *next < until) {
+        until = until.max(next + duration);
+        from = next;
+    }
+    Some(until)
+}
+
+/// Freeze that currently applies to a deploy.
+#[derive(Debug)]
+struct ActiveFreeze {
+    action: FreezeAction,
+    until: NaiveDateTime,
+    reason: Option<String>,
+}
+
+/// Combines all active freezes: pushes are dropped if any of them says so,
+/// otherwise they're held until the last one ends.
+fn active_freeze<'a>(
+    freezes: impl Iterator<Item = &'a Freeze>,
+    now: NaiveDateTime,
+) -> Option<ActiveFreeze> {
+    freezes
+        .filter_map(|freeze| {
+            frozen_until(freeze, now).map(|until| ActiveFreeze {
+                action: freeze.action,
+                until,
+                reason: freeze.reason.clone(),
+            })
+        })
+        .reduce(|a, b| match (a.action, b.action) {
+            (FreezeAction::Drop, FreezeAction::Hold) => a,
+            (FreezeAction::Hold, FreezeAction::Drop) => b,
+            _ if b.until > a.until => b,
+            _ => a,
+        })
+}
+
+/// Deploys of a repo, or of one of its environments, that a freeze
+/// override applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct OverrideKey {
+    owner: String,
+    repo: String,
+    environment: Option<String>,
+}
+
+impl OverrideKey {
+    fn of(task: &Task) -> Self {
+        Self {
+            owner: task.branch_spec.owner.clone(),
+            repo: task.branch_spec.repo.clone(),
+            environment: task.environment.clone(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Held {
+    task: Task,
+    /// Unix timestamp of when the freeze ends and the task is submitted
+    /// again.
+    release_at: u64,
+}
+
+/// Holds or drops deploys during freeze windows. Only the latest push into
+/// every target is held, since older ones would be superseded anyway. Held
+/// tasks are persisted in the state directory, so that they survive restarts.
+#[derive(Debug)]
+pub struct Freezes {
+    path: PathBuf,
+    queue: Queue,
+    approvals: Addr<Approvals>,
+    notifier: Addr<Notifier>,
+    repos: Arc<ReposConfig>,
+    audit_log: PathBuf,
+    held: HashMap<Target, Held>,
+    releases: HashMap<Target, SpawnHandle>,
+    overrides: HashMap<OverrideKey, Instant>,
+}
+
+impl Freezes {
+    pub fn load(
+        path: PathBuf,
+        queue: Queue,
+        approvals: Addr<Approvals>,
+        notifier: Addr<Notifier>,
+        repos: Arc<ReposConfig>,
+        audit_log: PathBuf,
+    ) -> eyre::Result<Self> {
+        let held = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice::<Vec<Held>>(&content)
+                .wrap_err_with(|| format!("failed to parse {}", path.display()))?
+                .into_iter()
+                .map(|held| (held.task.target(), held))
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read {}", path.display()))
+            },
+        };
+        Ok(Self {
+            path,
+            queue,
+            approvals,
+            notifier,
+            repos,
+            audit_log,
+            held,
+            releases: HashMap::new(),
+            overrides: HashMap::new(),
+        })
+    }
+
+    fn save(&self) {
+        let held: Vec<_> = self.held.values().collect();
+        if let Err(err) = deployments::write_json(&self.path, &held) {
+            tracing::error!("Failed to save held deploys: {:#}", err);
+        }
+    }
+
+    fn notify(&self, task: Task, status: Status) {
+        let notification = Notification::new(task, status, &self.repos);
+        if let Err(err) = self.notifier.try_send(notification) {
+            tracing::error!("Failed to send notification: {}", err);
+        }
+    }
+
+    /// Passes the task on to approval and the runners.
+    fn release(&self, task: Task) -> Result<TaskId, SubmitError> {
+        let repo_config = self.repos.get(&task.branch_spec);
+        let protection = repo_config.protection(task.environment.as_deref());
+        approvals::submit(&self.queue, &self.approvals, protection, task)
+    }
+
+    fn is_overridden(&mut self, key: &OverrideKey) -> bool {
+        match self.overrides.get(key) {
+            Some(until) if Instant::now() < *until => true,
+            Some(_) => {
+                self.overrides.remove(key);
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// Submits the held task again once its freeze ends. Tasks whose freeze
+    /// ended while adm wasn't running are submitted right away.
+    fn schedule_release(&mut self, target: Target, ctx: &mut Context<Self>) {
+        let Some(release_at) = self.held.get(&target).map(|held| held.release_at) else {
+            return;
+        };
+        let wait = Duration::from_secs(release_at.saturating_sub(now()));
+        let handle = ctx.run_later(wait, {
+            let target = target.clone();
+            move |act, ctx| {
+                act.releases.remove(&target);
+                if let Some(held) = act.held.remove(&target) {
+                    act.save();
+                    // Another freeze may have started in the meantime.
+                    if let Err(err) = act.submit(held.task, ctx) {
+                        tracing::error!("Failed to release held task: {:?}", err);
+                    }
+                }
+            }
+        });
+        if let Some(previous) = self.releases.insert(target, handle) {
+            ctx.cancel_future(previous);
+        }
+    }
+
+    fn submit(&mut self, task: Task, ctx: &mut Context<Self>) -> Result<TaskId, SubmitError> {
+        let repo_config = self.repos.get(&task.branch_spec);
+        let now = chrono::Local::now().naive_local();
+        let freeze = active_freeze(repo_config.freezes(task.environment.as_deref()), now);
+        let freeze = match freeze {
+            Some(freeze) if !self.is_overridden(&OverrideKey::of(&task)) => freeze,
+            _ => return self.release(task),
+        };
+
+        let id = task.id;
+        match freeze.action {
+            FreezeAction::Drop => {
+                tracing::info!("Dropping task {}: deploys are frozen", id);
+                self.notify(task, Status::Dropped(freeze.reason));
+            },
+            FreezeAction::Hold => {
+                tracing::info!("Holding task {} until {}", id, freeze.until);
+                let target = task.target();
+                let wait = (freeze.until - now).to_std().unwrap_or_default();
+                // Rounded up, so that the task isn't released before the
+                // freeze ends.
+                let release_at = self::now() + wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                self.notify(task.clone(), Status::Held {
+                    until: freeze.until,
+                    reason: freeze.reason,
+                });
+                let commit_hash = task.commit_hash.clone();
+                let previous = self.held.insert(target.clone(), Held { task, release_at });
+                self.save();
+                self.schedule_release(target, ctx);
+                if let Some(previous) = previous {
+                    tracing::info!(
+                        "Task {} is superseded by {} and won't be deployed",
+                        previous.task.id,
+                        id,
+                    );
+                    self.notify(previous.task, Status::Superseded(commit_hash));
+                }
+            },
+        }
+        Ok(id)
+    }
+
+    /// Appends an entry to the audit log, which is never truncated.
+    fn audit(&self, entry: &serde_json::Value) -> eyre::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.audit_log)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .wrap_err_with(|| format!("failed to write audit log {}", self.audit_log.display()))
+    }
+}
+
+impl Actor for Freezes {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let targets: Vec<_> = self.held.keys().cloned().collect();
+        for target in targets {
+            self.schedule_release(target, ctx);
+        }
+    }
+}
+
+/// Submits a task, holding or dropping it if deploys are frozen.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<TaskId, SubmitError>")]
+pub struct Submit(pub Task);
+
+impl Handler<Submit> for Freezes {
+    type Result = Result<TaskId, SubmitError>;
+
+    fn handle(&mut self, Submit(task): Submit, ctx: &mut Self::Context) -> Self::Result {
+        self.submit(task, ctx)
+    }
+}
+
+/// Lifts freezes of a repo or one of its environments for a while and
+/// releases held deploys.
+#[derive(Debug, Message)]
+#[rtype(result = "eyre::Result<usize>")]
+pub struct Override {
+    pub owner: String,
+    pub repo: String,
+    pub environment: Option<String>,
+    /// Whoever made the request, recorded in the audit log.
+    pub by: String,
+    pub reason: String,
+    pub duration: Duration,
+}
+
+impl Handler<Override> for Freezes {
+    type Result = eyre::Result<usize>;
+
+    fn handle(&mut self, msg: Override, ctx: &mut Self::Context) -> Self::Result {
+        let Override {
+            owner,
+            repo,
+            environment,
+            by,
+            reason,
+            duration,
+        } = msg;
+        tracing::warn!(
+            "Freeze of {}/{} ({}) overridden by {} for {}: {}",
+            owner,
+            repo,
+            environment.as_deref().unwrap_or("branches"),
+            by,
+            humantime::format_duration(duration),
+            reason,
+        );
+        self.audit(&serde_json::json!({
+            "time": chrono::Local::now().to_rfc3339(),
+            "action": "freeze-override",
+            "repo": format!("{}/{}", owner, repo),
+            "environment": &environment,
+            "by": &by,
+            "reason": &reason,
+            "duration": humantime::format_duration(duration).to_string(),
+        }))?;
+
+        let key = OverrideKey {
+            owner,
+            repo,
+            environment,
+        };
+        self.overrides
+            .insert(key.clone(), Instant::now() + duration);
+        let targets: Vec<_> = self
+            .held
+            .iter()
+            .filter(|(_, held)| OverrideKey::of(&held.task) == key)
+            .map(|(target, _)| target.clone())
+            .collect();
+        let mut released = 0;
+        for target in targets {
+            if let Some(handle) = self.releases.remove(&target) {
+                ctx.cancel_future(handle);
+            }
+            if let Some(Held { task, .. }) = self.held.remove(&target) {
+                self.save();
+                self.notify(task.clone(), Status::FreezeOverridden {
+                    by: by.clone(),
+                    reason: reason.clone(),
+                });
+                self.release(task)?;
+                released += 1;
+            }
+        }
+        Ok(released)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn freeze(config: &str) -> Freeze {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn freeze_without_duration_lasts_while_schedule_matches() {
+        let freeze = freeze(r#"schedule = "* 17-18 * * fri""#);
+        assert_eq!(
+            frozen_until(&freeze, at("2024-03-15 17:30")),
+            Some(at("2024-03-15 19:00"))
+        );
+        assert_eq!(frozen_until(&freeze, at("2024-03-15 19:00")), None);
+        assert_eq!(frozen_until(&freeze, at("2024-03-14 17:30")), None);
+    }
+
+    #[test]
+    fn freeze_with_duration() {
+        let freeze = freeze(
+            r#"
+            schedule = "0 12 * * *"
+            duration = "30m"
+            "#,
+        );
+        assert_eq!(frozen_until(&freeze, at("2024-03-15 11:59")), None);
+        assert_eq!(
+            frozen_until(&freeze, at("2024-03-15 12:00")),
+            Some(at("2024-03-15 12:30"))
+        );
+        assert_eq!(
+            frozen_until(&freeze, at("2024-03-15 12:29")),
+            Some(at("2024-03-15 12:30"))
+        );
+        assert_eq!(frozen_until(&freeze, at("2024-03-15 12:30")), None);
+    }
+
+    #[test]
+    fn overlapping_windows_are_merged() {
+        // Every window starts before the previous one ends.
+        let overlapping = freeze(
+            r#"
+            schedule = "0,20,40 9 * * *"
+            duration = "30m"
+            "#,
+        );
+        assert_eq!(
+            frozen_until(&overlapping, at("2024-03-15 09:05")),
+            Some(at("2024-03-15 10:10"))
+        );
+        assert_eq!(
+            frozen_until(&overlapping, at("2024-03-15 10:00")),
+            Some(at("2024-03-15 10:10"))
+        );
+        assert_eq!(frozen_until(&overlapping, at("2024-03-15 10:10")), None);
+
+        // Windows that only touch aren't merged.
+        let touching = freeze(
+            r#"
+            schedule = "0,30 9 * * *"
+            duration = "30m"
+            "#,
+        );
+        assert_eq!(
+            frozen_until(&touching, at("2024-03-15 09:10")),
+            Some(at("2024-03-15 09:30"))
+        );
+    }
+
+    #[test]
+    fn drop_wins_over_hold() {
+        let freezes = [
+            freeze(
+                r#"
+                schedule = "0 9 * * *"
+                duration = "2h"
+                reason = "long hold"
+                "#,
+            ),
+            freeze(
+                r#"
+                schedule = "0 9 * * *"
+                duration = "1h"
+                action = "drop"
+                reason = "short drop"
+                "#,
+            ),
+            freeze(
+                r#"
+                schedule = "0 9 * * *"
+                duration = "3h"
+                reason = "longer hold"
+                "#,
+            ),
+        ];
+        let active = active_freeze(freezes.iter(), at("2024-03-15 09:30")).unwrap();
+        assert_eq!(active.action, FreezeAction::Drop);
+        assert_eq!(active.until, at("2024-03-15 10:00"));
+
+        let active = active_freeze(freezes.iter(), at("2024-03-15 10:30")).unwrap();
+        assert_eq!(active.action, FreezeAction::Hold);
+        assert_eq!(active.until, at("2024-03-15 12:00"));
+        assert_eq!(active.reason.as_deref(), Some("longer hold"));
+
+        assert!(active_freeze(freezes.iter(), at("2024-03-15 12:00")).is_none());
+    }
+}
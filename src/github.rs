@@ -6,16 +6,16 @@ pub struct User {
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Repository {
     pub name: String,
-    pub full_name: String,
     pub owner: User,
     pub url: String,
+    pub ssh_url: String,
+    pub clone_url: String,
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct PushEvent {
     #[serde(rename = "ref")]
     pub reference: String,
-    pub before: String,
     pub after: String,
     pub repository: Repository,
     pub sender: User,
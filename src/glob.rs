@@ -0,0 +1,82 @@
+//! Minimal glob matching for branch names and paths.
+//!
+//! `*` matches any sequence of characters except `/`, `**` matches any
+//! sequence including `/`, and `?` matches a single character other than
+//! `/`. Everything else is matched literally.
+
+pub fn matches(pattern: &str, text: &str) -> bool {
+    matches_bytes(pattern.as_bytes(), text.as_bytes())
+}
+
+fn matches_bytes(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => {
+            // `a/**/b` also matches `a/b`.
+            let rest_after_slash = rest.strip_prefix(b"/").unwrap_or(rest);
+            matches_bytes(rest_after_slash, text)
+                || (0..=text.len()).any(|skip| matches_bytes(rest, &text[skip..]))
+        },
+        [b'*', rest @ ..] => {
+            let segment = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=segment).any(|skip| matches_bytes(rest, &text[skip..]))
+        },
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => matches_bytes(rest, text),
+            _ => false,
+        },
+        [p, rest @ ..] => match text {
+            [c, text @ ..] if c == p => matches_bytes(rest, text),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literals() {
+        assert!(matches("master", "master"));
+        assert!(!matches("master", "master2"));
+        assert!(!matches("master", "maste"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn single_star_stays_in_segment() {
+        assert!(matches("release/*", "release/1.0"));
+        assert!(matches("release/*", "release/"));
+        assert!(!matches("release/*", "release/1.0/hotfix"));
+        assert!(matches("*.md", "README.md"));
+        assert!(!matches("*.md", "docs/README.md"));
+        assert!(matches("v*.*", "v1.2"));
+        assert!(matches("*", "master"));
+        assert!(!matches("*", "feature/x"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert!(matches("**", "a/b/c"));
+        assert!(matches("docs/**", "docs/a/b.md"));
+        assert!(matches("**/*.md", "docs/a/b.md"));
+        assert!(matches("**/*.md", "README.md"));
+        assert!(matches("services/**/Dockerfile", "services/Dockerfile"));
+        assert!(matches(
+            "services/**/Dockerfile",
+            "services/api/v2/Dockerfile"
+        ));
+        assert!(!matches("services/**/Dockerfile", "other/api/Dockerfile"));
+        assert!(!matches("docs/**", "documentation/a.md"));
+    }
+
+    #[test]
+    fn question_mark() {
+        assert!(matches("v?", "v1"));
+        assert!(!matches("v?", "v"));
+        assert!(!matches("v?", "v12"));
+        assert!(!matches("a?b", "a/b"));
+    }
+}
@@ -1,17 +1,25 @@
+use std::sync::Arc;
+
+use actix::Addr;
 use actix_web::web;
 
 use crate::{
+    freeze::Submit,
     github::PushEvent,
     http::Webhook,
-    runner::{BranchSpec, Reason, Runner, Task},
+    pins::Pins,
+    repos::{GitRef, RemoteUrl, ReposConfig},
+    runner::{BranchSpec, Reason, Task, TaskId},
 };
 
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum PushHookError {
-    #[error("ref must have format refs/heads/<branch>")]
+    #[error("ref must have format refs/heads/<branch> or refs/tags/<tag>")]
     NotBranch,
-    #[error("only pushes to master branch are processed")]
-    NotMaster,
+    #[error("ref isn't deployed: it's neither master nor matches an environment")]
+    NotDeployed,
+    #[error("{sender} isn't allowed to deploy into {environment}")]
+    NotAllowed { sender: String, environment: String },
     #[error("failed to queue build task")]
     SendError,
 }
@@ -20,7 +28,8 @@ impl actix_web::ResponseError for PushHookError {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
             PushHookError::NotBranch => actix_web::http::StatusCode::BAD_REQUEST,
-            PushHookError::NotMaster => actix_web::http::StatusCode::OK,
+            PushHookError::NotDeployed => actix_web::http::StatusCode::OK,
+            PushHookError::NotAllowed { .. } => actix_web::http::StatusCode::FORBIDDEN,
             PushHookError::SendError => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -28,31 +37,57 @@ impl actix_web::ResponseError for PushHookError {
 
 pub async fn push_hook(
     Webhook(hook): Webhook<PushEvent>,
-    tx: web::Data<actix::Addr<Runner>>,
+    repos: web::Data<Arc<ReposConfig>>,
+    pins: web::Data<Addr<Pins>>,
 ) -> Result<String, PushHookError> {
-    let branch = hook
-        .reference
-        .strip_prefix("refs/heads/")
-        .ok_or(PushHookError::NotBranch)?;
+    let git_ref = GitRef::parse(&hook.reference).ok_or(PushHookError::NotBranch)?;
+    let branch_spec = BranchSpec {
+        owner: hook.repository.owner.login,
+        repo: hook.repository.name,
+        branch: git_ref.name().to_string(),
+    };
+    let repo_config = repos.get(&branch_spec);
 
-    if branch != "master" {
-        return Err(PushHookError::NotMaster);
+    let environment = repo_config
+        .deployed_into(git_ref)
+        .ok_or(PushHookError::NotDeployed)?
+        .environment_name();
+    let protection = repo_config.protection(environment.as_deref());
+    if !protection.allows(&hook.sender.login) {
+        let target = environment.unwrap_or_else(|| git_ref.name().to_owned());
+        tracing::warn!(
+            "Ignoring push to {} by {}: not allowed to deploy into {}",
+            hook.reference,
+            hook.sender.login,
+            target,
+        );
+        return Err(PushHookError::NotAllowed {
+            sender: hook.sender.login,
+            environment: target,
+        });
     }
 
+    let clone_url = match repo_config.remote {
+        RemoteUrl::Url => hook.repository.url.clone(),
+        RemoteUrl::SshUrl => hook.repository.ssh_url,
+        RemoteUrl::CloneUrl => hook.repository.clone_url,
+    };
     let task = Task {
-        branch_spec: BranchSpec {
-            owner: hook.repository.owner.login,
-            repo: hook.repository.name,
-            branch: branch.to_string(),
-        },
-        reason: Reason::PushToMaster,
+        id: TaskId::generate(),
+        branch_spec,
+        reference: hook.reference,
+        environment,
+        sender: Some(hook.sender.login),
+        approved_by: None,
+        reason: Reason::Push,
         url: hook.repository.url,
+        clone_url,
         commit_hash: hook.after,
     };
 
-    match tx.try_send(task) {
-        Ok(()) => Ok("OK".into()),
-        err @ Err(_) => {
+    match pins.send(Submit(task)).await {
+        Ok(Ok(id)) => Ok(id.to_string()),
+        err => {
             tracing::error!("Failed to send task: {:?}", err);
             Err(PushHookError::SendError)
         },
@@ -1,10 +1,21 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use actix_web::{
     dev::Payload, error::ResponseError, http::StatusCode, web::Bytes, FromRequest, HttpRequest,
 };
 use futures::future::{FutureExt, LocalBoxFuture};
 use secstr::SecUtf8;
+use sha2::Digest as _;
 
-use crate::signature::{self, Signature};
+use crate::{
+    repos::{Scope, User},
+    runner::TaskId,
+    signature::{self, Signature},
+};
 
 #[derive(Debug, Clone)]
 pub struct Webhook<T>(pub T);
@@ -52,8 +63,8 @@ pub struct WebhookConfig {
 }
 
 impl WebhookConfig {
-    pub fn new(key: SecUtf8) -> Self {
-        Self { key: Some(key) }
+    pub fn new(key: Option<SecUtf8>) -> Self {
+        Self { key }
     }
 }
 
@@ -101,3 +112,343 @@ where
         ))
     }
 }
+
+/// Whoever made an API request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Principal {
+    /// Holder of the shared API token.
+    Admin,
+    /// User with their own token, see [`User`].
+    User(String),
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Admin => f.write_str("admin"),
+            Self::User(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Extractor for requests carrying a valid `Authorization: Bearer <token>`
+/// header, with either the shared API token or a user's own one. Users are
+/// only let in if the resource requires a [`Scope`] in its app data and
+/// they have it.
+#[derive(Debug, Clone)]
+pub struct Authorized(pub Principal);
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("`Authorization` header isn't found")]
+    HeaderNotFound,
+    #[error("`Authorization` header must have format `Bearer <token>`")]
+    InvalidHeader,
+    #[error("invalid API token")]
+    InvalidToken,
+    #[error("API token can't be used here")]
+    OutOfScope,
+    #[error("API token is not specified")]
+    NoToken,
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::HeaderNotFound | AuthError::InvalidHeader => StatusCode::UNAUTHORIZED,
+            AuthError::InvalidToken | AuthError::OutOfScope => StatusCode::FORBIDDEN,
+            AuthError::NoToken => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// How long a token for events of a single build may be used to connect.
+pub const EVENTS_TOKEN_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Default)]
+pub struct ApiConfig {
+    pub token: Option<SecUtf8>,
+    /// Names and scopes of users by SHA-256 of their tokens.
+    pub users: HashMap<[u8; 32], (String, HashSet<Scope>)>,
+}
+
+impl ApiConfig {
+    pub fn new(token: SecUtf8) -> Self {
+        Self {
+            token: Some(token),
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn with_users<'a>(mut self, users: impl IntoIterator<Item = (&'a str, &'a User)>) -> Self {
+        self.users.extend(
+            users
+                .into_iter()
+                .map(|(name, user)| (user.token_sha256, (name.to_owned(), user.scopes.clone()))),
+        );
+        self
+    }
+
+    /// Token that gives access to events of one build until `expires_at`,
+    /// for `EventSource` in browsers, which can't set headers. Unlike the
+    /// API token, it's fine for it to end up in access logs.
+    pub fn events_token(&self, task_id: TaskId, expires_at: u64) -> Option<String> {
+        use hmac::Mac as _;
+
+        let mac = self.events_mac(task_id, expires_at)?;
+        Some(format!(
+            "{}.{}",
+            expires_at,
+            hex::encode(mac.finalize().into_bytes())
+        ))
+    }
+
+    fn verify_events_token(&self, task_id: TaskId, token: &str) -> Result<(), AuthError> {
+        use hmac::Mac as _;
+
+        let (expires_at, signature) = token.split_once('.').ok_or(AuthError::InvalidToken)?;
+        let expires_at: u64 = expires_at.parse().map_err(|_| AuthError::InvalidToken)?;
+        let signature = hex::decode(signature).map_err(|_| AuthError::InvalidToken)?;
+        if expires_at < now() {
+            return Err(AuthError::InvalidToken);
+        }
+        self.events_mac(task_id, expires_at)
+            .ok_or(AuthError::NoToken)?
+            .verify(&signature)
+            .map_err(|_| AuthError::InvalidToken)
+    }
+
+    fn events_mac(&self, task_id: TaskId, expires_at: u64) -> Option<hmac::Hmac<sha2::Sha256>> {
+        use hmac::{Mac as _, NewMac as _};
+
+        let key = self.token.as_ref()?;
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_varkey(key.unsecure().as_bytes()).ok()?;
+        mac.update(format!("build-events:{task_id}:{expires_at}").as_bytes());
+        Some(mac)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn check_bearer(req: &HttpRequest) -> Result<Authorized, AuthError> {
+    let config = req
+        .app_data::<ApiConfig>()
+        .filter(|config| config.token.is_some() || !config.users.is_empty())
+        .ok_or(AuthError::NoToken)?;
+    let token = req
+        .headers()
+        .get("Authorization")
+        .ok_or(AuthError::HeaderNotFound)?
+        .to_str()
+        .ok()
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or(AuthError::InvalidHeader)?;
+
+    if config
+        .token
+        .as_ref()
+        .is_some_and(|expected| SecUtf8::from(token) == *expected)
+    {
+        return Ok(Authorized(Principal::Admin));
+    }
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&sha2::Sha256::digest(token.as_bytes()));
+    let (name, scopes) = config.users.get(&hash).ok_or(AuthError::InvalidToken)?;
+    match req.app_data::<Scope>() {
+        Some(scope) if scopes.contains(scope) => Ok(Authorized(Principal::User(name.clone()))),
+        _ => Err(AuthError::OutOfScope),
+    }
+}
+
+impl FromRequest for Authorized {
+    type Config = ApiConfig;
+    type Error = AuthError;
+    type Future = futures::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        futures::future::ready(check_bearer(req))
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct EventsTokenQuery {
+    token: String,
+}
+
+/// Access to events of the build in the `task_id` path segment, either with
+/// the API token or with a token from [`ApiConfig::events_token`] passed as
+/// the `token` query parameter.
+#[derive(Debug, Clone, Copy)]
+pub struct EventsAccess;
+
+impl FromRequest for EventsAccess {
+    type Config = ();
+    type Error = AuthError;
+    type Future = futures::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let check = || {
+            if req.headers().contains_key("Authorization") {
+                return check_bearer(req).map(|_| Self);
+            }
+            let token = actix_web::web::Query::<EventsTokenQuery>::from_query(req.query_string())
+                .map_err(|_| AuthError::HeaderNotFound)?
+                .into_inner()
+                .token;
+            let task_id: TaskId = req
+                .match_info()
+                .get("task_id")
+                .and_then(|id| id.parse().ok())
+                .ok_or(AuthError::InvalidToken)?;
+            req.app_data::<ApiConfig>()
+                .ok_or(AuthError::NoToken)?
+                .verify_events_token(task_id, &token)
+                .map(|()| Self)
+        };
+        futures::future::ready(check())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ApiConfig {
+        ApiConfig::new(SecUtf8::from("secret"))
+    }
+
+    #[test]
+    fn events_token_is_bound_to_build() {
+        let config = config();
+        let task_id: TaskId = "42".parse().unwrap();
+        let token = config.events_token(task_id, now() + 60).unwrap();
+        assert!(config.verify_events_token(task_id, &token).is_ok());
+
+        let other: TaskId = "43".parse().unwrap();
+        assert!(config.verify_events_token(other, &token).is_err());
+        let other_key = ApiConfig::new(SecUtf8::from("other"));
+        assert!(other_key.verify_events_token(task_id, &token).is_err());
+    }
+
+    #[test]
+    fn events_token_expires() {
+        let config = config();
+        let task_id: TaskId = "42".parse().unwrap();
+        let token = config.events_token(task_id, now() - 1).unwrap();
+        assert!(config.verify_events_token(task_id, &token).is_err());
+    }
+
+    #[test]
+    fn events_token_expiry_is_signed() {
+        let config = config();
+        let task_id: TaskId = "42".parse().unwrap();
+        let token = config.events_token(task_id, now() - 1).unwrap();
+        let (_, signature) = token.split_once('.').unwrap();
+        let extended = format!("{}.{}", now() + 60, signature);
+        assert!(config.verify_events_token(task_id, &extended).is_err());
+        assert!(config.verify_events_token(task_id, "garbage").is_err());
+    }
+
+    #[test]
+    fn no_events_tokens_without_api_token() {
+        let task_id: TaskId = "42".parse().unwrap();
+        assert!(ApiConfig::default()
+            .events_token(task_id, now() + 60)
+            .is_none());
+    }
+
+    fn authorize(
+        config: &ApiConfig,
+        scope: Option<Scope>,
+        token: &str,
+    ) -> Result<Principal, AuthError> {
+        let mut req = actix_web::test::TestRequest::default()
+            .app_data(config.clone())
+            .header("Authorization", format!("Bearer {token}"));
+        if let Some(scope) = scope {
+            req = req.app_data(scope);
+        }
+        check_bearer(&req.to_http_request()).map(|Authorized(principal)| principal)
+    }
+
+    #[test]
+    fn principals() {
+        let repos: crate::repos::ReposConfig = toml::from_str(
+            r#"
+            [users.alice]
+            # printf %s alice-token | sha256sum
+            token_sha256 = "9c220f200955d76c0a38d308225e0ef10c5f971acaf2f8d1d8f732affa5bd1dc"
+            "#,
+        )
+        .unwrap();
+        let mut config = config().with_users(repos.users());
+        let approve = Some(Scope::Approve);
+
+        assert_eq!(
+            authorize(&config, None, "secret").unwrap(),
+            Principal::Admin
+        );
+        assert_eq!(
+            authorize(&config, approve, "secret").unwrap(),
+            Principal::Admin
+        );
+        assert_eq!(
+            authorize(&config, approve, "alice-token").unwrap(),
+            Principal::User("alice".to_owned())
+        );
+        assert!(matches!(
+            authorize(&config, approve, "bob-token"),
+            Err(AuthError::InvalidToken)
+        ));
+
+        config.token = None;
+        assert!(authorize(&config, approve, "secret").is_err());
+        assert!(authorize(&config, approve, "alice-token").is_ok());
+        assert!(matches!(
+            authorize(&ApiConfig::default(), approve, "secret"),
+            Err(AuthError::NoToken)
+        ));
+    }
+
+    #[test]
+    fn users_are_limited_to_their_scopes() {
+        let repos: crate::repos::ReposConfig = toml::from_str(
+            r#"
+            [users.alice]
+            # printf %s alice-token | sha256sum
+            token_sha256 = "9c220f200955d76c0a38d308225e0ef10c5f971acaf2f8d1d8f732affa5bd1dc"
+
+            [users.bob]
+            # printf %s bob-token | sha256sum
+            token_sha256 = "97dd3707015dcf069cf73022ed7173b1165db6eff24b441cb57fd069a8c4e525"
+            scopes = ["pin", "override_freeze"]
+            "#,
+        )
+        .unwrap();
+        let config = config().with_users(repos.users());
+
+        // Approving is the default scope.
+        assert!(authorize(&config, Some(Scope::Approve), "alice-token").is_ok());
+        assert!(matches!(
+            authorize(&config, Some(Scope::Pin), "alice-token"),
+            Err(AuthError::OutOfScope)
+        ));
+        assert!(matches!(
+            authorize(&config, Some(Scope::Approve), "bob-token"),
+            Err(AuthError::OutOfScope)
+        ));
+        assert!(authorize(&config, Some(Scope::Pin), "bob-token").is_ok());
+        assert!(authorize(&config, Some(Scope::OverrideFreeze), "bob-token").is_ok());
+        // Resources without a scope are only for the shared token.
+        for token in ["alice-token", "bob-token"] {
+            assert!(matches!(
+                authorize(&config, None, token),
+                Err(AuthError::OutOfScope)
+            ));
+        }
+    }
+}
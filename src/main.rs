@@ -1,6 +1,5 @@
 #![deny(unsafe_code)]
 #![deny(non_ascii_idents)]
-#![deny(pointer_structural_match)]
 #![warn(clippy::pedantic)]
 #![warn(absolute_paths_not_starting_with_crate)]
 #![warn(anonymous_parameters)]
@@ -17,23 +16,44 @@
 #![warn(unused_qualifications)]
 #![warn(variant_size_differences)]
 
+mod api;
+mod approvals;
+mod build_log;
+mod cli;
 mod config;
+mod cron;
+mod deployments;
+mod freeze;
 mod git;
 mod github;
+mod glob;
 mod hooks;
 mod http;
 mod lock_manager;
 mod notifier;
+mod pins;
+mod poll;
+mod redeploys;
+mod repos;
 mod runner;
+mod secrets;
 mod signature;
+mod vault;
 
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use actix::{Actor, SyncArbiter};
+use actix::{Actor, Addr, SyncArbiter};
 use actix_web::{guard, middleware::Logger, web, App, HttpServer};
 use color_eyre::eyre;
+use structopt::StructOpt as _;
 
-use crate::runner::Runner;
+use crate::{
+    repos::Scope,
+    runner::{Mirrors, Queue, Runner, Tasks},
+};
 
 #[actix_web::main]
 async fn main() -> eyre::Result<()> {
@@ -42,40 +62,217 @@ async fn main() -> eyre::Result<()> {
     tracing_log::LogTracer::init()?;
     tracing::subscriber::set_global_default(tracing_subscriber::fmt().finish())?;
 
+    match cli::Opt::from_args().command {
+        None | Some(cli::Command::Serve) => serve(true).await,
+        Some(cli::Command::Poll) => serve(false).await,
+        Some(cli::Command::Secrets { state_dir, command }) => {
+            cli::secrets(&secrets::SecretStore::new(&state_dir), command)
+        },
+        Some(cli::Command::Promote {
+            server,
+            repo,
+            from,
+            to,
+        }) => cli::promote(&server, &repo, &from, &to).await,
+        Some(cli::Command::OverrideFreeze(args)) => cli::override_freeze(args).await,
+        Some(cli::Command::Pin(args)) => cli::pin(args).await,
+        Some(cli::Command::Unpin(args)) => cli::unpin(args).await,
+    }
+}
+
+/// Like the default format, but without query strings, which may contain
+/// tokens for build events.
+const ACCESS_LOG_FORMAT: &str = r#"%a "%U" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
+/// State shared by runners. Also starts the Vault client and brings the
+/// workspaces to the current layout.
+fn runner_context(
+    repo_root: PathBuf,
+    state_dir: &Path,
+    pass_env: Vec<String>,
+    deployments: Arc<deployments::Deployments>,
+    logs: Arc<build_log::BuildLogs>,
+    tasks: Arc<Tasks>,
+    repos: Arc<repos::ReposConfig>,
+) -> eyre::Result<runner::Context> {
+    let vault = vault::Vault::new(envy::prefixed("ADM_VAULT_").from_env()?)?
+        .map(|vault| vault::Client::new(vault.start()));
+    let mirrors = Mirrors::new(repo_root.join(".mirrors"));
+    runner::migrate_layout(&repo_root)?;
+    Ok(runner::Context {
+        mirrors,
+        secrets: secrets::SecretStore::new(state_dir),
+        vault,
+        deployments,
+        base_path: repo_root,
+        pass_env,
+        lock_manager: lock_manager::LockManager::new(),
+        logs,
+        tasks,
+        repos,
+    })
+}
+
+/// Starts `count` runners sharing the context and returns the queue feeding
+/// them.
+fn start_runners(
+    count: u8,
+    context: Arc<runner::Context>,
+    notifier: Addr<notifier::Notifier>,
+    tasks: Arc<Tasks>,
+) -> Queue {
+    let builder = SyncArbiter::start(usize::from(count), move || {
+        Runner::new(context.clone(), notifier.clone())
+    });
+    Queue::new(builder, tasks)
+}
+
+/// Starts everything that deploys, and the HTTP server if `http` is set.
+async fn serve(http: bool) -> eyre::Result<()> {
     let config::Config {
         host,
         port,
         repo_root,
+        repos_file,
+        state_dir,
+        log_retention,
         webhook_secret,
         telegram_token,
         telegram_groups,
         parallel_builds,
+        api_token,
+        pass_env,
     } = envy::prefixed("ADM_").from_env()?;
+    eyre::ensure!(
+        !http || webhook_secret.is_some(),
+        "ADM_WEBHOOK_SECRET must be set to serve webhooks"
+    );
 
     let notifier = notifier::Notifier::new(notifier::Config {
         telegram_token,
         telegram_groups,
     })
     .start();
-    let lock_manager = Arc::new(lock_manager::LockManager::new());
-    let builder = SyncArbiter::start(parallel_builds as usize, move || {
-        Runner::new(repo_root.clone(), lock_manager.clone(), notifier.clone())
+    let logs = Arc::new(build_log::BuildLogs::new(
+        state_dir.join("logs"),
+        log_retention,
+    )?);
+    let repos = Arc::new(match repos_file {
+        Some(path) => repos::ReposConfig::load(&path)?,
+        None => repos::ReposConfig::default(),
     });
+    let tasks = Arc::new(Tasks::new());
+    let deployments = Arc::new(deployments::Deployments::load(
+        state_dir.join("deployments.json"),
+    )?);
+    let context = Arc::new(runner_context(
+        repo_root,
+        &state_dir,
+        pass_env,
+        deployments.clone(),
+        logs.clone(),
+        tasks.clone(),
+        repos.clone(),
+    )?);
+    let queue = start_runners(parallel_builds, context, notifier.clone(), tasks);
+    let approvals = approvals::Approvals::load(
+        state_dir.join("approvals.json"),
+        queue.clone(),
+        notifier.clone(),
+        repos.clone(),
+    )?
+    .start();
+    let freezes = freeze::Freezes::load(
+        state_dir.join("held.json"),
+        queue.clone(),
+        approvals.clone(),
+        notifier.clone(),
+        repos.clone(),
+        state_dir.join("audit.log"),
+    )?
+    .start();
+    let pins = pins::Pins::load(
+        state_dir.join("pins.json"),
+        freezes.clone().recipient(),
+        notifier,
+        repos.clone(),
+        deployments.clone(),
+    )?
+    .start();
+    redeploys::Redeploys::new(pins.clone(), repos.clone(), deployments.clone()).start();
+    poll::Poller::new(pins.clone(), repos.clone(), deployments.clone()).start();
+    if !http {
+        return actix_web::rt::signal::ctrl_c().await.map_err(Into::into);
+    }
 
+    let api_config = api_token
+        .map_or_else(http::ApiConfig::default, http::ApiConfig::new)
+        .with_users(repos.users());
     HttpServer::new(move || {
         App::new()
-            .data(builder.clone())
+            .data(queue.clone())
+            .data(logs.clone())
+            .data(repos.clone())
+            .data(deployments.clone())
+            .data(approvals.clone())
+            .data(freezes.clone())
+            .data(pins.clone())
             .app_data(http::WebhookConfig::new(webhook_secret.clone()))
-            .wrap(Logger::default())
-            .route(
-                "/{repo}",
-                web::post()
-                    .guard(guard::Header("X-GitHub-Event", "push"))
-                    .to(hooks::push_hook),
-            )
+            .app_data(api_config.clone())
+            .wrap(Logger::new(ACCESS_LOG_FORMAT))
+            .configure(routes)
     })
     .bind((host, port))?
     .run()
     .await
     .map_err(Into::into)
 }
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/builds/{task_id}/log", web::get().to(api::build_log))
+        .route("/builds/{task_id}/events", web::get().to(api::build_events))
+        .route(
+            "/builds/{task_id}/events/token",
+            web::post().to(api::events_token),
+        )
+        .route(
+            "/builds/{task_id}/cancel",
+            web::post().to(api::cancel_build),
+        )
+        .service(
+            web::resource("/approvals/{task_id}/approve")
+                .app_data(Scope::Approve)
+                .route(web::post().to(api::approve)),
+        )
+        .service(
+            web::resource("/approvals/{task_id}/reject")
+                .app_data(Scope::Approve)
+                .route(web::post().to(api::reject)),
+        )
+        .route(
+            "/repos/{owner}/{repo}/promote",
+            web::post().to(api::promote),
+        )
+        .service(
+            web::resource("/repos/{owner}/{repo}/freeze-override")
+                .app_data(Scope::OverrideFreeze)
+                .route(web::post().to(api::override_freeze)),
+        )
+        .service(
+            web::resource("/repos/{owner}/{repo}/pin")
+                .app_data(Scope::Pin)
+                .route(web::get().to(api::get_pin))
+                .route(web::post().to(api::pin)),
+        )
+        .service(
+            web::resource("/repos/{owner}/{repo}/unpin")
+                .app_data(Scope::Pin)
+                .route(web::post().to(api::unpin)),
+        )
+        .route(
+            "/{repo}",
+            web::post()
+                .guard(guard::Header("X-GitHub-Event", "push"))
+                .to(hooks::push_hook),
+        );
+}
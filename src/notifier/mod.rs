@@ -2,19 +2,64 @@ use std::fmt;
 
 mod status;
 mod telegram;
-use std::sync::Arc;
+use std::{rc::Rc, sync::Arc};
 
 use actix::prelude::*;
 use secstr::SecUtf8;
 
 pub use self::status::Status;
-use crate::runner::Task;
+use crate::{
+    repos::{ReposConfig, Unit},
+    runner::{Task, Timings},
+};
 
 #[derive(Debug, Clone, Message)]
 #[rtype(result = "()")]
 pub struct Notification {
     pub task: Arc<Task>,
     pub status: Arc<Status>,
+    /// Overrides the default Telegram chats, e.g. for an environment.
+    pub telegram_groups: Option<Vec<i64>>,
+    /// Monorepo unit the notification is about.
+    pub unit: Option<String>,
+    /// How long the deploy took, if it succeeded.
+    pub timings: Option<Timings>,
+}
+
+impl Notification {
+    /// Notification sent to the chats of the task's environment, if it has
+    /// any configured.
+    pub fn new(task: Task, status: Status, repos: &ReposConfig) -> Self {
+        let telegram_groups = task.environment.as_deref().and_then(|name| {
+            repos
+                .get(&task.branch_spec)
+                .environment(name)
+                .and_then(|env| env.telegram_groups.clone())
+        });
+        Self {
+            task: Arc::new(task),
+            status: Arc::new(status),
+            telegram_groups,
+            unit: None,
+            timings: None,
+        }
+    }
+
+    /// Notification about a deploy of one monorepo unit, sent to its chats.
+    pub fn unit(task: Task, status: Status, unit: &Unit) -> Self {
+        Self {
+            task: Arc::new(task),
+            status: Arc::new(status),
+            telegram_groups: unit.telegram_groups.clone(),
+            unit: Some(unit.name.clone()),
+            timings: None,
+        }
+    }
+
+    pub fn with_timings(mut self, timings: Option<Timings>) -> Self {
+        self.timings = timings;
+        self
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -25,7 +70,7 @@ pub struct Config {
 
 #[derive(Clone)]
 pub struct Notifier {
-    telegram: Option<Arc<telegram::Telegram>>,
+    telegram: Option<Rc<telegram::Telegram>>,
 }
 
 impl fmt::Debug for Notifier {
@@ -44,13 +89,13 @@ impl fmt::Debug for Notifier {
 
 impl Notifier {
     pub fn new(config: Config) -> Self {
-        let http = Arc::new(awc::Client::new());
+        let http = Rc::new(awc::Client::new());
         let Config {
             telegram_token,
             telegram_groups,
         } = config;
         let telegram = telegram_token.and_then(|token| {
-            telegram_groups.map(|groups| Arc::new(telegram::Telegram::new(http, &token, groups)))
+            telegram_groups.map(|groups| Rc::new(telegram::Telegram::new(http, &token, groups)))
         });
         Self { telegram }
     }
@@ -64,9 +109,20 @@ impl Handler<Notification> for Notifier {
     type Result = <Notification as Message>::Result;
 
     fn handle(&mut self, msg: Notification, ctx: &mut Self::Context) -> Self::Result {
-        let Notification { task, status } = msg;
+        let Notification {
+            task,
+            status,
+            telegram_groups,
+            unit,
+            timings,
+        } = msg;
         if let Some(telegram) = &self.telegram {
-            ctx.spawn(telegram.clone().notify(task, status).into_actor(self));
+            ctx.spawn(
+                telegram
+                    .clone()
+                    .notify(task, status, telegram_groups, unit, timings)
+                    .into_actor(self),
+            );
         }
     }
 }
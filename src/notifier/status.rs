@@ -1,18 +1,141 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
+use chrono::NaiveDateTime;
 use color_eyre::eyre;
 
+use crate::runner::{Interrupted, MigrationFailed, Stale, TestsFailed};
+
 #[derive(Debug)]
 pub enum Status {
     Fail(eyre::Report),
+    TimedOut(Duration),
+    Cancelled,
     Success,
+    /// The task waits for someone to approve it.
+    PendingApproval(Duration),
+    Rejected(String),
+    ApprovalExpired,
+    /// The task is held by a deploy freeze.
+    Held {
+        until: NaiveDateTime,
+        reason: Option<String>,
+    },
+    /// The task was dropped because of a deploy freeze.
+    Dropped(Option<String>),
+    /// The held task was replaced by a newer commit, which is held instead.
+    Superseded(String),
+    FreezeOverridden {
+        by: String,
+        reason: String,
+    },
+    /// Tests failed, so the running deploy was left as is.
+    TestsFailed(TestsFailed),
+    /// The migration failed, so containers weren't recreated.
+    MigrationFailed(MigrationFailed),
+    /// The task was skipped because a newer commit is already deployed.
+    Stale(String),
+    /// The task wasn't deployed because the target is pinned to a commit.
+    Pinned {
+        commit_hash: String,
+        reason: Option<String>,
+    },
+}
+
+impl Status {
+    /// Whether the task hasn't run yet and still may.
+    pub fn is_pending(&self) -> bool {
+        matches!(self, Status::PendingApproval(_))
+    }
+
+    /// Whether the task was actually run.
+    pub fn ran(&self) -> bool {
+        matches!(
+            self,
+            Status::Fail(_)
+                | Status::TestsFailed(_)
+                | Status::MigrationFailed(_)
+                | Status::TimedOut(_)
+                | Status::Cancelled
+                | Status::Success
+        )
+    }
+
+    /// Tail of the output of a failed command, shown below the status.
+    pub fn output(&self) -> Option<&str> {
+        match self {
+            Status::TestsFailed(failed) => Some(&failed.output),
+            Status::MigrationFailed(failed) => Some(&failed.output),
+            _ => None,
+        }
+    }
+
+    /// What happened to a task that wasn't run (yet).
+    pub fn headline(&self) -> &'static str {
+        match self {
+            Status::PendingApproval(_) => "needs approval",
+            Status::Rejected(_) => "was rejected",
+            Status::ApprovalExpired => "wasn't approved in time",
+            Status::Held { .. } => "is on hold",
+            Status::Dropped(_) | Status::Superseded(_) => "was dropped",
+            Status::FreezeOverridden { .. } => "is released from a freeze",
+            Status::Stale(_) | Status::Pinned { .. } => "was skipped",
+            _ => "finished",
+        }
+    }
 }
 
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Status::Fail(err) => write!(f, "{}", err),
+            Status::Fail(err) => write!(f, "{err}"),
+            Status::TestsFailed(failed) => write!(f, "{failed}"),
+            Status::MigrationFailed(failed) => write!(f, "{failed}"),
+            Status::TimedOut(timeout) => {
+                write!(
+                    f,
+                    "timed out after {}",
+                    humantime::format_duration(*timeout)
+                )
+            },
+            Status::Cancelled => f.write_str("cancelled"),
             Status::Success => f.write_str("completed"),
+            Status::PendingApproval(timeout) => write!(
+                f,
+                "awaiting approval for {}",
+                humantime::format_duration(*timeout)
+            ),
+            Status::Rejected(by) => write!(f, "rejected by {by}"),
+            Status::ApprovalExpired => f.write_str("not approved in time"),
+            Status::Held { until, reason } => {
+                write!(f, "held by a deploy freeze until {}", until.format("%F %R"))?;
+                reason
+                    .iter()
+                    .try_for_each(|reason| write!(f, " ({reason})"))
+            },
+            Status::Dropped(reason) => {
+                f.write_str("dropped because of a deploy freeze")?;
+                reason
+                    .iter()
+                    .try_for_each(|reason| write!(f, " ({reason})"))
+            },
+            Status::Superseded(commit_hash) => {
+                write!(f, "superseded by {commit_hash} while on hold")
+            },
+            Status::FreezeOverridden { by, reason } => {
+                write!(f, "deploy freeze overridden by {by}: {reason}")
+            },
+            Status::Stale(deployed) => {
+                write!(f, "skipped, a newer commit {deployed} is already deployed")
+            },
+            Status::Pinned {
+                commit_hash,
+                reason,
+            } => {
+                write!(f, "not deployed, pinned to {commit_hash}")?;
+                reason
+                    .iter()
+                    .try_for_each(|reason| write!(f, " ({reason})"))
+            },
         }
     }
 }
@@ -21,7 +144,20 @@ impl<T> From<Result<T, eyre::Report>> for Status {
     fn from(res: Result<T, eyre::Report>) -> Self {
         match res {
             Ok(_) => Self::Success,
-            Err(err) => Self::Fail(err),
+            Err(err) => match err.downcast_ref::<Interrupted>() {
+                Some(Interrupted::TimedOut(timeout)) => Self::TimedOut(*timeout),
+                Some(Interrupted::Cancelled) => Self::Cancelled,
+                None => match err.downcast::<Stale>() {
+                    Ok(stale) => Self::Stale(stale.deployed),
+                    Err(err) => match err.downcast::<TestsFailed>() {
+                        Ok(failed) => Self::TestsFailed(failed),
+                        Err(err) => match err.downcast::<MigrationFailed>() {
+                            Ok(failed) => Self::MigrationFailed(failed),
+                            Err(err) => Self::Fail(err),
+                        },
+                    },
+                },
+            },
         }
     }
 }
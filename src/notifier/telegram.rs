@@ -1,17 +1,18 @@
-use std::sync::Arc;
+use std::{rc::Rc, sync::Arc};
 
 use askama::Template;
 use color_eyre::eyre::{self, WrapErr as _};
 use secstr::SecUtf8;
 
 use super::Status;
-use crate::runner::Task;
+use crate::runner::{Task, Timings};
 
 // Keep unused variants for documentation
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, serde::Serialize)]
 enum ParseMode {
-    HTML,
+    #[serde(rename = "HTML")]
+    Html,
     Markdown,
     MarkdownV2,
 }
@@ -28,23 +29,35 @@ struct SendMessage<'a> {
 struct MessageTemplate<'a> {
     pub task: &'a Task,
     pub status: &'a Status,
+    pub unit: Option<&'a str>,
+    pub timings: Option<Timings>,
 }
 
 impl<'a> MessageTemplate<'a> {
-    fn new(task: &'a Task, status: &'a Status) -> Self {
-        Self { task, status }
+    fn new(
+        task: &'a Task,
+        status: &'a Status,
+        unit: Option<&'a str>,
+        timings: Option<Timings>,
+    ) -> Self {
+        Self {
+            task,
+            status,
+            unit,
+            timings,
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Telegram {
-    http: Arc<awc::Client>,
+    http: Rc<awc::Client>,
     url: SecUtf8,
     pub chats: Vec<i64>,
 }
 
 impl Telegram {
-    pub fn new(http: Arc<awc::Client>, token: &SecUtf8, chats: Vec<i64>) -> Self {
+    pub fn new(http: Rc<awc::Client>, token: &SecUtf8, chats: Vec<i64>) -> Self {
         let url = SecUtf8::from(format!(
             "https://api.telegram.org/bot{}/sendMessage",
             token.unsecure()
@@ -52,16 +65,23 @@ impl Telegram {
         Self { http, url, chats }
     }
 
-    async fn try_notify(&self, task: Arc<Task>, status: Arc<Status>) -> eyre::Result<()> {
-        let text = &MessageTemplate::new(&task, &status)
+    async fn try_notify(
+        &self,
+        task: Arc<Task>,
+        status: Arc<Status>,
+        chats: Option<Vec<i64>>,
+        unit: Option<String>,
+        timings: Option<Timings>,
+    ) -> eyre::Result<()> {
+        let text = &MessageTemplate::new(&task, &status, unit.as_deref(), timings)
             .render()
             .wrap_err("Failed to render message template")?;
 
-        for chat_id in self.chats.iter().copied() {
+        for chat_id in chats.as_ref().unwrap_or(&self.chats).iter().copied() {
             let message = SendMessage {
                 chat_id,
                 text,
-                parse_mode: ParseMode::HTML,
+                parse_mode: ParseMode::Html,
             };
 
             let mut resp = self
@@ -88,8 +108,15 @@ impl Telegram {
         Ok(())
     }
 
-    pub async fn notify(self: Arc<Self>, task: Arc<Task>, status: Arc<Status>) {
-        if let Err(err) = self.try_notify(task, status).await {
+    pub async fn notify(
+        self: Rc<Self>,
+        task: Arc<Task>,
+        status: Arc<Status>,
+        chats: Option<Vec<i64>>,
+        unit: Option<String>,
+        timings: Option<Timings>,
+    ) {
+        if let Err(err) = self.try_notify(task, status, chats, unit, timings).await {
             tracing::error!("Failed sending Telegram notification: {}", err);
         }
     }
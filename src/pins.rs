@@ -0,0 +1,519 @@
+use std::{
+    collections::HashMap,
+    fs,
+    future::Future,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use actix::prelude::*;
+use color_eyre::eyre::{self, WrapErr as _};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    deployments::{self, Deployed, Deployments},
+    freeze::Submit,
+    notifier::{Notification, Notifier, Status},
+    repos::ReposConfig,
+    runner::{Reason, SubmitError, Target, Task, TaskId},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PinError {
+    #[error("{0} isn't pinned")]
+    NotPinned(Target),
+    #[error("nothing was deployed into {0} yet")]
+    NothingDeployed(Target),
+    #[error("failed to queue pinned commit: {0}")]
+    Submit(#[from] SubmitError),
+    #[error("failed to save pins: {0:#}")]
+    Save(eyre::Report),
+}
+
+impl actix_web::ResponseError for PinError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            PinError::NotPinned(_) => actix_web::http::StatusCode::NOT_FOUND,
+            PinError::NothingDeployed(_) => actix_web::http::StatusCode::CONFLICT,
+            PinError::Submit(_) | PinError::Save(_) => {
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+            },
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// A target that stays on one commit no matter what gets pushed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pin {
+    pub commit_hash: String,
+    pub reason: Option<String>,
+    /// Whoever pinned the target.
+    pub by: String,
+    /// Unix timestamp of when the target was pinned.
+    pub pinned_at: u64,
+    /// Unix timestamp of when the pin is lifted by itself.
+    pub expires_at: Option<u64>,
+    /// Latest commit that was pushed while the target was pinned, with
+    /// `finished_at` set to when the push arrived.
+    pub pending: Option<Deployed>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    target: Target,
+    #[serde(flatten)]
+    pin: Pin,
+}
+
+/// Keeps pinned targets on their commits, recording pushes into them
+/// instead of deploying. Pins are persisted in the state directory.
+#[derive(Debug)]
+pub struct Pins {
+    path: PathBuf,
+    /// Where deploys go unless they're kept back by a pin, i.e. freezes.
+    freezes: Recipient<Submit>,
+    notifier: Addr<Notifier>,
+    repos: Arc<ReposConfig>,
+    deployments: Arc<Deployments>,
+    pinned: HashMap<Target, Pin>,
+    expiry: HashMap<Target, SpawnHandle>,
+}
+
+impl Pins {
+    pub fn load(
+        path: PathBuf,
+        freezes: Recipient<Submit>,
+        notifier: Addr<Notifier>,
+        repos: Arc<ReposConfig>,
+        deployments: Arc<Deployments>,
+    ) -> eyre::Result<Self> {
+        let pinned = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice::<Vec<Record>>(&content)
+                .wrap_err_with(|| format!("failed to parse {}", path.display()))?
+                .into_iter()
+                .map(|record| (record.target, record.pin))
+                .collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).wrap_err_with(|| format!("failed to read {}", path.display()))
+            },
+        };
+        Ok(Self {
+            path,
+            freezes,
+            notifier,
+            repos,
+            deployments,
+            pinned,
+            expiry: HashMap::new(),
+        })
+    }
+
+    fn save(&self) -> Result<(), PinError> {
+        let records: Vec<_> = self
+            .pinned
+            .iter()
+            .map(|(target, pin)| Record {
+                target: target.clone(),
+                pin: pin.clone(),
+            })
+            .collect();
+        deployments::write_json(&self.path, &records).map_err(PinError::Save)
+    }
+
+    fn notify(&self, task: Task, status: Status) {
+        let notification = Notification::new(task, status, &self.repos);
+        if let Err(err) = self.notifier.try_send(notification) {
+            tracing::error!("Failed to send notification: {}", err);
+        }
+    }
+
+    fn deploy(&self, task: Task) -> impl Future<Output = Result<TaskId, SubmitError>> {
+        let freezes = self.freezes.clone();
+        async move {
+            freezes
+                .send(Submit(task))
+                .await
+                .unwrap_or(Err(SubmitError::Closed))
+        }
+    }
+
+    fn schedule_expiry(&mut self, target: Target, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.expiry.remove(&target) {
+            ctx.cancel_future(handle);
+        }
+        let Some(expires_at) = self.pinned.get(&target).and_then(|pin| pin.expires_at) else {
+            return;
+        };
+        let wait = Duration::from_secs(expires_at.saturating_sub(now()));
+        let key = target.clone();
+        let handle = ctx.run_later(wait, move |act, ctx| {
+            act.expiry.remove(&target);
+            let Some(pin) = act.pinned.remove(&target) else {
+                return;
+            };
+            tracing::info!("Pin of {} to {} expired", target, pin.commit_hash);
+            if let Err(err) = act.save() {
+                tracing::error!("{}", err);
+            }
+            if let Some(pending) = pin.pending {
+                let deploy = act.deploy(pending.task(&target, Reason::Push));
+                ctx.spawn(
+                    async move {
+                        if let Err(err) = deploy.await {
+                            tracing::error!("Failed to deploy pending commit: {}", err);
+                        }
+                    }
+                    .into_actor(act),
+                );
+            }
+        });
+        self.expiry.insert(key, handle);
+    }
+}
+
+impl Actor for Pins {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let targets: Vec<_> = self.pinned.keys().cloned().collect();
+        for target in targets {
+            self.schedule_expiry(target, ctx);
+        }
+    }
+}
+
+impl Handler<Submit> for Pins {
+    type Result = ResponseFuture<Result<TaskId, SubmitError>>;
+
+    fn handle(&mut self, Submit(task): Submit, _ctx: &mut Self::Context) -> Self::Result {
+        let target = task.target();
+        let pin = match self.pinned.get_mut(&target) {
+            Some(pin) if !matches!(task.reason, Reason::Pin) => pin,
+            _ => return Box::pin(self.deploy(task)),
+        };
+        tracing::info!(
+            "Not deploying task {}: {} is pinned to {}",
+            task.id,
+            target,
+            pin.commit_hash,
+        );
+        pin.pending = Some(Deployed::new(&task));
+        let status = Status::Pinned {
+            commit_hash: pin.commit_hash.clone(),
+            reason: pin.reason.clone(),
+        };
+        let id = task.id;
+        if let Err(err) = self.save() {
+            tracing::error!("{}", err);
+        }
+        self.notify(task, status);
+        Box::pin(async move { Ok(id) })
+    }
+}
+
+/// Pins a target to a commit, deploying it if it's not running already.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Option<TaskId>, PinError>")]
+pub struct PinTarget {
+    pub target: Target,
+    /// The currently deployed commit is pinned if not set.
+    pub commit: Option<String>,
+    pub reason: Option<String>,
+    /// Whoever made the request.
+    pub by: String,
+    pub expires: Option<Duration>,
+}
+
+impl Handler<PinTarget> for Pins {
+    type Result = ResponseFuture<Result<Option<TaskId>, PinError>>;
+
+    fn handle(&mut self, msg: PinTarget, ctx: &mut Self::Context) -> Self::Result {
+        let PinTarget {
+            target,
+            commit,
+            reason,
+            by,
+            expires,
+        } = msg;
+        let Some(current) = self
+            .deployments
+            .get(&target)
+            .and_then(|deployment| deployment.current)
+        else {
+            return Box::pin(async move { Err(PinError::NothingDeployed(target)) });
+        };
+        let commit_hash = commit.unwrap_or_else(|| current.commit_hash.clone());
+        tracing::info!(
+            "Pinning {} to {} by {}: {}",
+            target,
+            commit_hash,
+            by,
+            reason.as_deref().unwrap_or("no reason given"),
+        );
+
+        let pinned_at = now();
+        let pending = self.pinned.remove(&target).and_then(|pin| pin.pending);
+        self.pinned.insert(target.clone(), Pin {
+            commit_hash: commit_hash.clone(),
+            reason,
+            by,
+            pinned_at,
+            expires_at: expires.map(|expires| pinned_at + expires.as_secs()),
+            pending,
+        });
+        self.schedule_expiry(target.clone(), ctx);
+        if let Err(err) = self.save() {
+            return Box::pin(async move { Err(err) });
+        }
+
+        if commit_hash == current.commit_hash {
+            return Box::pin(async move { Ok(None) });
+        }
+        let mut task = current.task(&target, Reason::Pin);
+        task.commit_hash = commit_hash;
+        let deploy = self.deploy(task);
+        Box::pin(async move { Ok(Some(deploy.await?)) })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Unpinned {
+    /// Latest commit pushed while the target was pinned.
+    pub pending: Option<String>,
+    /// Task deploying the pending commit, if it was requested.
+    pub task_id: Option<TaskId>,
+}
+
+/// Lifts a pin, optionally deploying the latest commit that was pushed
+/// while it was in place.
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Unpinned, PinError>")]
+pub struct UnpinTarget {
+    pub target: Target,
+    pub deploy: bool,
+}
+
+impl Handler<UnpinTarget> for Pins {
+    type Result = ResponseFuture<Result<Unpinned, PinError>>;
+
+    fn handle(&mut self, msg: UnpinTarget, ctx: &mut Self::Context) -> Self::Result {
+        let UnpinTarget { target, deploy } = msg;
+        let Some(pin) = self.pinned.remove(&target) else {
+            return Box::pin(async move { Err(PinError::NotPinned(target)) });
+        };
+        if let Some(handle) = self.expiry.remove(&target) {
+            ctx.cancel_future(handle);
+        }
+        tracing::info!("Unpinned {} from {}", target, pin.commit_hash);
+        if let Err(err) = self.save() {
+            return Box::pin(async move { Err(err) });
+        }
+
+        let pending = pin
+            .pending
+            .as_ref()
+            .map(|pending| pending.commit_hash.clone());
+        let deploy = pin
+            .pending
+            .filter(|_| deploy)
+            .map(|pending| self.deploy(pending.task(&target, Reason::Push)));
+        Box::pin(async move {
+            let task_id = match deploy {
+                Some(deploy) => Some(deploy.await?),
+                None => None,
+            };
+            Ok(Unpinned { pending, task_id })
+        })
+    }
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "Option<Pin>")]
+pub struct GetPin(pub Target);
+
+impl Handler<GetPin> for Pins {
+    type Result = Option<Pin>;
+
+    fn handle(&mut self, GetPin(target): GetPin, _ctx: &mut Self::Context) -> Self::Result {
+        self.pinned.get(&target).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::runner::BranchSpec;
+
+    /// Stands in for freezes, collecting the deploys that pins let through.
+    struct Recorder(Arc<Mutex<Vec<Task>>>);
+
+    impl Actor for Recorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Submit> for Recorder {
+        type Result = Result<TaskId, SubmitError>;
+
+        fn handle(&mut self, Submit(task): Submit, _ctx: &mut Self::Context) -> Self::Result {
+            let id = task.id;
+            self.0.lock().unwrap().push(task);
+            Ok(id)
+        }
+    }
+
+    fn staging() -> Target {
+        Target::Environment {
+            owner: "me".to_owned(),
+            repo: "app".to_owned(),
+            name: "staging".to_owned(),
+        }
+    }
+
+    fn push(commit_hash: &str) -> Task {
+        Task {
+            id: TaskId::generate(),
+            branch_spec: BranchSpec {
+                owner: "me".to_owned(),
+                repo: "app".to_owned(),
+                branch: "master".to_owned(),
+            },
+            reference: "refs/heads/master".to_owned(),
+            environment: Some("staging".to_owned()),
+            sender: Some("bob".to_owned()),
+            approved_by: None,
+            commit_hash: commit_hash.to_owned(),
+            url: String::new(),
+            clone_url: String::new(),
+            reason: Reason::Push,
+        }
+    }
+
+    fn pin(commit: Option<&str>, expires: Option<Duration>) -> PinTarget {
+        PinTarget {
+            target: staging(),
+            commit: commit.map(str::to_owned),
+            reason: Some("incident".to_owned()),
+            by: "alice".to_owned(),
+            expires,
+        }
+    }
+
+    /// Runs `test` with pins whose deploys end up in the returned list.
+    fn with_pins<F, Fut>(name: &str, test: F)
+    where
+        F: FnOnce(PathBuf, Addr<Pins>, Arc<Mutex<Vec<Task>>>) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let dir = std::env::temp_dir().join(format!("adm-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let deployments = Deployments::load(dir.join("deployments.json")).unwrap();
+        deployments.record(&push("a"), &Status::Success).unwrap();
+        let path = dir.join("pins.json");
+
+        System::new(name).block_on(async move {
+            let deployed = Arc::new(Mutex::new(Vec::new()));
+            let recorder = Recorder(deployed.clone()).start();
+            let notifier = Notifier::new(crate::notifier::Config {
+                telegram_token: None,
+                telegram_groups: None,
+            })
+            .start();
+            let pins = Pins::load(
+                path.clone(),
+                recorder.recipient(),
+                notifier,
+                Arc::default(),
+                Arc::new(deployments),
+            )
+            .unwrap()
+            .start();
+            test(path, pins, deployed).await;
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn commits(deployed: &Mutex<Vec<Task>>) -> Vec<(String, String)> {
+        deployed
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|task| (task.commit_hash.clone(), task.reason.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn pushes_are_recorded_and_released_on_unpin() {
+        with_pins("pins-unpin", |path, pins, deployed| async move {
+            // The running commit is pinned without deploying anything.
+            assert_eq!(pins.send(pin(None, None)).await.unwrap().unwrap(), None);
+            let pinned = pins.send(GetPin(staging())).await.unwrap().unwrap();
+            assert_eq!(pinned.commit_hash, "a");
+            assert_eq!(pinned.by, "alice");
+
+            pins.send(Submit(push("b"))).await.unwrap().unwrap();
+            pins.send(Submit(push("c"))).await.unwrap().unwrap();
+            assert!(commits(&deployed).is_empty());
+            let pinned = pins.send(GetPin(staging())).await.unwrap().unwrap();
+            assert_eq!(pinned.pending.unwrap().commit_hash, "c");
+            assert!(fs::read_to_string(&path).unwrap().contains("\"c\""));
+
+            let unpinned = pins
+                .send(UnpinTarget {
+                    target: staging(),
+                    deploy: true,
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(unpinned.pending.as_deref(), Some("c"));
+            assert!(unpinned.task_id.is_some());
+            assert_eq!(commits(&deployed), [("c".to_owned(), "push".to_owned())]);
+            assert!(pins.send(GetPin(staging())).await.unwrap().is_none());
+            assert!(matches!(
+                pins.send(UnpinTarget {
+                    target: staging(),
+                    deploy: true,
+                })
+                .await
+                .unwrap(),
+                Err(PinError::NotPinned(_))
+            ));
+
+            // Pushes go through once unpinned.
+            pins.send(Submit(push("d"))).await.unwrap().unwrap();
+            assert_eq!(commits(&deployed).len(), 2);
+        });
+    }
+
+    #[test]
+    fn expired_pins_deploy_the_pending_commit() {
+        with_pins("pins-expiry", |_path, pins, deployed| async move {
+            let pinned = pins
+                .send(pin(Some("b"), Some(Duration::from_secs(1))))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(pinned.is_some());
+            pins.send(Submit(push("c"))).await.unwrap().unwrap();
+            assert_eq!(commits(&deployed), [("b".to_owned(), "pin".to_owned())]);
+
+            actix::clock::delay_for(Duration::from_millis(2500)).await;
+            assert!(pins.send(GetPin(staging())).await.unwrap().is_none());
+            assert_eq!(commits(&deployed), [
+                ("b".to_owned(), "pin".to_owned()),
+                ("c".to_owned(), "push".to_owned())
+            ]);
+        });
+    }
+}
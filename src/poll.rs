@@ -0,0 +1,175 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use actix::prelude::*;
+use actix_web::{error::BlockingError, web};
+use color_eyre::eyre;
+use rand::Rng as _;
+
+use crate::{
+    deployments::Deployments,
+    freeze::Submit,
+    pins::Pins,
+    repos::{GitRef, Poll, ReposConfig},
+    runner::{self, BranchSpec, Reason, Target, Task, TaskId},
+};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+/// Time until the next poll: the interval doubled for every failed poll in a
+/// row, give or take 10% so that polls of different repos spread out.
+fn next_delay(interval: Duration, failures: u32) -> Duration {
+    let delay = interval
+        .saturating_mul(1 << failures.min(16))
+        .min(MAX_BACKOFF.max(interval));
+    delay.mul_f64(rand::thread_rng().gen_range(0.9, 1.1))
+}
+
+/// Deploys pushes to repos that can't send webhooks, by periodically asking
+/// the remotes where their branches point.
+#[derive(Debug)]
+pub struct Poller {
+    pins: Addr<Pins>,
+    repos: Arc<ReposConfig>,
+    deployments: Arc<Deployments>,
+    /// Last seen heads of the polled branches.
+    seen: HashMap<BranchSpec, String>,
+}
+
+impl Poller {
+    pub fn new(pins: Addr<Pins>, repos: Arc<ReposConfig>, deployments: Arc<Deployments>) -> Self {
+        Self {
+            pins,
+            repos,
+            deployments,
+            seen: HashMap::new(),
+        }
+    }
+
+    fn poll(&mut self, owner: String, repo: String, failures: u32, ctx: &mut Context<Self>) {
+        let repo_config = self.repos.repo(&owner, &repo);
+        let Some(poll) = repo_config.poll.clone() else {
+            return;
+        };
+        let url = poll.url.clone();
+        let refs = web::block(move || runner::remote_refs(&url, &repo_config));
+        ctx.spawn(refs.into_actor(self).map(move |refs, act, ctx| {
+            let refs = refs.map_err(|err| match err {
+                BlockingError::Error(err) => err,
+                BlockingError::Canceled => eyre::eyre!("polling thread was stopped"),
+            });
+            let failures = match refs {
+                Ok(refs) => {
+                    act.update(&owner, &repo, &poll, &refs, ctx);
+                    0
+                },
+                Err(err) => {
+                    tracing::warn!("Failed to poll {}/{}: {:#}", owner, repo, err);
+                    failures + 1
+                },
+            };
+            let delay = next_delay(poll.interval.unwrap_or(DEFAULT_INTERVAL), failures);
+            ctx.run_later(delay, move |act, ctx| act.poll(owner, repo, failures, ctx));
+        }));
+    }
+
+    /// Whether `head` should be deployed. Right after start, it's compared
+    /// with the last deploy of the branch into the target, so that pushes
+    /// made while adm was down aren't missed.
+    fn is_new(&self, branch_spec: &BranchSpec, target: &Target, head: &str) -> bool {
+        if let Some(seen) = self.seen.get(branch_spec) {
+            return seen != head;
+        }
+        match self.deployments.get(target) {
+            Some(deployment) if deployment.last.branch == branch_spec.branch => {
+                deployment.last.commit_hash != head
+            },
+            // Another branch was deployed into the environment last.
+            Some(_) => false,
+            None => true,
+        }
+    }
+
+    fn update(
+        &mut self,
+        owner: &str,
+        repo: &str,
+        poll: &Poll,
+        refs: &HashMap<String, String>,
+        ctx: &mut Context<Self>,
+    ) {
+        let repo_config = self.repos.repo(owner, repo);
+        for branch in &poll.branches {
+            let reference = format!("refs/heads/{branch}");
+            let Some(head) = refs.get(&reference) else {
+                tracing::debug!("{}/{} has no branch {}", owner, repo, branch);
+                continue;
+            };
+            // Checked when the config is loaded.
+            let Some(deployed_into) = repo_config.deployed_into(GitRef::Branch(branch)) else {
+                continue;
+            };
+            let task = Task {
+                id: TaskId::generate(),
+                branch_spec: BranchSpec {
+                    owner: owner.to_owned(),
+                    repo: repo.to_owned(),
+                    branch: branch.clone(),
+                },
+                reference,
+                environment: deployed_into.environment_name(),
+                sender: None,
+                approved_by: None,
+                reason: Reason::Push,
+                url: poll.web_url.clone().unwrap_or_else(|| poll.url.clone()),
+                clone_url: poll.url.clone(),
+                commit_hash: head.clone(),
+            };
+            if !self.is_new(&task.branch_spec, &task.target(), head) {
+                continue;
+            }
+            self.seen.insert(task.branch_spec.clone(), head.clone());
+
+            // Polls can't tell who pushed.
+            let protection = repo_config.protection(task.environment.as_deref());
+            if protection.allowed_senders.is_some() {
+                tracing::warn!(
+                    "Not deploying polled commit {} into {}: only some senders may deploy there",
+                    head,
+                    task.target(),
+                );
+                continue;
+            }
+            tracing::info!("Polled new commit {} on {}", head, task.target());
+            let submit = self.pins.send(Submit(task));
+            ctx.spawn(
+                async move {
+                    match submit.await {
+                        Ok(Ok(_)) => {},
+                        err => tracing::error!("Failed to queue polled commit: {:?}", err),
+                    }
+                }
+                .into_actor(self),
+            );
+        }
+    }
+}
+
+impl Actor for Poller {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let polled: Vec<_> = self
+            .repos
+            .iter()
+            .filter_map(|(owner, repo, config)| {
+                let interval = config.poll.as_ref()?.interval.unwrap_or(DEFAULT_INTERVAL);
+                Some((owner.to_owned(), repo.to_owned(), interval))
+            })
+            .collect();
+        for (owner, repo, interval) in polled {
+            let delay = interval.mul_f64(rand::thread_rng().gen_range(0.0, 1.0));
+            ctx.run_later(delay, move |act, ctx| act.poll(owner, repo, 0, ctx));
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use actix::prelude::*;
+use actix_web::{error::BlockingError, web};
+use chrono::NaiveDateTime;
+use color_eyre::eyre;
+
+use crate::{
+    cron::Schedule,
+    deployments::Deployments,
+    freeze::Submit,
+    pins::Pins,
+    repos::{Redeploy, ReposConfig},
+    runner::{self, Reason},
+};
+
+/// When to redeploy next. Timers may fire a bit early by the wall clock, so
+/// the search starts from the previous redeploy if it's later than `now`,
+/// which keeps the same minute from being redeployed twice.
+fn next_run(
+    schedule: &Schedule,
+    now: NaiveDateTime,
+    previous: Option<NaiveDateTime>,
+) -> Option<NaiveDateTime> {
+    schedule.next_after(previous.map_or(now, |previous| previous.max(now)))
+}
+
+/// Queues scheduled redeploys of the heads of deployed branches.
+#[derive(Debug)]
+pub struct Redeploys {
+    pins: Addr<Pins>,
+    repos: Arc<ReposConfig>,
+    deployments: Arc<Deployments>,
+}
+
+impl Redeploys {
+    pub fn new(pins: Addr<Pins>, repos: Arc<ReposConfig>, deployments: Arc<Deployments>) -> Self {
+        Self {
+            pins,
+            repos,
+            deployments,
+        }
+    }
+
+    fn schedule(
+        owner: String,
+        repo: String,
+        redeploy: Redeploy,
+        previous: Option<NaiveDateTime>,
+        ctx: &mut Context<Self>,
+    ) {
+        let now = chrono::Local::now().naive_local();
+        let Some(next) = next_run(&redeploy.schedule, now, previous) else {
+            tracing::warn!(
+                "Redeploy schedule {} of {}/{} never matches",
+                redeploy.schedule,
+                owner,
+                repo,
+            );
+            return;
+        };
+        let wait = (next - now).to_std().unwrap_or_default();
+        ctx.run_later(wait, move |act, ctx| {
+            act.redeploy(&owner, &repo, &redeploy, ctx);
+            Self::schedule(owner, repo, redeploy, Some(next), ctx);
+        });
+    }
+
+    fn redeploy(&self, owner: &str, repo: &str, redeploy: &Redeploy, ctx: &mut Context<Self>) {
+        let target = redeploy.target(owner, repo);
+        let Some(deployed) = self
+            .deployments
+            .get(&target)
+            .and_then(|deployment| deployment.current)
+        else {
+            tracing::info!("Not redeploying {}: nothing is deployed yet", target);
+            return;
+        };
+
+        let repo_config = self.repos.repo(owner, repo);
+        let pins = self.pins.clone();
+        ctx.spawn(
+            async move {
+                let (url, reference) = (deployed.clone_url.clone(), deployed.reference.clone());
+                let head = web::block(move || {
+                    runner::remote_refs(&url, &repo_config)?
+                        .remove(&reference)
+                        .ok_or_else(|| eyre::eyre!("{} doesn't exist", reference))
+                })
+                .await
+                .map_err(|err| match err {
+                    BlockingError::Error(err) => err,
+                    BlockingError::Canceled => eyre::eyre!("redeploy thread was stopped"),
+                });
+                let mut task = deployed.task(&target, Reason::Scheduled);
+                match head {
+                    Ok(head) => task.commit_hash = head,
+                    Err(err) => {
+                        tracing::error!("Failed to redeploy {}: {:#}", target, err);
+                        return;
+                    },
+                }
+                task.sender = None;
+                tracing::info!("Redeploying {} at {}", target, task.commit_hash);
+                match pins.send(Submit(task)).await {
+                    Ok(Ok(_)) => {},
+                    err => tracing::error!("Failed to queue redeploy of {}: {:?}", target, err),
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+impl Actor for Redeploys {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let redeploys: Vec<_> = self
+            .repos
+            .iter()
+            .flat_map(|(owner, repo, config)| {
+                config
+                    .redeploys
+                    .iter()
+                    .map(move |redeploy| (owner.to_owned(), repo.to_owned(), redeploy.clone()))
+            })
+            .collect();
+        for (owner, repo, redeploy) in redeploys {
+            Self::schedule(owner, repo, redeploy, None, ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        repos::RepoConfig,
+        runner::{BranchSpec, Target},
+    };
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn redeploys_follow_the_schedule() {
+        let schedule: Schedule = "30 3 * * mon-fri".parse().unwrap();
+        assert_eq!(
+            next_run(&schedule, at("2024-03-14 12:00:00"), None),
+            Some(at("2024-03-15 03:30:00"))
+        );
+        // Over the weekend.
+        assert_eq!(
+            next_run(
+                &schedule,
+                at("2024-03-15 03:30:00"),
+                Some(at("2024-03-15 03:30:00"))
+            ),
+            Some(at("2024-03-18 03:30:00"))
+        );
+    }
+
+    #[test]
+    fn early_timers_dont_redeploy_twice() {
+        let schedule: Schedule = "30 3 * * *".parse().unwrap();
+        let previous = Some(at("2024-03-15 03:30:00"));
+        // Without the previous run, this would be 03:30 of the same day.
+        assert_eq!(
+            next_run(&schedule, at("2024-03-15 03:29:59"), previous),
+            Some(at("2024-03-16 03:30:00"))
+        );
+        assert_eq!(
+            next_run(&schedule, at("2024-03-15 03:30:02"), previous),
+            Some(at("2024-03-16 03:30:00"))
+        );
+        // Runs missed while the system was suspended aren't made up for.
+        assert_eq!(
+            next_run(&schedule, at("2024-03-17 12:00:00"), previous),
+            Some(at("2024-03-18 03:30:00"))
+        );
+
+        let never: Schedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(next_run(&never, at("2024-03-15 03:30:00"), None), None);
+    }
+
+    #[test]
+    fn redeploy_targets() {
+        let config: RepoConfig = toml::from_str(
+            r#"
+            redeploys = [
+                { schedule = "0 4 * * *" },
+                { schedule = "0 4 * * *", branch = "develop" },
+                { schedule = "0 4 * * *", environment = "prod" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let redeploys = &config.redeploys;
+        let branch = |name: &str| {
+            Target::Branch(BranchSpec {
+                owner: "me".to_owned(),
+                repo: "app".to_owned(),
+                branch: name.to_owned(),
+            })
+        };
+        assert_eq!(redeploys[0].target("me", "app"), branch("master"));
+        assert_eq!(redeploys[1].target("me", "app"), branch("develop"));
+        assert_eq!(redeploys[2].target("me", "app"), Target::Environment {
+            owner: "me".to_owned(),
+            repo: "app".to_owned(),
+            name: "prod".to_owned(),
+        });
+    }
+}
@@ -0,0 +1,588 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use secstr::SecUtf8;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    config::{deserialize_opt_secutf8, deserialize_secutf8},
+    cron, glob,
+    runner::{BranchSpec, Target},
+};
+
+/// Which of the URLs from the push payload is used to clone the repo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteUrl {
+    #[default]
+    Url,
+    SshUrl,
+    CloneUrl,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Credentials {
+    SshKey {
+        /// Defaults to the username from the URL.
+        username: Option<String>,
+        private_key: PathBuf,
+        public_key: Option<PathBuf>,
+        #[serde(default, deserialize_with = "deserialize_opt_secutf8")]
+        passphrase: Option<SecUtf8>,
+    },
+    SshAgent {
+        username: Option<String>,
+    },
+    Token {
+        #[serde(default = "default_token_username")]
+        username: String,
+        #[serde(deserialize_with = "deserialize_secutf8")]
+        token: SecUtf8,
+    },
+}
+
+fn default_token_username() -> String {
+    "x-access-token".into()
+}
+
+/// How decrypted secrets are passed to `docker-compose`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SecretsDelivery {
+    /// As environment variables.
+    #[default]
+    Env,
+    /// In a temporary file passed with `--env-file`, which replaces the
+    /// project's `.env`.
+    EnvFile,
+}
+
+/// Secrets read from the KV engine of a Vault-compatible server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VaultSecrets {
+    /// Path of the secret, relative to the mount point.
+    pub path: String,
+    /// Keys that must be present, otherwise the deploy fails.
+    #[serde(default)]
+    pub required: Vec<String>,
+}
+
+/// Git ref a push was made to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GitRef<'a> {
+    Branch(&'a str),
+    Tag(&'a str),
+}
+
+impl<'a> GitRef<'a> {
+    pub fn parse(reference: &'a str) -> Option<Self> {
+        reference
+            .strip_prefix("refs/heads/")
+            .map(Self::Branch)
+            .or_else(|| reference.strip_prefix("refs/tags/").map(Self::Tag))
+    }
+
+    pub fn name(self) -> &'a str {
+        match self {
+            Self::Branch(name) | Self::Tag(name) => name,
+        }
+    }
+}
+
+/// Restrictions on deploys into an environment or a branch.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Protection {
+    /// GitHub logins whose pushes are deployed. Anyone's are if unset.
+    pub allowed_senders: Option<Vec<String>>,
+    /// Deploys wait until someone approves them through the API.
+    #[serde(default)]
+    pub require_approval: bool,
+    /// Users that may approve or reject deploys, see [`User`]. Anyone with
+    /// access to the API, including the shared API token, can if unset.
+    pub approvers: Option<Vec<String>>,
+    /// How long deploys wait for approval before they're dropped. Defaults
+    /// to a day.
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub approval_timeout: Option<Duration>,
+}
+
+impl Protection {
+    pub fn allows(&self, sender: &str) -> bool {
+        self.allowed_senders.as_ref().map_or(true, |senders| {
+            senders.iter().any(|allowed| allowed == sender)
+        })
+    }
+}
+
+/// What happens to pushes that arrive during a freeze.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FreezeAction {
+    /// The latest push is deployed when the freeze ends.
+    #[default]
+    Hold,
+    Drop,
+}
+
+/// Window during which nothing is deployed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Freeze {
+    /// Without `duration` deploys are frozen during every minute the
+    /// schedule matches, e.g. `* 17-23 * * fri`. Otherwise the freeze starts
+    /// at every match and lasts for `duration`.
+    pub schedule: cron::Schedule,
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub duration: Option<Duration>,
+    #[serde(default)]
+    pub action: FreezeAction,
+    /// Shown in notifications.
+    pub reason: Option<String>,
+}
+
+/// Regular rebuild of what's deployed, pulling fresh base images even if the
+/// code didn't change.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Redeploy {
+    pub schedule: cron::Schedule,
+    /// Environment to redeploy. If not set, `branch` is redeployed.
+    pub environment: Option<String>,
+    #[serde(default = "default_branch")]
+    pub branch: String,
+}
+
+fn default_branch() -> String {
+    "master".into()
+}
+
+impl Redeploy {
+    pub fn target(&self, owner: &str, repo: &str) -> Target {
+        match &self.environment {
+            Some(name) => Target::Environment {
+                owner: owner.to_owned(),
+                repo: repo.to_owned(),
+                name: name.clone(),
+            },
+            None => Target::Branch(BranchSpec {
+                owner: owner.to_owned(),
+                repo: repo.to_owned(),
+                branch: self.branch.clone(),
+            }),
+        }
+    }
+}
+
+/// Part of a monorepo that's deployed as a compose project of its own.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Unit {
+    pub name: String,
+    /// Directory with the unit's compose file, relative to the repo root.
+    pub path: String,
+    /// Globs of files that affect the unit when changed. Defaults to
+    /// everything under `path`.
+    pub paths: Option<Vec<String>>,
+    /// Chats that get a separate notification about the unit's deploys.
+    pub telegram_groups: Option<Vec<i64>>,
+}
+
+impl Unit {
+    pub fn is_affected(&self, changed: &[String]) -> bool {
+        let default = [format!("{}/**", self.path.trim_end_matches('/'))];
+        let patterns = self.paths.as_deref().unwrap_or(&default);
+        changed
+            .iter()
+            .any(|path| patterns.iter().any(|pattern| glob::matches(pattern, path)))
+    }
+}
+
+/// Compose service that's rebuilt only when the files it's built from
+/// change.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServicePaths {
+    pub service: String,
+    /// Globs of files that affect the service, relative to the repo root.
+    pub paths: Vec<String>,
+}
+
+impl ServicePaths {
+    pub fn is_affected(&self, path: &str) -> bool {
+        self.paths
+            .iter()
+            .any(|pattern| glob::matches(pattern, path))
+    }
+}
+
+/// Tests that must pass before a deploy. They're run in a compose project of
+/// their own, so they don't touch the running deploy.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tests {
+    #[serde(default = "default_tests_compose_file")]
+    pub compose_file: String,
+    /// Service that's run, its exit status decides whether tests passed.
+    #[serde(default = "default_tests_service")]
+    pub service: String,
+}
+
+fn default_tests_compose_file() -> String {
+    "docker-compose.test.yml".into()
+}
+
+fn default_tests_service() -> String {
+    "tests".into()
+}
+
+/// Polling of a remote that can't send webhooks.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Poll {
+    /// URL the repo is fetched from.
+    pub url: String,
+    /// Link to the repo shown in notifications. Defaults to `url`.
+    pub web_url: Option<String>,
+    #[serde(default = "default_poll_branches")]
+    pub branches: Vec<String>,
+    /// Average time between polls, see [`crate::poll::DEFAULT_INTERVAL`].
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub interval: Option<Duration>,
+}
+
+fn default_poll_branches() -> Vec<String> {
+    vec![default_branch()]
+}
+
+/// Named deploy target like `staging` or `production`. All matching pushes
+/// are deployed into the same workspace and compose project.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Environment {
+    pub name: String,
+    /// Glob patterns of branches deployed into the environment.
+    #[serde(default)]
+    pub branches: Vec<String>,
+    /// Glob patterns of tags deployed into the environment.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Passed to `docker-compose` with `-f`. Note that `docker-compose.yml`
+    /// isn't read implicitly when these are set, so it has to be listed too.
+    #[serde(default)]
+    pub compose_files: Vec<String>,
+    /// Variables set for deploy commands, overriding the repo's ones.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Telegram chats notified instead of the default ones.
+    pub telegram_groups: Option<Vec<i64>>,
+    #[serde(default)]
+    pub protection: Protection,
+    /// Applied in addition to the repo's freezes.
+    #[serde(default)]
+    pub freezes: Vec<Freeze>,
+}
+
+impl Environment {
+    pub fn matches(&self, git_ref: GitRef<'_>) -> bool {
+        let patterns = match git_ref {
+            GitRef::Branch(_) => &self.branches,
+            GitRef::Tag(_) => &self.tags,
+        };
+        patterns
+            .iter()
+            .any(|pattern| glob::matches(pattern, git_ref.name()))
+    }
+}
+
+/// Shallow and partial fetches aren't supported by libgit2, so setting any of
+/// these makes adm use the `git` CLI instead.
+///
+/// Such fetches bypass the shared mirror and go straight into the branch
+/// workspace: a shallow or filtered mirror would be missing objects that
+/// other workspaces expect to borrow from it. Each workspace of the repo
+/// then fetches from the remote on its own.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FetchConfig {
+    pub depth: Option<u32>,
+    /// Partial clone filter, e.g. `blob:none`.
+    pub filter: Option<String>,
+}
+
+impl FetchConfig {
+    pub fn use_cli(&self) -> bool {
+        self.depth.is_some() || self.filter.is_some()
+    }
+}
+
+/// Where pushes to a ref are deployed.
+#[derive(Debug, Clone, Copy)]
+pub enum DeployedInto<'a> {
+    /// Into the branch's own workspace, for `master` of repos without
+    /// environments.
+    Branch,
+    Environment(&'a Environment),
+}
+
+impl DeployedInto<'_> {
+    pub fn environment_name(self) -> Option<String> {
+        match self {
+            Self::Branch => None,
+            Self::Environment(environment) => Some(environment.name.clone()),
+        }
+    }
+}
+
+/// Per-repository settings, keyed by `owner/repo` in the config file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoConfig {
+    /// Maximum duration of a single build, after which it's killed.
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub remote: RemoteUrl,
+    pub credentials: Option<Credentials>,
+    /// Used to verify SSH host keys. Defaults to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+    #[serde(default)]
+    pub fetch: FetchConfig,
+    /// Variables set for deploy commands.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Variables passed from adm's environment to deploy commands, in
+    /// addition to the global allowlist.
+    #[serde(default)]
+    pub pass_env: Vec<String>,
+    #[serde(default)]
+    pub secrets: SecretsDelivery,
+    pub vault: Option<VaultSecrets>,
+    /// Checked in order, the first matching environment is used. If there
+    /// are none, pushes to `master` are deployed.
+    #[serde(default)]
+    pub environments: Vec<Environment>,
+    /// Protection of branches that aren't deployed into an environment.
+    #[serde(default)]
+    pub protection: Protection,
+    #[serde(default)]
+    pub freezes: Vec<Freeze>,
+    #[serde(default)]
+    pub redeploys: Vec<Redeploy>,
+    pub poll: Option<Poll>,
+    /// Deployed in order. If there are none, the whole repo is deployed as
+    /// one compose project.
+    #[serde(default)]
+    pub units: Vec<Unit>,
+    /// Only services affected by changes since the deployed commit, and ones
+    /// that depend on them, are restarted. Changes to files that aren't
+    /// mapped to any service restart everything. Not used with units.
+    #[serde(default)]
+    pub services: Vec<ServicePaths>,
+    pub tests: Option<Tests>,
+    /// One-off service that migrates the database, run with `docker-compose
+    /// run --rm` after images are built and before containers are recreated.
+    /// It should be excluded from `up`, e.g. with a profile. Not used with
+    /// units.
+    pub migration: Option<String>,
+    /// Wait for services to be running or healthy after they're recreated,
+    /// failing the deploy if they don't get there.
+    #[serde(default)]
+    pub wait: bool,
+}
+
+impl RepoConfig {
+    pub fn known_hosts(&self) -> PathBuf {
+        self.known_hosts.clone().unwrap_or_else(|| {
+            let home = std::env::var_os("HOME").unwrap_or_default();
+            Path::new(&home).join(".ssh").join("known_hosts")
+        })
+    }
+
+    pub fn environment(&self, name: &str) -> Option<&Environment> {
+        self.environments.iter().find(|env| env.name == name)
+    }
+
+    pub fn environment_for(&self, git_ref: GitRef<'_>) -> Option<&Environment> {
+        self.environments.iter().find(|env| env.matches(git_ref))
+    }
+
+    /// Where pushes to `git_ref` are deployed, if they are.
+    pub fn deployed_into(&self, git_ref: GitRef<'_>) -> Option<DeployedInto<'_>> {
+        if self.environments.is_empty() {
+            (git_ref == GitRef::Branch("master")).then_some(DeployedInto::Branch)
+        } else {
+            self.environment_for(git_ref).map(DeployedInto::Environment)
+        }
+    }
+
+    /// Protection of an environment, or of plain branches if it's `None`.
+    pub fn protection(&self, environment: Option<&str>) -> &Protection {
+        environment
+            .and_then(|name| self.environment(name))
+            .map_or(&self.protection, |env| &env.protection)
+    }
+
+    /// Freezes of the repo and of the environment, if there's one.
+    pub fn freezes<'a>(&'a self, environment: Option<&str>) -> impl Iterator<Item = &'a Freeze> {
+        let env_freezes = environment
+            .and_then(|name| self.environment(name))
+            .map(|env| env.freezes.as_slice())
+            .unwrap_or_default();
+        self.freezes.iter().chain(env_freezes)
+    }
+}
+
+/// Someone who uses the API with their own token, so that approvals and
+/// freeze overrides are attributed to them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    /// Hex-encoded SHA-256 of the token, e.g. from `printf %s "$TOKEN" |
+    /// sha256sum`, so that tokens themselves aren't stored in the config.
+    #[serde(deserialize_with = "deserialize_sha256")]
+    pub token_sha256: [u8; 32],
+    /// Endpoints the token may be used for. Everything else needs the shared
+    /// API token.
+    #[serde(default = "default_scopes")]
+    pub scopes: HashSet<Scope>,
+}
+
+/// Group of API endpoints that a [`User`] may be given access to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Approving and rejecting deploys.
+    Approve,
+    /// Deploying despite a freeze.
+    OverrideFreeze,
+    /// Pinning targets to commits and unpinning them.
+    Pin,
+}
+
+fn default_scopes() -> HashSet<Scope> {
+    std::iter::once(Scope::Approve).collect()
+}
+
+fn deserialize_sha256<'de, D>(de: D) -> Result<[u8; 32], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let hash = String::deserialize(de)?;
+    let mut bytes = [0; 32];
+    hex::decode_to_slice(hash.trim(), &mut bytes).map_err(serde::de::Error::custom)?;
+    Ok(bytes)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReposConfig {
+    #[serde(default)]
+    repos: HashMap<String, RepoConfig>,
+    /// API users by their names.
+    #[serde(default)]
+    users: HashMap<String, User>,
+}
+
+impl ReposConfig {
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read repo config {}", path.display()))?;
+        let config: Self = toml::from_str(&content)
+            .wrap_err_with(|| format!("failed to parse repo config {}", path.display()))?;
+        config
+            .validate()
+            .wrap_err_with(|| format!("invalid repo config {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Checks what can't be checked while parsing.
+    fn validate(&self) -> eyre::Result<()> {
+        for (name, config) in &self.repos {
+            let Some(poll) = &config.poll else {
+                continue;
+            };
+            for branch in &poll.branches {
+                eyre::ensure!(
+                    config.deployed_into(GitRef::Branch(branch)).is_some(),
+                    "{} polls branch {}, which isn't deployed anywhere",
+                    name,
+                    branch,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, branch_spec: &BranchSpec) -> RepoConfig {
+        self.repo(&branch_spec.owner, &branch_spec.repo)
+    }
+
+    pub fn repo(&self, owner: &str, repo: &str) -> RepoConfig {
+        self.repos
+            .get(&format!("{owner}/{repo}"))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = (&str, &User)> {
+        self.users.iter().map(|(name, user)| (name.as_str(), user))
+    }
+
+    /// All configured repos with their owners and names.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &RepoConfig)> {
+        self.repos.iter().filter_map(|(name, config)| {
+            let (owner, repo) = name.split_once('/')?;
+            Some((owner, repo, config))
+        })
+    }
+}
+
+pub fn deserialize_opt_duration<'de, D>(de: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(de)?
+        .map(|s| humantime::parse_duration(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn polled_branches_must_be_deployed() {
+        let config: ReposConfig = toml::from_str(
+            r#"
+            [repos."me/app"]
+            poll = { url = "https://example.org/app.git", branches = ["master", "develop"] }
+
+            [[repos."me/app".environments]]
+            name = "staging"
+            branches = ["develop"]
+            "#,
+        )
+        .unwrap();
+        let err = config.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "me/app polls branch master, which isn't deployed anywhere"
+        );
+
+        let config: ReposConfig = toml::from_str(
+            r#"
+            [repos."me/app"]
+            poll = { url = "https://example.org/app.git" }
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+    }
+}
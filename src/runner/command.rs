@@ -0,0 +1,253 @@
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    io::{self, BufRead as _, BufReader, Read},
+    os::unix::process::CommandExt as _,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
+
+use super::control::TaskControl;
+use crate::build_log::BuildLog;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+fn forward_lines<R>(reader: R, tx: mpsc::Sender<io::Result<Vec<u8>>>)
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let mut line = Vec::new();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    if tx.send(Ok(line)).is_err() {
+                        break;
+                    }
+                },
+                Err(err) => {
+                    let _ = tx.send(Err(err));
+                    break;
+                },
+            }
+        }
+    });
+}
+
+fn signal_group(child: &Child, signal: Signal) {
+    let pid = match i32::try_from(child.id()) {
+        Ok(pid) => Pid::from_raw(pid),
+        Err(_) => return,
+    };
+    if let Err(err) = killpg(pid, signal) {
+        tracing::warn!(
+            "Failed to send {} to process group {}: {}",
+            signal,
+            pid,
+            err
+        );
+    }
+}
+
+/// Runs the command, writing its stdout and stderr to the build log line by
+/// line as they're produced.
+///
+/// The command is started in its own process group, which is terminated as a
+/// whole if the task is interrupted.
+pub fn run(
+    command: &mut Command,
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<ExitStatus> {
+    run_inspecting(command, log, control, |_| {})
+}
+
+/// Like [`run`], but also returns the last `lines` lines of the output.
+pub fn run_with_tail(
+    command: &mut Command,
+    log: &BuildLog,
+    control: &TaskControl,
+    lines: usize,
+) -> eyre::Result<(ExitStatus, Vec<String>)> {
+    let mut tail = VecDeque::with_capacity(lines);
+    let status = run_inspecting(command, log, control, |line| {
+        tail.push_back(line.to_owned());
+        if tail.len() > lines {
+            tail.pop_front();
+        }
+    })?;
+    Ok((status, tail.into()))
+}
+
+fn run_inspecting(
+    command: &mut Command,
+    log: &BuildLog,
+    control: &TaskControl,
+    mut inspect: impl FnMut(&str),
+) -> eyre::Result<ExitStatus> {
+    control.check()?;
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .wrap_err("failed to spawn command")?;
+
+    let (tx, rx) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        forward_lines(stdout, tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward_lines(stderr, tx);
+    }
+
+    let mut interrupted = None;
+    let mut killed_at = None;
+    // The loop ends when both pipes are closed.
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(Ok(line)) => {
+                let line = String::from_utf8_lossy(&line);
+                // Progress indicators redraw the line using `\r`, keep only
+                // what would be visible in the terminal.
+                let line = line.trim_end_matches(&['\r', '\n'][..]);
+                let line = line.rsplit('\r').next().unwrap_or(line);
+                log.output(line);
+                inspect(line);
+            },
+            Ok(Err(err)) => tracing::warn!("Failed to read command output: {}", err),
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        match (interrupted, killed_at) {
+            (None, _) => {
+                if let Err(reason) = control.check() {
+                    log.line(&format!("Build {reason}, terminating"));
+                    signal_group(&child, Signal::SIGTERM);
+                    interrupted = Some(reason);
+                    killed_at = Some(Instant::now());
+                }
+            },
+            (Some(_), Some(at)) if at.elapsed() >= KILL_GRACE_PERIOD => {
+                log.line("Build didn't terminate in time, killing");
+                signal_group(&child, Signal::SIGKILL);
+                killed_at = None;
+            },
+            (Some(_), _) => {},
+        }
+    }
+
+    let status = child.wait().wrap_err("failed to wait for command")?;
+    match interrupted {
+        Some(reason) => Err(reason.into()),
+        None => Ok(status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use super::*;
+    use crate::{
+        build_log::BuildLogs,
+        runner::{control::Interrupted, TaskId},
+    };
+
+    /// Whether the process exists and isn't a zombie waiting to be reaped.
+    fn is_running(pid: &str) -> bool {
+        fs::read_to_string(format!("/proc/{pid}/stat")).is_ok_and(|stat| !stat.contains(") Z "))
+    }
+
+    fn wait_for(mut condition: impl FnMut() -> bool) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(5) {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        false
+    }
+
+    /// Runs a script that leaves a process in the background, interrupts it
+    /// with `interrupt` once it's started and returns the error and whether
+    /// the background process survived.
+    fn run_interrupted(
+        name: &str,
+        interrupt: impl FnOnce(&TaskControl) + Send + 'static,
+    ) -> (eyre::Report, bool) {
+        let dir = std::env::temp_dir().join(format!("adm-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let logs = Arc::new(BuildLogs::new(dir.join("logs"), 10).unwrap());
+        let log = logs.create(TaskId::generate()).unwrap();
+        let pid_file = dir.join("pid");
+
+        let control = Arc::new(TaskControl::default());
+        let interrupter = {
+            let control = control.clone();
+            let pid_file = pid_file.clone();
+            thread::spawn(move || {
+                assert!(wait_for(|| pid_file.exists()), "script didn't start");
+                interrupt(&control);
+            })
+        };
+        let err = run(
+            Command::new("sh")
+                .arg("-c")
+                // The background process doesn't hold the output pipes, so the
+                // command finishes when the shell does.
+                .arg("sleep 60 >/dev/null 2>&1 & echo $! > \"$1\"; wait")
+                .arg("sh")
+                .arg(&pid_file),
+            &log,
+            &control,
+        )
+        .unwrap_err();
+        interrupter.join().unwrap();
+
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        let pid = pid.trim();
+        let survived = !wait_for(|| !is_running(pid));
+        if survived {
+            let _ = Command::new("kill").arg(pid).status();
+        }
+        let _ = fs::remove_dir_all(&dir);
+        (err, survived)
+    }
+
+    #[test]
+    fn cancel_terminates_process_group() {
+        let (err, survived) = run_interrupted("cancel", TaskControl::cancel);
+        assert!(matches!(
+            err.downcast_ref::<Interrupted>(),
+            Some(Interrupted::Cancelled)
+        ));
+        assert!(!survived, "background process wasn't terminated");
+    }
+
+    #[test]
+    fn timeout_terminates_process_group() {
+        let (err, survived) = run_interrupted("timeout", |control| {
+            control.set_timeout(Duration::from_millis(100));
+        });
+        assert!(matches!(
+            err.downcast_ref::<Interrupted>(),
+            Some(Interrupted::TimedOut(_))
+        ));
+        assert!(!survived, "background process wasn't terminated");
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use super::TaskId;
+
+/// Reason a build was stopped before it could finish.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+pub enum Interrupted {
+    #[error("timed out after {}", humantime::format_duration(*.0))]
+    TimedOut(Duration),
+    #[error("cancelled")]
+    Cancelled,
+}
+
+/// Shared state used to interrupt a queued or running task.
+#[derive(Debug, Default)]
+pub struct TaskControl {
+    cancelled: AtomicBool,
+    deadline: Mutex<Option<(Instant, Duration)>>,
+}
+
+impl TaskControl {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Starts the timeout clock.
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self.deadline.lock().unwrap() = Some((Instant::now() + timeout, timeout));
+    }
+
+    pub fn check(&self) -> Result<(), Interrupted> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(Interrupted::Cancelled);
+        }
+        match *self.deadline.lock().unwrap() {
+            Some((deadline, timeout)) if Instant::now() >= deadline => {
+                Err(Interrupted::TimedOut(timeout))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Registry of tasks that are either queued or running.
+#[derive(Debug, Default)]
+pub struct Tasks(DashMap<TaskId, Arc<TaskControl>>);
+
+impl Tasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&self, id: TaskId) -> Arc<TaskControl> {
+        self.0.entry(id).or_default().clone()
+    }
+
+    pub fn remove(&self, id: TaskId) {
+        self.0.remove(&id);
+    }
+
+    /// Cancels a task. Returns `false` if there's no such task.
+    pub fn cancel(&self, id: TaskId) -> bool {
+        match self.0.get(&id) {
+            Some(control) => {
+                control.cancel();
+                true
+            },
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fs,
+    io::Write as _,
+    os::unix::fs::OpenOptionsExt as _,
+    path::PathBuf,
+    process::Command,
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+
+use super::Task;
+use crate::{
+    repos::{Environment, RepoConfig, SecretsDelivery},
+    secrets::Secrets,
+};
+
+/// Variables passed from adm's own environment to deploy commands, in addition
+/// to the ones configured with `ADM_PASS_ENV` and per repo.
+const DEFAULT_PASS_ENV: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "LANG",
+    "LC_ALL",
+    "TZ",
+    "XDG_RUNTIME_DIR",
+    "SSH_AUTH_SOCK",
+    "DOCKER_HOST",
+    "DOCKER_CONTEXT",
+    "DOCKER_CONFIG",
+    "DOCKER_TLS_VERIFY",
+    "DOCKER_CERT_PATH",
+    "DOCKER_BUILDKIT",
+    "COMPOSE_DOCKER_CLI_BUILD",
+];
+
+/// Environment of deploy commands. They don't inherit adm's environment,
+/// which contains its own secrets, but only get allowlisted variables,
+/// per-repo variables and deploy metadata.
+#[derive(Debug, Default)]
+pub struct DeployEnv {
+    vars: BTreeMap<OsString, OsString>,
+    env_file: Option<EnvFile>,
+    compose_files: Vec<String>,
+}
+
+/// Temporary `.env` file with secrets, removed when dropped.
+#[derive(Debug)]
+struct EnvFile(PathBuf);
+
+impl EnvFile {
+    fn create(path: PathBuf, secrets: &Secrets) -> eyre::Result<Self> {
+        let mut content = String::new();
+        for (name, value) in secrets {
+            eyre::ensure!(
+                !value.unsecure().contains('\n'),
+                "secret {} contains a newline and can't be put into an env file",
+                name,
+            );
+            content.push_str(name);
+            content.push('=');
+            content.push_str(value.unsecure());
+            content.push('\n');
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .wrap_err_with(|| format!("failed to create directory {}", parent.display()))?;
+        }
+        let file = Self(path);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&file.0)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .wrap_err_with(|| format!("failed to write env file {}", file.0.display()))?;
+        Ok(file)
+    }
+}
+
+impl Drop for EnvFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.0) {
+            tracing::error!("Failed to remove env file {:?}: {}", self.0, err);
+        }
+    }
+}
+
+impl DeployEnv {
+    pub fn new(
+        task: &Task,
+        project_name: &str,
+        pass_env: &[String],
+        repo_config: &RepoConfig,
+        environment: Option<&Environment>,
+    ) -> Self {
+        Self::inheriting(
+            &std::env::vars_os().collect(),
+            task,
+            project_name,
+            pass_env,
+            repo_config,
+            environment,
+        )
+    }
+
+    /// Like `new`, but takes allowlisted variables from `parent` rather than
+    /// adm's own environment.
+    fn inheriting(
+        parent: &BTreeMap<OsString, OsString>,
+        task: &Task,
+        project_name: &str,
+        pass_env: &[String],
+        repo_config: &RepoConfig,
+        environment: Option<&Environment>,
+    ) -> Self {
+        let mut env = Self::default();
+        let allowlist = DEFAULT_PASS_ENV
+            .iter()
+            .copied()
+            .chain(pass_env.iter().map(String::as_str))
+            .chain(repo_config.pass_env.iter().map(String::as_str));
+        for name in allowlist {
+            if let Some(value) = parent.get(OsStr::new(name)) {
+                env.set(name, value);
+            }
+        }
+        for (name, value) in &repo_config.env {
+            env.set(name, value);
+        }
+        if let Some(environment) = environment {
+            for (name, value) in &environment.env {
+                env.set(name, value);
+            }
+            env.compose_files.clone_from(&environment.compose_files);
+            env.set("ADM_ENVIRONMENT", &environment.name);
+        }
+
+        // Metadata goes last, so that it can't be overridden.
+        let branch_spec = &task.branch_spec;
+        env.set("ADM_TASK_ID", task.id.to_string());
+        env.set(
+            "ADM_REPO",
+            format!("{}/{}", branch_spec.owner, branch_spec.repo),
+        );
+        env.set("ADM_BRANCH", &branch_spec.branch);
+        env.set("ADM_REF", &task.reference);
+        env.set("ADM_COMMIT", &task.commit_hash);
+        env.set("ADM_REASON", task.reason.to_string());
+        env.set("COMPOSE_PROJECT_NAME", project_name);
+        env
+    }
+
+    pub fn set(&mut self, name: impl Into<OsString>, value: impl Into<OsString>) {
+        self.vars.insert(name.into(), value.into());
+    }
+
+    /// Switches to the compose project of a monorepo unit.
+    pub fn set_unit(&mut self, unit: &str, project_name: &str) {
+        self.set("ADM_UNIT", unit);
+        self.set("COMPOSE_PROJECT_NAME", project_name);
+    }
+
+    /// Passes decrypted secrets either as variables or in an env file at
+    /// `env_file`.
+    pub fn add_secrets(
+        &mut self,
+        secrets: &Secrets,
+        delivery: SecretsDelivery,
+        env_file: PathBuf,
+    ) -> eyre::Result<()> {
+        match delivery {
+            SecretsDelivery::Env => {
+                for (name, value) in secrets {
+                    self.set(name, value.unsecure());
+                }
+            },
+            SecretsDelivery::EnvFile if !secrets.is_empty() => {
+                self.env_file = Some(EnvFile::create(env_file, secrets)?);
+            },
+            SecretsDelivery::EnvFile => {},
+        }
+        Ok(())
+    }
+
+    /// `docker-compose` command running in this environment.
+    pub fn compose(&self) -> Command {
+        self.compose_with(&self.compose_files)
+    }
+
+    /// `docker-compose` command for a separate project defined in `file`,
+    /// e.g. for tests.
+    pub fn compose_project(&self, file: &str, project_name: &str) -> Command {
+        let mut command = self.compose_with(&[file]);
+        command.env("COMPOSE_PROJECT_NAME", project_name);
+        command
+    }
+
+    fn compose_with(&self, files: &[impl AsRef<OsStr>]) -> Command {
+        let mut command = Command::new("docker-compose");
+        command.env_clear().envs(&self.vars);
+        for file in files {
+            command.arg("-f").arg(file);
+        }
+        if let Some(EnvFile(path)) = &self.env_file {
+            command.arg("--env-file").arg(path);
+        }
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{BranchSpec, Reason, TaskId};
+
+    fn task() -> Task {
+        Task {
+            id: TaskId(42),
+            branch_spec: BranchSpec {
+                owner: "me".to_owned(),
+                repo: "app".to_owned(),
+                branch: "master".to_owned(),
+            },
+            reference: "refs/heads/master".to_owned(),
+            environment: None,
+            sender: None,
+            approved_by: None,
+            commit_hash: "0123456789abcdef0123456789abcdef01234567".to_owned(),
+            url: String::new(),
+            clone_url: String::new(),
+            reason: Reason::Push,
+        }
+    }
+
+    fn repo_config(config: &str) -> RepoConfig {
+        toml::from_str(config).unwrap()
+    }
+
+    fn parent(vars: &[(&str, &str)]) -> BTreeMap<OsString, OsString> {
+        vars.iter()
+            .map(|(name, value)| ((*name).into(), (*value).into()))
+            .collect()
+    }
+
+    fn var<'a>(env: &'a DeployEnv, name: &str) -> Option<&'a str> {
+        env.vars
+            .get(OsStr::new(name))
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn only_allowlisted_variables_are_passed() {
+        let parent = parent(&[
+            ("PATH", "/usr/bin"),
+            ("ADM_TEST_GLOBAL", "global"),
+            ("ADM_TEST_REPO", "repo"),
+            ("ADM_TEST_SECRET", "secret"),
+        ]);
+        let config = repo_config(r#"pass_env = ["ADM_TEST_REPO"]"#);
+        let env = DeployEnv::inheriting(
+            &parent,
+            &task(),
+            "adm-me-app-master",
+            &["ADM_TEST_GLOBAL".to_owned()],
+            &config,
+            None,
+        );
+        assert_eq!(var(&env, "ADM_TEST_GLOBAL"), Some("global"));
+        assert_eq!(var(&env, "ADM_TEST_REPO"), Some("repo"));
+        assert_eq!(var(&env, "ADM_TEST_SECRET"), None);
+        assert_eq!(var(&env, "PATH"), Some("/usr/bin"));
+        assert_eq!(var(&env, "HOME"), None);
+    }
+
+    #[test]
+    fn configured_variables_override_in_order() {
+        let config = repo_config(
+            r#"
+            pass_env = ["ADM_TEST_OVERRIDDEN"]
+            env = { ADM_TEST_OVERRIDDEN = "repo", REPO_ONLY = "repo", ADM_COMMIT = "fake" }
+
+            [[environments]]
+            name = "prod"
+            branches = ["master"]
+            compose_files = ["docker-compose.yml", "prod.yml"]
+            env = { REPO_ONLY = "prod", COMPOSE_PROJECT_NAME = "fake" }
+            "#,
+        );
+        let env = DeployEnv::inheriting(
+            &parent(&[("ADM_TEST_OVERRIDDEN", "adm")]),
+            &task(),
+            "adm-me-app-env-prod",
+            &[],
+            &config,
+            config.environments.first(),
+        );
+        assert_eq!(var(&env, "ADM_TEST_OVERRIDDEN"), Some("repo"));
+        assert_eq!(var(&env, "REPO_ONLY"), Some("prod"));
+        assert_eq!(var(&env, "ADM_ENVIRONMENT"), Some("prod"));
+        assert_eq!(env.compose_files, ["docker-compose.yml", "prod.yml"]);
+        // Metadata can't be overridden.
+        assert_eq!(
+            var(&env, "ADM_COMMIT"),
+            Some("0123456789abcdef0123456789abcdef01234567")
+        );
+        assert_eq!(
+            var(&env, "COMPOSE_PROJECT_NAME"),
+            Some("adm-me-app-env-prod")
+        );
+        assert_eq!(var(&env, "ADM_TASK_ID"), Some("42"));
+        assert_eq!(var(&env, "ADM_REPO"), Some("me/app"));
+    }
+
+    #[test]
+    fn compose_doesnt_inherit_environment() {
+        let mut env = DeployEnv::default();
+        env.set("FOO", "bar");
+        let command = env.compose_project("docker-compose.test.yml", "adm-tests");
+        let envs: BTreeMap<_, _> = command.get_envs().collect();
+        assert_eq!(envs.get(OsStr::new("FOO")), Some(&Some(OsStr::new("bar"))));
+        assert_eq!(
+            envs.get(OsStr::new("COMPOSE_PROJECT_NAME")),
+            Some(&Some(OsStr::new("adm-tests")))
+        );
+        assert_eq!(envs.len(), 2);
+        assert!(format!("{command:?}").starts_with("env -i "));
+        let args: Vec<_> = command.get_args().collect();
+        assert_eq!(args, ["-f", "docker-compose.test.yml"]);
+    }
+
+    #[test]
+    fn env_file_rejects_multiline_secrets() {
+        let path = std::env::temp_dir().join(format!("adm-test-{}.env", std::process::id()));
+        let mut secrets = Secrets::new();
+        secrets.insert("KEY".to_owned(), "line\nbreak".into());
+        let mut env = DeployEnv::default();
+        assert!(env
+            .add_secrets(&secrets, SecretsDelivery::EnvFile, path.clone())
+            .is_err());
+        assert!(!path.exists());
+    }
+}
@@ -1,47 +1,403 @@
-use std::path::Path;
+use std::{cell::Cell, collections::HashMap, path::Path, process::Command};
 
-pub fn open_or_clone(
-    url: &str,
-    path: &Path,
-) -> Result<git2::Repository, (git2::Error, git2::Error)> {
-    match git2::Repository::open(&path) {
+use color_eyre::eyre::{self, WrapErr as _};
+use secstr::SecUtf8;
+
+use super::{
+    command,
+    control::TaskControl,
+    known_hosts::{self, KnownHosts, Verdict},
+};
+use crate::{
+    build_log::BuildLog,
+    repos::{Credentials, FetchConfig},
+};
+
+/// Everything needed to authenticate to the remote and verify its identity.
+#[derive(Debug)]
+pub struct RemoteAuth<'a> {
+    pub credentials: Option<&'a Credentials>,
+    pub known_hosts: Option<KnownHosts>,
+    /// Host in the `known_hosts` format.
+    pub host: Option<String>,
+}
+
+impl<'a> RemoteAuth<'a> {
+    pub fn new(url: &str, credentials: Option<&'a Credentials>, known_hosts: &Path) -> Self {
+        let known_hosts = match KnownHosts::load(known_hosts) {
+            Ok(known_hosts) => Some(known_hosts),
+            Err(err) => {
+                tracing::warn!("Failed to read known hosts from {:?}: {}", known_hosts, err);
+                None
+            },
+        };
+        Self {
+            credentials,
+            known_hosts,
+            host: ssh_host(url),
+        }
+    }
+
+    pub fn callbacks<'b>(&'b self, control: &'b TaskControl) -> git2::RemoteCallbacks<'b> {
+        let mut callbacks = git2::RemoteCallbacks::new();
+        let tried = Cell::new(git2::CredentialType::empty());
+        callbacks.credentials(move |_url, username_from_url, allowed| {
+            let cred = self.credentials(username_from_url, allowed)?;
+            // libgit2 calls this again and again until it succeeds, so fail
+            // if the same kind of credentials was already rejected.
+            let credtype = git2::CredentialType::from_bits_truncate(cred.credtype());
+            if tried.get().contains(credtype) {
+                return Err(git2::Error::from_str("authentication failed"));
+            }
+            tried.set(tried.get() | credtype);
+            Ok(cred)
+        });
+        // Only SSH host keys are checked here. libgit2 validates TLS
+        // certificates by itself, but calls this callback for every TLS
+        // connection and would accept any certificate it approves.
+        if self.host.is_some() {
+            callbacks
+                .certificate_check(move |cert, hostname| self.check_certificate(cert, hostname));
+        }
+        callbacks.sideband_progress(move |data| {
+            for line in String::from_utf8_lossy(data).split(&['\r', '\n'][..]) {
+                if !line.trim().is_empty() {
+                    tracing::debug!("remote: {}", line.trim());
+                }
+            }
+            control.check().is_ok()
+        });
+        let mut reported = 0;
+        callbacks.transfer_progress(move |progress| {
+            // Report every 10%, returning `false` aborts the transfer.
+            let total = progress.total_objects();
+            let received = progress.received_objects();
+            let percent = (received * 100).checked_div(total).unwrap_or(0);
+            if total > 0 && (percent >= reported + 10 || (received == total && reported < 100)) {
+                reported = percent - percent % 10;
+                tracing::info!(
+                    "Receiving objects: {}% ({}/{}), {} bytes",
+                    percent,
+                    received,
+                    total,
+                    progress.received_bytes(),
+                );
+            }
+            control.check().is_ok()
+        });
+        callbacks
+    }
+
+    pub fn fetch_options<'b>(&'b self, control: &'b TaskControl) -> git2::FetchOptions<'b> {
+        let mut options = git2::FetchOptions::new();
+        options.remote_callbacks(self.callbacks(control));
+        options
+    }
+
+    /// Environment for the `git` CLI which makes it use the same
+    /// credentials and known hosts.
+    pub fn git_env(&self, known_hosts: &Path) -> Result<Vec<(String, String)>, String> {
+        let mut ssh_command = format!(
+            "ssh -o StrictHostKeyChecking=yes -o UserKnownHostsFile={}",
+            shell_quote(&known_hosts.to_string_lossy()),
+        );
+        let mut env = vec![("GIT_TERMINAL_PROMPT".to_owned(), "0".to_owned())];
+        match self.credentials {
+            Some(Credentials::SshKey {
+                passphrase: Some(_),
+                ..
+            }) => {
+                return Err("SSH keys with passphrase can't be used with the `git` CLI".into());
+            },
+            Some(Credentials::SshKey {
+                username,
+                private_key,
+                ..
+            }) => {
+                ssh_command.push_str(" -o IdentitiesOnly=yes -i ");
+                ssh_command.push_str(&shell_quote(&private_key.to_string_lossy()));
+                if let Some(username) = username {
+                    ssh_command.push_str(" -l ");
+                    ssh_command.push_str(&shell_quote(username));
+                }
+            },
+            Some(Credentials::SshAgent {
+                username: Some(username),
+            }) => {
+                ssh_command.push_str(" -l ");
+                ssh_command.push_str(&shell_quote(username));
+            },
+            Some(Credentials::Token { username, token }) => {
+                // Passed through the environment so that the token doesn't
+                // show up in the process list.
+                let basic = base64::encode(format!("{}:{}", username, token.unsecure()));
+                env.push(("GIT_CONFIG_COUNT".into(), "1".into()));
+                env.push(("GIT_CONFIG_KEY_0".into(), "http.extraHeader".into()));
+                env.push((
+                    "GIT_CONFIG_VALUE_0".into(),
+                    format!("Authorization: Basic {basic}"),
+                ));
+            },
+            Some(Credentials::SshAgent { username: None }) | None => {},
+        }
+        env.push(("GIT_SSH_COMMAND".into(), ssh_command));
+        Ok(env)
+    }
+
+    fn credentials(
+        &self,
+        username_from_url: Option<&str>,
+        allowed: git2::CredentialType,
+    ) -> Result<git2::Cred, git2::Error> {
+        let default_username = || username_from_url.unwrap_or("git");
+        match self.credentials {
+            Some(Credentials::SshKey {
+                username,
+                private_key,
+                public_key,
+                passphrase,
+            }) if allowed.contains(git2::CredentialType::SSH_KEY) => git2::Cred::ssh_key(
+                username.as_deref().unwrap_or_else(default_username),
+                public_key.as_deref(),
+                private_key,
+                passphrase.as_ref().map(SecUtf8::unsecure),
+            ),
+            Some(Credentials::SshAgent { username })
+                if allowed.contains(git2::CredentialType::SSH_KEY) =>
+            {
+                git2::Cred::ssh_key_from_agent(username.as_deref().unwrap_or_else(default_username))
+            },
+            Some(Credentials::Token { username, token })
+                if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) =>
+            {
+                git2::Cred::userpass_plaintext(username, token.unsecure())
+            },
+            _ if allowed.contains(git2::CredentialType::USERNAME) => {
+                git2::Cred::username(default_username())
+            },
+            Some(_) => Err(git2::Error::from_str(&format!(
+                "configured credentials can't be used, remote requires {allowed:?}",
+            ))),
+            None => Err(git2::Error::from_str(
+                "remote requires authentication, but no credentials are configured",
+            )),
+        }
+    }
+
+    fn check_certificate(&self, cert: &git2::cert::Cert<'_>, hostname: &str) -> bool {
+        match self.verify_host_key(cert, hostname) {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::error!("Failed to verify identity of {}: {}", hostname, err);
+                false
+            },
+        }
+    }
+
+    fn verify_host_key(&self, cert: &git2::cert::Cert<'_>, hostname: &str) -> Result<(), String> {
+        let hostkey = cert.as_hostkey().ok_or("expected an SSH host key")?;
+        let key_sha256 = hostkey
+            .hash_sha256()
+            .ok_or("SSH host key has no SHA-256 hash")?;
+        let known_hosts = self
+            .known_hosts
+            .as_ref()
+            .ok_or("no known hosts to verify SSH host key")?;
+
+        let host = self.host.as_deref().unwrap_or(hostname);
+        match known_hosts.verify(host, key_sha256) {
+            Verdict::Trusted => Ok(()),
+            verdict => Err(format!(
+                "SSH host key verification failed for {} ({:?}), key SHA256:{}",
+                host,
+                verdict,
+                base64::encode(key_sha256).trim_end_matches('='),
+            )),
+        }
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Extracts host and port from an SSH URL, either `ssh://[user@]host[:port]/`
+/// or scp-like `[user@]host:path`.
+fn ssh_host(url: &str) -> Option<String> {
+    if let Some(rest) = url.strip_prefix("ssh://") {
+        let authority = rest.split('/').next()?;
+        let authority = authority.rsplit('@').next()?;
+        let (host, port) = match authority.rfind(':') {
+            Some(idx) if !authority.ends_with(']') => {
+                (&authority[..idx], authority[idx + 1..].parse().ok())
+            },
+            _ => (authority, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        return Some(known_hosts::host_key(host, port));
+    }
+    if url.contains("://") {
+        return None;
+    }
+    let (authority, _path) = url.split_at(url.find(':')?);
+    Some(authority.rsplit('@').next()?.to_owned())
+}
+
+/// Opens the repo at `path` or initializes a new one. The caller must hold
+/// the lock for the repo, since lock files left by earlier runs are removed.
+pub fn open_or_init(url: &str, path: &Path, bare: bool) -> Result<git2::Repository, git2::Error> {
+    match git2::Repository::open(path) {
         Ok(repo) => {
             tracing::info!(
                 path = path.to_string_lossy().as_ref(),
                 "Opened repo at {:?}",
                 path
             );
+            remove_stale_locks(repo.path());
             Ok(repo)
         },
-        Err(open_err) => match git2::Repository::clone(url, &path) {
-            Ok(repo) => {
-                tracing::info!(
-                    url = url,
-                    path = path.to_string_lossy().as_ref(),
-                    "Cloned repo {} to {:?}",
-                    url,
-                    path
-                );
-                Ok(repo)
-            },
-            Err(clone_err) => {
-                tracing::error!(
-                    url = url,
-                    path = path.to_string_lossy().as_ref(),
-                    concat!(
-                        "Failed to either open or clone repository. ",
-                        "Open error: {}. Clone error: {}",
-                    ),
-                    open_err,
-                    clone_err,
-                );
-                Err((open_err, clone_err))
-            },
+        // Anything other than a missing repo means that it's broken and
+        // mustn't be reinitialized in place.
+        Err(open_err) if open_err.code() != git2::ErrorCode::NotFound => {
+            tracing::error!(
+                path = path.to_string_lossy().as_ref(),
+                "Failed to open repository: {}",
+                open_err,
+            );
+            Err(open_err)
+        },
+        Err(_) => {
+            match git2::Repository::init_opts(path, git2::RepositoryInitOptions::new().bare(bare))
+                .and_then(|repo| {
+                    repo.remote("origin", url)?;
+                    Ok(repo)
+                }) {
+                Ok(repo) => {
+                    tracing::info!(
+                        url = url,
+                        path = path.to_string_lossy().as_ref(),
+                        "Initialized repo for {} at {:?}",
+                        url,
+                        path
+                    );
+                    Ok(repo)
+                },
+                Err(init_err) => {
+                    tracing::error!(
+                        url = url,
+                        path = path.to_string_lossy().as_ref(),
+                        "Failed to initialize repository: {}",
+                        init_err,
+                    );
+                    Err(init_err)
+                },
+            }
+        },
+    }
+}
+
+/// Removes lock files left behind by an interrupted git process, e.g. if adm
+/// was killed in the middle of a checkout.
+fn remove_stale_locks(git_dir: &Path) {
+    remove_lock_files(git_dir, false);
+    remove_lock_files(&git_dir.join("refs"), true);
+}
+
+fn remove_lock_files(dir: &Path, recursive: bool) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Failed to look for stale locks in {:?}: {}", dir, err);
+            }
+            return;
         },
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            if recursive {
+                remove_lock_files(&path, true);
+            }
+        } else if path.extension() == Some("lock".as_ref()) {
+            tracing::warn!("Removing stale lock {:?}", path);
+            if let Err(err) = std::fs::remove_file(&path) {
+                tracing::warn!("Failed to remove stale lock {:?}: {}", path, err);
+            }
+        }
     }
 }
 
-pub fn pull_repo(repo: &mut git2::Repository) -> Result<(), git2::Error> {
+/// Points `origin` to the URL, e.g. if the repo was renamed or transferred.
+pub fn ensure_origin_url(repo: &git2::Repository, url: &str) -> Result<(), git2::Error> {
+    let origin = repo.find_remote("origin")?;
+    if origin.url() == Some(url) {
+        return Ok(());
+    }
+    tracing::info!(
+        "Changing URL of `origin` from {} to {}",
+        origin.url().unwrap_or("<non-UTF-8 URL>"),
+        url,
+    );
+    repo.remote_set_url("origin", url)
+}
+
+/// Checks whether the error means that the repository itself is broken, as
+/// opposed to e.g. network or authentication problems.
+pub fn is_corruption(err: &eyre::Report) -> bool {
+    use git2::{ErrorClass, ErrorCode};
+
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<git2::Error>())
+        .any(|err| match err.code() {
+            ErrorCode::HashsumMismatch => true,
+            // Stale lock files are removed when the repo is opened, so this
+            // is another process working on it.
+            ErrorCode::Locked
+            | ErrorCode::NotFound
+            | ErrorCode::InvalidSpec
+            | ErrorCode::Auth
+            | ErrorCode::Certificate
+            | ErrorCode::User => false,
+            _ => matches!(
+                err.class(),
+                ErrorClass::Odb
+                    | ErrorClass::Zlib
+                    | ErrorClass::Index
+                    | ErrorClass::Object
+                    | ErrorClass::Reference
+                    | ErrorClass::Repository
+                    | ErrorClass::Config
+            ),
+        })
+}
+
+/// Ref that [`refspec`] fetches the given ref into.
+pub fn tracking_ref(reference: &str) -> String {
+    match reference.strip_prefix("refs/heads/") {
+        Some(branch) => format!("refs/remotes/origin/{branch}"),
+        None => reference.to_owned(),
+    }
+}
+
+/// Refspec which fetches exactly the given ref into its remote-tracking ref.
+pub fn refspec(reference: &str) -> String {
+    format!("+{}:{}", reference, tracking_ref(reference))
+}
+
+/// Refspec which fetches exactly the given ref into the ref with the same
+/// name, as in mirrors.
+pub fn mirror_refspec(reference: &str) -> String {
+    format!("+{reference}:{reference}")
+}
+
+pub fn fetch(
+    repo: &mut git2::Repository,
+    reference: &str,
+    refspec: &str,
+    auth: &RemoteAuth<'_>,
+    control: &TaskControl,
+) -> Result<(), git2::Error> {
     let mut origin = match repo.find_remote("origin") {
         Ok(remote) => remote,
         Err(err) => {
@@ -50,14 +406,129 @@ pub fn pull_repo(repo: &mut git2::Repository) -> Result<(), git2::Error> {
         },
     };
 
-    if let Err(err) = origin.fetch(&["master"], None, None) {
-        tracing::error!("Failed to fetch origin/master: {}", err);
+    let mut options = auth.fetch_options(control);
+    options.prune(git2::FetchPrune::On);
+    if let Err(err) = origin.fetch(&[refspec], Some(&mut options), None) {
+        tracing::error!("Failed to fetch {}: {}", reference, err);
         return Err(err);
     }
 
+    let stats = origin.stats();
+    tracing::info!(
+        "Fetched {}: {} objects, {} bytes",
+        reference,
+        stats.received_objects(),
+        stats.received_bytes(),
+    );
+    Ok(())
+}
+
+/// Asks the remote which commits its refs point to, without fetching
+/// anything.
+pub fn ls_remote(
+    url: &str,
+    auth: &RemoteAuth<'_>,
+    control: &TaskControl,
+) -> Result<HashMap<String, git2::Oid>, git2::Error> {
+    let mut remote = git2::Remote::create_detached(url)?;
+    let connection =
+        remote.connect_auth(git2::Direction::Fetch, Some(auth.callbacks(control)), None)?;
+    Ok(connection
+        .list()?
+        .iter()
+        .map(|head| (head.name().to_owned(), head.oid()))
+        .collect())
+}
+
+/// Makes the repo borrow objects from another object database. Returns
+/// whether anything changed, in which case the repo must be reopened.
+pub fn set_alternates(repo: &git2::Repository, objects: &Path) -> std::io::Result<bool> {
+    let info = repo.path().join("objects").join("info");
+    let alternates = info.join("alternates");
+    let content = format!("{}\n", objects.display());
+    match std::fs::read_to_string(&alternates) {
+        Ok(current) if current == content => return Ok(false),
+        Ok(_) => {},
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {},
+        Err(err) => return Err(err),
+    }
+    std::fs::create_dir_all(&info)?;
+    std::fs::write(&alternates, content)?;
+    tracing::info!("Set alternates of {:?} to {:?}", repo.path(), objects);
+    Ok(true)
+}
+
+/// Finds the commit, peeling annotated tags: for tag pushes the webhook
+/// reports the ID of the tag object rather than of the commit.
+fn find_commit(repo: &git2::Repository, oid: git2::Oid) -> Result<git2::Commit<'_>, git2::Error> {
+    repo.find_object(oid, None)?.peel_to_commit()
+}
+
+/// Commit that the ref points to, peeling annotated tags.
+pub fn ref_commit(repo: &git2::Repository, name: &str) -> Result<git2::Oid, git2::Error> {
+    Ok(repo.find_reference(name)?.peel_to_commit()?.id())
+}
+
+/// Whether `commit_id`, possibly an annotated tag, is the commit `oid`.
+pub fn is_commit(
+    repo: &git2::Repository,
+    commit_id: &str,
+    oid: git2::Oid,
+) -> Result<bool, git2::Error> {
+    Ok(find_commit(repo, commit_id.parse()?)?.id() == oid)
+}
+
+/// Checks that the commit is present after fetching. If it isn't, the branch
+/// was most likely force-pushed after the commit.
+pub fn ensure_commit(repo: &git2::Repository, commit_id: &str) -> Result<(), git2::Error> {
+    let oid: git2::Oid = commit_id.parse()?;
+    if let Err(err) = find_commit(repo, oid) {
+        tracing::error!(
+            "Commit `{}` wasn't fetched, the branch was probably force-pushed: {}",
+            oid,
+            err
+        );
+        return Err(err);
+    }
     Ok(())
 }
 
+/// Whether `commit_id` is a strict ancestor of `descendant_id`. Commits that
+/// aren't in the repo, e.g. because of a force-push, aren't descendants of
+/// anything.
+pub fn is_ancestor(
+    repo: &git2::Repository,
+    commit_id: &str,
+    descendant_id: &str,
+) -> Result<bool, git2::Error> {
+    let commit = find_commit(repo, commit_id.parse()?)?;
+    let Ok(descendant) = find_commit(repo, descendant_id.parse()?) else {
+        return Ok(false);
+    };
+    repo.graph_descendant_of(descendant.id(), commit.id())
+}
+
+/// Paths of files that differ between two commits. Both old and new paths
+/// of renamed files are included.
+pub fn changed_paths(
+    repo: &git2::Repository,
+    from: &str,
+    to: &str,
+) -> Result<Vec<String>, git2::Error> {
+    let from = find_commit(repo, from.parse()?)?.tree()?;
+    let to = find_commit(repo, to.parse()?)?.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&from), Some(&to), None)?;
+    let mut paths: Vec<_> = diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .filter_map(|path| path.to_str().map(str::to_owned))
+        .collect();
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
 pub fn checkout(repo: &mut git2::Repository, commit_id: &str) -> Result<(), git2::Error> {
     let oid: git2::Oid = match commit_id.parse() {
         Ok(oid) => oid,
@@ -66,7 +537,7 @@ pub fn checkout(repo: &mut git2::Repository, commit_id: &str) -> Result<(), git2
             return Err(err);
         },
     };
-    let commit = match repo.find_commit(oid) {
+    let commit = match find_commit(repo, oid) {
         Ok(commit) => commit,
         Err(err) => {
             tracing::error!("Failed to find commit `{}`: {}", oid, err);
@@ -87,3 +558,275 @@ pub fn checkout(repo: &mut git2::Repository, commit_id: &str) -> Result<(), git2
     }
     Ok(())
 }
+
+/// Fetches with the `git` CLI, which unlike libgit2 supports shallow and
+/// partial fetches.
+pub fn fetch_with_cli(
+    path: &Path,
+    reference: &str,
+    fetch: &FetchConfig,
+    env: &[(String, String)],
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    let mut command = Command::new("git");
+    command
+        .current_dir(path)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .args(["fetch", "--prune", "--progress", "--no-tags"]);
+    if let Some(depth) = fetch.depth {
+        command.arg(format!("--depth={depth}"));
+    }
+    if let Some(filter) = &fetch.filter {
+        command.arg(format!("--filter={filter}"));
+    }
+    command.arg("origin").arg(refspec(reference));
+    run_git(&mut command, log, control)
+}
+
+/// Checks out the commit with the `git` CLI. Needed for partial clones, since
+/// libgit2 can't fetch missing objects.
+pub fn checkout_with_cli(
+    path: &Path,
+    commit_id: &str,
+    env: &[(String, String)],
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    for args in &[&["reset", "--hard", commit_id][..], &["clean", "-ffd"][..]] {
+        run_git(
+            Command::new("git")
+                .current_dir(path)
+                .envs(env.iter().map(|(k, v)| (k, v)))
+                .args(*args),
+            log,
+            control,
+        )?;
+    }
+    Ok(())
+}
+
+fn run_git(command: &mut Command, log: &BuildLog, control: &TaskControl) -> eyre::Result<()> {
+    let status = command::run(command, log, control).wrap_err("failed to run `git`")?;
+    if status.success() {
+        Ok(())
+    } else {
+        eyre::bail!("`git` returned failure ({})", status)
+    }
+}
+
+/// Recursively initializes, fetches and checks out submodules at the commits
+/// recorded in the superproject, using the superproject's credentials.
+pub fn update_submodules(
+    repo: &git2::Repository,
+    credentials: Option<&Credentials>,
+    known_hosts: &Path,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    for mut submodule in repo.submodules().wrap_err("failed to list submodules")? {
+        let name = submodule.name().unwrap_or("<non-UTF-8 name>").to_owned();
+        let update = |submodule: &mut git2::Submodule<'_>| -> eyre::Result<()> {
+            control.check()?;
+            submodule.sync().wrap_err("failed to sync URL")?;
+            let url = submodule.url().unwrap_or_default().to_owned();
+            let auth = RemoteAuth::new(&url, credentials, known_hosts);
+            let mut checkout = git2::build::CheckoutBuilder::new();
+            checkout.force().remove_untracked(true);
+            let mut options = git2::SubmoduleUpdateOptions::new();
+            options
+                .checkout(checkout)
+                .fetch(auth.fetch_options(control));
+            submodule
+                .update(true, Some(&mut options))
+                .map_err(|err| match control.check() {
+                    Err(interrupted) => eyre::Report::new(interrupted),
+                    Ok(()) => eyre::Report::new(err),
+                })
+                .wrap_err_with(|| format!("failed to update from {url}"))?;
+            tracing::info!(
+                "Updated submodule `{}` to {}",
+                submodule.path().display(),
+                submodule
+                    .index_id()
+                    .map(|id| id.to_string())
+                    .unwrap_or_default(),
+            );
+
+            let subrepo = submodule.open().wrap_err("failed to open")?;
+            update_submodules(&subrepo, credentials, known_hosts, control)
+        };
+        update(&mut submodule).wrap_err_with(|| format!("failed to update submodule `{name}`"))?;
+    }
+    Ok(())
+}
+
+/// Same as [`update_submodules`], but with the `git` CLI.
+pub fn update_submodules_with_cli(
+    path: &Path,
+    env: &[(String, String)],
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    let commands: [&[&str]; 3] = [
+        &["submodule", "sync", "--recursive"],
+        &[
+            "submodule",
+            "update",
+            "--init",
+            "--recursive",
+            "--force",
+            "--progress",
+        ],
+        &["submodule", "foreach", "--recursive", "git clean -ffd"],
+    ];
+    for args in &commands {
+        run_git(
+            Command::new("git")
+                .current_dir(path)
+                .envs(env.iter().map(|(k, v)| (k, v)))
+                .args(*args),
+            log,
+            control,
+        )
+        .wrap_err("failed to update submodules")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh_hosts() {
+        assert_eq!(
+            ssh_host("git@github.com:owner/repo.git").as_deref(),
+            Some("github.com")
+        );
+        assert_eq!(
+            ssh_host("ssh://git@github.com/owner/repo.git").as_deref(),
+            Some("github.com")
+        );
+        assert_eq!(
+            ssh_host("ssh://git@git.example.org:2222/repo.git").as_deref(),
+            Some("[git.example.org]:2222")
+        );
+        assert_eq!(
+            ssh_host("ssh://[::1]:2222/repo.git").as_deref(),
+            Some("[::1]:2222")
+        );
+        assert_eq!(ssh_host("https://github.com/owner/repo.git"), None);
+        assert_eq!(ssh_host("/srv/git/repo.git"), None);
+    }
+
+    /// Commits `file` with the given content and tags the commit with an
+    /// annotated tag, returning the ID of the tag object.
+    fn commit_and_tag(repo: &git2::Repository, file: &str, tag: &str) -> String {
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join(file), tag).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(file)).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("adm", "adm@example.org").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let commit = repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                tag,
+                &tree,
+                parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+            )
+            .unwrap();
+        let commit = repo.find_object(commit, None).unwrap();
+        repo.tag(tag, &commit, &signature, tag, false)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn stale_locks_are_removed() {
+        let path = std::env::temp_dir().join(format!("adm-test-locks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        git2::Repository::init(&path).unwrap();
+        let git_dir = path.join(".git");
+        let locks = [
+            git_dir.join("index.lock"),
+            git_dir.join("refs").join("heads").join("master.lock"),
+            git_dir
+                .join("refs")
+                .join("remotes")
+                .join("origin")
+                .join("master.lock"),
+        ];
+        for lock in &locks {
+            std::fs::create_dir_all(lock.parent().unwrap()).unwrap();
+            std::fs::write(lock, "").unwrap();
+        }
+
+        open_or_init("/srv/git/repo.git", &path, false).unwrap();
+        for lock in &locks {
+            assert!(!lock.exists(), "{:?} wasn't removed", lock);
+        }
+        assert!(git_dir.join("HEAD").exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn corruption_errors() {
+        use git2::{ErrorClass, ErrorCode};
+
+        let corruption = |code, class| {
+            let err = eyre::Report::new(git2::Error::new(code, class, "test"))
+                .wrap_err("failed to fetch repo");
+            is_corruption(&err)
+        };
+        assert!(corruption(ErrorCode::GenericError, ErrorClass::Odb));
+        assert!(corruption(ErrorCode::GenericError, ErrorClass::Index));
+        assert!(corruption(ErrorCode::HashsumMismatch, ErrorClass::Net));
+        assert!(!corruption(ErrorCode::Locked, ErrorClass::Index));
+        assert!(!corruption(ErrorCode::NotFound, ErrorClass::Reference));
+        assert!(!corruption(ErrorCode::Auth, ErrorClass::Ssh));
+        assert!(!corruption(ErrorCode::GenericError, ErrorClass::Net));
+        assert!(!is_corruption(&eyre::eyre!("failed to deploy")));
+    }
+
+    #[test]
+    fn refspecs() {
+        assert_eq!(
+            refspec("refs/heads/main"),
+            "+refs/heads/main:refs/remotes/origin/main"
+        );
+        assert_eq!(tracking_ref("refs/heads/main"), "refs/remotes/origin/main");
+        assert_eq!(refspec("refs/tags/v1"), "+refs/tags/v1:refs/tags/v1");
+        assert_eq!(
+            mirror_refspec("refs/heads/main"),
+            "+refs/heads/main:refs/heads/main"
+        );
+    }
+
+    #[test]
+    fn annotated_tags_are_peeled() {
+        let path = std::env::temp_dir().join(format!("adm-test-tags-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let mut repo = git2::Repository::init(&path).unwrap();
+        let v1 = commit_and_tag(&repo, "a.txt", "v1");
+        let v2 = commit_and_tag(&repo, "b.txt", "v2");
+        assert!(repo.find_commit(v1.parse().unwrap()).is_err());
+
+        ensure_commit(&repo, &v1).unwrap();
+        let tagged = ref_commit(&repo, "refs/tags/v2").unwrap();
+        assert!(is_commit(&repo, &v2, tagged).unwrap());
+        assert!(!is_commit(&repo, &v1, tagged).unwrap());
+        assert!(is_ancestor(&repo, &v1, &v2).unwrap());
+        assert!(!is_ancestor(&repo, &v2, &v1).unwrap());
+        assert!(!is_ancestor(&repo, &v2, &v2).unwrap());
+        assert_eq!(changed_paths(&repo, &v1, &v2).unwrap(), ["b.txt"]);
+        checkout(&mut repo, &v1).unwrap();
+        assert!(!path.join("b.txt").exists());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+//! Minimal `known_hosts` parser, used to verify SSH host keys since libgit2
+//! doesn't do it by itself.
+
+use std::{io, path::Path};
+
+use hmac::{Mac as _, NewMac as _};
+use sha2::Digest as _;
+
+use crate::glob;
+
+#[derive(Debug)]
+struct Entry {
+    revoked: bool,
+    hosts: String,
+    key_sha256: [u8; 32],
+}
+
+#[derive(Debug, Default)]
+pub struct KnownHosts(Vec<Entry>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Trusted,
+    Revoked,
+    Mismatch,
+    Unknown,
+}
+
+impl KnownHosts {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let entries = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let mut hosts = fields.next()?;
+                let mut revoked = false;
+                if let Some(marker) = hosts.strip_prefix('@') {
+                    match marker {
+                        "revoked" => revoked = true,
+                        // Certificate authorities aren't supported.
+                        _ => return None,
+                    }
+                    hosts = fields.next()?;
+                }
+                let _key_type = fields.next()?;
+                let key = base64::decode(fields.next()?).ok()?;
+                let mut key_sha256 = [0; 32];
+                key_sha256.copy_from_slice(&sha2::Sha256::digest(&key));
+                Some(Entry {
+                    revoked,
+                    hosts: hosts.to_owned(),
+                    key_sha256,
+                })
+            })
+            .collect();
+        Self(entries)
+    }
+
+    /// Checks the SHA-256 hash of the host key against the entries for the
+    /// host. `host` must be in the `known_hosts` format, i.e. `[host]:port`
+    /// for non-standard ports.
+    pub fn verify(&self, host: &str, key_sha256: &[u8; 32]) -> Verdict {
+        let mut known = false;
+        for entry in self
+            .0
+            .iter()
+            .filter(|entry| hosts_match(&entry.hosts, host))
+        {
+            if entry.key_sha256 == *key_sha256 {
+                if entry.revoked {
+                    return Verdict::Revoked;
+                }
+                return Verdict::Trusted;
+            }
+            known |= !entry.revoked;
+        }
+        if known {
+            Verdict::Mismatch
+        } else {
+            Verdict::Unknown
+        }
+    }
+}
+
+/// Formats host and port the way they're written in `known_hosts`.
+pub fn host_key(host: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) if port != 22 => format!("[{host}]:{port}"),
+        _ => host.to_owned(),
+    }
+}
+
+fn hosts_match(patterns: &str, host: &str) -> bool {
+    if let Some(hashed) = patterns.strip_prefix("|1|") {
+        return hashed_match(hashed, host);
+    }
+
+    let mut matched = false;
+    for pattern in patterns.split(',') {
+        if let Some(pattern) = pattern.strip_prefix('!') {
+            if host_matches(pattern, host) {
+                return false;
+            }
+        } else if host_matches(pattern, host) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+/// Host names are case-insensitive and can't contain `/`, so `*` in
+/// patterns matches anything.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    glob::matches(&pattern.to_ascii_lowercase(), &host.to_ascii_lowercase())
+}
+
+fn hashed_match(hashed: &str, host: &str) -> bool {
+    let verify = || -> Option<bool> {
+        let mut parts = hashed.splitn(2, '|');
+        let salt = base64::decode(parts.next()?).ok()?;
+        let hash = base64::decode(parts.next()?).ok()?;
+        let mut mac = hmac::Hmac::<sha1::Sha1>::new_varkey(&salt).ok()?;
+        mac.update(host.as_bytes());
+        Some(mac.verify(&hash).is_ok())
+    };
+    verify().unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIBBBqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    fn sha256(key: &str) -> [u8; 32] {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(&sha2::Sha256::digest(&base64::decode(key).unwrap()));
+        hash
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(hosts_match("github.com", "github.com"));
+        assert!(hosts_match("GitHub.com", "github.COM"));
+        assert!(hosts_match("example.org,*.github.com", "ssh.github.com"));
+        assert!(hosts_match("git?.example.org", "git1.example.org"));
+        assert!(!hosts_match("*.github.com", "github.com"));
+        assert!(!hosts_match(
+            "*.example.org,!evil.example.org",
+            "evil.example.org"
+        ));
+        assert!(hosts_match(
+            "[git.example.org]:2222",
+            "[git.example.org]:2222"
+        ));
+        assert!(!hosts_match("git.example.org", "[git.example.org]:2222"));
+    }
+
+    #[test]
+    fn hashed_hosts() {
+        let salt = b"0123456789abcdefghij";
+        let mut mac = hmac::Hmac::<sha1::Sha1>::new_varkey(salt).unwrap();
+        mac.update(b"github.com");
+        let hash = mac.finalize().into_bytes();
+        let patterns = format!("|1|{}|{}", base64::encode(salt), base64::encode(hash));
+        assert!(hosts_match(&patterns, "github.com"));
+        assert!(!hosts_match(&patterns, "gitlab.com"));
+    }
+
+    #[test]
+    fn verdicts() {
+        let known_hosts = KnownHosts::parse(&format!(
+            "# comment\ngithub.com ssh-ed25519 {KEY}\n@revoked old.example.org ssh-ed25519 \
+             {KEY}\n@cert-authority *.example.org ssh-ed25519 {OTHER_KEY}\n",
+        ));
+        assert_eq!(
+            known_hosts.verify("github.com", &sha256(KEY)),
+            Verdict::Trusted
+        );
+        assert_eq!(
+            known_hosts.verify("github.com", &sha256(OTHER_KEY)),
+            Verdict::Mismatch
+        );
+        assert_eq!(
+            known_hosts.verify("old.example.org", &sha256(KEY)),
+            Verdict::Revoked
+        );
+        assert_eq!(
+            known_hosts.verify("new.example.org", &sha256(OTHER_KEY)),
+            Verdict::Unknown
+        );
+    }
+
+    #[test]
+    fn host_keys() {
+        assert_eq!(host_key("github.com", None), "github.com");
+        assert_eq!(host_key("github.com", Some(22)), "github.com");
+        assert_eq!(host_key("github.com", Some(2222)), "[github.com]:2222");
+    }
+}
@@ -0,0 +1,540 @@
+//! On-disk layout of workspaces and naming of compose projects.
+//!
+//! Every part of a `BranchSpec` is encoded separately, so that the encoding
+//! is collision-free: distinct specs always map to distinct directories and
+//! project names, and encoded parts can never contain a path separator or be
+//! `.`, `..` or a hidden directory like `.mirrors`. Environments are put
+//! next to branches under a name starting with `@`, which is always encoded
+//! in branch names.
+
+use std::{
+    ffi::OsStr,
+    fmt::Write as _,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+
+use super::{BranchSpec, Target};
+
+/// Bumped whenever the layout changes in a way that requires migration.
+const LAYOUT_VERSION: &str = "2";
+const LAYOUT_VERSION_FILE: &str = ".layout-version";
+/// Written into `.git` of workspaces whose compose project was started under
+/// the old naming scheme, so that it can be stopped on the next deploy.
+const LEGACY_PROJECT_FILE: &str = "adm-legacy-project";
+
+/// Encodes a string into a single path component. ASCII alphanumerics, `-`,
+/// `_` and non-leading dots are kept as is, all other bytes are
+/// percent-encoded.
+pub fn dir_name(part: &str) -> String {
+    let mut name = String::with_capacity(part.len());
+    for (i, byte) in part.bytes().enumerate() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => name.push(char::from(byte)),
+            b'.' if i != 0 => name.push('.'),
+            _ => {
+                let _ = write!(name, "%{byte:02X}");
+            },
+        }
+    }
+    name
+}
+
+/// Encodes a string for use in a compose project name, which may only
+/// contain lowercase letters, digits, `-` and `_`. `-` is used as a separator
+/// and `_` as an escape, so everything else is encoded as `_xx`.
+fn project_part(part: &str) -> String {
+    let mut name = String::with_capacity(part.len());
+    for byte in part.bytes() {
+        match byte {
+            b'a'..=b'z' | b'0'..=b'9' => name.push(char::from(byte)),
+            _ => {
+                let _ = write!(name, "_{byte:02x}");
+            },
+        }
+    }
+    name
+}
+
+/// Joins already encoded names onto `base`, checking that the result is
+/// still under it.
+fn join_under(base: &Path, names: &[String]) -> eyre::Result<PathBuf> {
+    let mut path = base.to_owned();
+    for name in names {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => path.push(name),
+            _ => eyre::bail!("{:?} can't be used as a directory name", name),
+        }
+    }
+    eyre::ensure!(
+        path.starts_with(base) && path != base,
+        "path {:?} is outside of {:?}",
+        path,
+        base,
+    );
+    Ok(path)
+}
+
+/// Joins a path from the repo, e.g. a monorepo unit's directory, onto the
+/// workspace, refusing to leave it.
+pub fn join_relative(workspace: &Path, relative: &str) -> eyre::Result<PathBuf> {
+    let mut path = workspace.to_owned();
+    for component in Path::new(relative).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {},
+            _ => eyre::bail!("{:?} isn't a relative path inside the repo", relative),
+        }
+    }
+    Ok(path)
+}
+
+/// Directory of the target's workspace.
+pub fn workspace_path(base: &Path, target: &Target) -> eyre::Result<PathBuf> {
+    match target {
+        Target::Branch(branch_spec) => branch_workspace_path(base, branch_spec),
+        Target::Environment { owner, repo, name } => join_under(base, &[
+            dir_name(owner),
+            dir_name(repo),
+            format!("@{}", dir_name(name)),
+        ]),
+    }
+}
+
+fn branch_workspace_path(base: &Path, branch_spec: &BranchSpec) -> eyre::Result<PathBuf> {
+    join_under(base, &[
+        dir_name(&branch_spec.owner),
+        dir_name(&branch_spec.repo),
+        dir_name(&branch_spec.branch),
+    ])
+}
+
+/// Directory of the repo's mirror.
+pub fn mirror_path(root: &Path, branch_spec: &BranchSpec) -> eyre::Result<PathBuf> {
+    join_under(root, &[
+        dir_name(&branch_spec.owner),
+        dir_name(&format!("{}.git", branch_spec.repo)),
+    ])
+}
+
+/// Name of the target's compose project. Environment projects have an extra
+/// `env` part, so they can't clash with branch ones.
+pub fn project_name(target: &Target) -> String {
+    match target {
+        Target::Branch(branch_spec) => branch_project_name(branch_spec),
+        Target::Environment { owner, repo, name } => format!(
+            "adm-{}-{}-env-{}",
+            project_part(owner),
+            project_part(repo),
+            project_part(name),
+        ),
+    }
+}
+
+/// Name of the compose project of a monorepo unit deployed into the target.
+pub fn unit_project_name(target: &Target, unit: &str) -> String {
+    format!("{}-unit-{}", project_name(target), project_part(unit))
+}
+
+/// Name of the compose project that tests of deploys into `target` run in.
+pub fn tests_project_name(target: &Target) -> String {
+    format!("{}-tests", project_name(target))
+}
+
+fn branch_project_name(branch_spec: &BranchSpec) -> String {
+    format!(
+        "adm-{}-{}-{}",
+        project_part(&branch_spec.owner),
+        project_part(&branch_spec.repo),
+        project_part(&branch_spec.branch),
+    )
+}
+
+/// Project name that was used before the encoding was introduced, as
+/// normalized by `docker-compose`.
+fn legacy_project_name(branch_spec: &BranchSpec) -> String {
+    format!(
+        "adm-{}-{}-{}",
+        branch_spec.owner, branch_spec.repo, branch_spec.branch
+    )
+    .to_lowercase()
+    .chars()
+    .filter(|c| matches!(c, 'a'..='z' | '0'..='9' | '-' | '_'))
+    .collect()
+}
+
+/// Returns the compose project that has to be stopped before deploying into
+/// the workspace, if it was left over from the old naming scheme.
+pub fn legacy_project(workspace: &Path) -> Option<String> {
+    fs::read_to_string(workspace.join(".git").join(LEGACY_PROJECT_FILE))
+        .ok()
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+}
+
+/// Forgets about the leftover compose project once it's stopped.
+pub fn clear_legacy_project(workspace: &Path) -> eyre::Result<()> {
+    let path = workspace.join(".git").join(LEGACY_PROJECT_FILE);
+    fs::remove_file(&path).wrap_err_with(|| format!("failed to remove {}", path.display()))
+}
+
+/// Recursively collects workspaces under `dir`, returning paths relative to
+/// `root`.
+fn find_workspaces(root: &Path, dir: &Path, found: &mut Vec<PathBuf>) -> eyre::Result<()> {
+    let checkout = if dir.join(".git").is_dir() {
+        if let Ok(relative) = dir.strip_prefix(root) {
+            found.push(relative.to_owned());
+        }
+        match git2::Repository::open(dir) {
+            Ok(repo) => Some(repo),
+            Err(err) => {
+                tracing::warn!("Not looking inside workspace {:?}: {}", dir, err);
+                return Ok(());
+            },
+        }
+    } else {
+        None
+    };
+    for entry in fs::read_dir(dir).wrap_err_with(|| format!("failed to read {}", dir.display()))? {
+        let entry = entry.wrap_err_with(|| format!("failed to read {}", dir.display()))?;
+        if !entry.file_type()?.is_dir() || entry.file_name() == ".git" {
+            continue;
+        }
+        if let Some(repo) = &checkout {
+            if !may_hold_branches(repo, &entry.file_name()) {
+                continue;
+            }
+        }
+        find_workspaces(root, &entry.path(), found)?;
+    }
+    Ok(())
+}
+
+/// Everything inside a workspace belongs to its checkout, except that the old
+/// layout nested workspaces of `feature/x` inside the one of `feature`. Those
+/// are neither tracked nor ignored by the outer workspace.
+fn may_hold_branches(repo: &git2::Repository, name: &OsStr) -> bool {
+    let name = Path::new(name);
+    let tracked = repo
+        .head()
+        .and_then(|head| head.peel_to_tree())
+        .is_ok_and(|tree| tree.get_path(name).is_ok());
+    !tracked && !repo.is_path_ignored(name).unwrap_or(true)
+}
+
+fn parse_relative(relative: &Path) -> Option<BranchSpec> {
+    let mut parts = relative.iter().map(|part| part.to_str());
+    let owner = parts.next()??.to_owned();
+    let repo = parts.next()??.to_owned();
+    let branch = parts.collect::<Option<Vec<_>>>()?.join("/");
+    if branch.is_empty() {
+        return None;
+    }
+    Some(BranchSpec {
+        owner,
+        repo,
+        branch,
+    })
+}
+
+fn move_dir(from: &Path, to: &Path) -> eyre::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("failed to create directory {}", parent.display()))?;
+    }
+    fs::rename(from, to)
+        .wrap_err_with(|| format!("failed to move {} to {}", from.display(), to.display()))
+}
+
+/// Removes now-empty directories between `path` and `root`.
+fn remove_empty_parents(root: &Path, path: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+fn migrate_workspaces(base: &Path) -> eyre::Result<()> {
+    let mut owners = Vec::new();
+    for entry in
+        fs::read_dir(base).wrap_err_with(|| format!("failed to read {}", base.display()))?
+    {
+        let entry = entry.wrap_err_with(|| format!("failed to read {}", base.display()))?;
+        // `.mirrors`, `.broken` and other service directories.
+        if entry.file_type()?.is_dir() && !entry.file_name().to_string_lossy().starts_with('.') {
+            owners.push(entry.path());
+        }
+    }
+
+    let mut workspaces = Vec::new();
+    for owner in owners {
+        find_workspaces(base, &owner, &mut workspaces)?;
+    }
+    // Move nested workspaces out before their parents.
+    workspaces.sort_by_key(|relative| std::cmp::Reverse(relative.components().count()));
+
+    for relative in workspaces {
+        let old = base.join(&relative);
+        if let Some(branch_spec) = parse_relative(&relative) {
+            migrate_workspace(base, &old, &branch_spec)?;
+        } else {
+            tracing::warn!("Skipping unrecognized workspace {:?}", old);
+        }
+    }
+    Ok(())
+}
+
+fn migrate_workspace(base: &Path, old: &Path, branch_spec: &BranchSpec) -> eyre::Result<()> {
+    let new = branch_workspace_path(base, branch_spec)?;
+    if old != new {
+        if new.exists() {
+            tracing::warn!(
+                "Not moving workspace {:?} to {:?}: destination already exists",
+                old,
+                new,
+            );
+            return Ok(());
+        }
+        tracing::info!("Moving workspace {:?} to {:?}", old, new);
+        move_dir(old, &new)?;
+        remove_empty_parents(base, old);
+    }
+
+    let legacy = legacy_project_name(branch_spec);
+    if legacy != branch_project_name(branch_spec) {
+        tracing::info!(
+            "Compose project {} of {:?} will be replaced on the next deploy",
+            legacy,
+            new,
+        );
+        let marker = new.join(".git").join(LEGACY_PROJECT_FILE);
+        fs::write(&marker, &legacy)
+            .wrap_err_with(|| format!("failed to write {}", marker.display()))?;
+    }
+    Ok(())
+}
+
+/// Brings workspaces created with the old, unencoded layout to the current
+/// one. Does nothing if the layout is already up to date.
+pub fn migrate(base: &Path) -> eyre::Result<()> {
+    let version_file = base.join(LAYOUT_VERSION_FILE);
+    if base.exists() {
+        match fs::read_to_string(&version_file) {
+            Ok(version) if version.trim() == LAYOUT_VERSION => return Ok(()),
+            _ => {
+                tracing::info!("Migrating workspaces in {:?} to the new layout", base);
+                migrate_workspaces(base).wrap_err("failed to migrate workspaces")?;
+            },
+        }
+    } else {
+        fs::create_dir_all(base)
+            .wrap_err_with(|| format!("failed to create directory {}", base.display()))?;
+    }
+    fs::write(&version_file, format!("{LAYOUT_VERSION}\n"))
+        .wrap_err_with(|| format!("failed to write {}", version_file.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn branch(owner: &str, repo: &str, branch: &str) -> Target {
+        Target::Branch(BranchSpec {
+            owner: owner.to_owned(),
+            repo: repo.to_owned(),
+            branch: branch.to_owned(),
+        })
+    }
+
+    fn environment(owner: &str, repo: &str, name: &str) -> Target {
+        Target::Environment {
+            owner: owner.to_owned(),
+            repo: repo.to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn dir_names() {
+        assert_eq!(dir_name("master"), "master");
+        assert_eq!(dir_name("Feature_1-x"), "Feature_1-x");
+        assert_eq!(dir_name("feature/x"), "feature%2Fx");
+        assert_eq!(dir_name("v1.2"), "v1.2");
+        assert_eq!(dir_name(".mirrors"), "%2Emirrors");
+        assert_eq!(dir_name(".."), "%2E.");
+        assert_eq!(dir_name("@prod"), "%40prod");
+        assert_eq!(dir_name("100%"), "100%25");
+        assert_eq!(dir_name("é"), "%C3%A9");
+    }
+
+    #[test]
+    fn project_parts() {
+        assert_eq!(project_part("master"), "master");
+        assert_eq!(project_part("Master"), "_4daster");
+        assert_eq!(project_part("feature/x"), "feature_2fx");
+        assert_eq!(project_part("a-b"), "a_2db");
+        assert_eq!(project_part("a_b"), "a_5fb");
+    }
+
+    #[test]
+    fn project_names() {
+        assert_eq!(
+            project_name(&branch("me", "app", "master")),
+            "adm-me-app-master"
+        );
+        assert_eq!(
+            project_name(&environment("me", "app", "prod")),
+            "adm-me-app-env-prod"
+        );
+        assert_eq!(
+            unit_project_name(&branch("me", "app", "master"), "api"),
+            "adm-me-app-master-unit-api"
+        );
+        assert_eq!(
+            tests_project_name(&environment("me", "app", "prod")),
+            "adm-me-app-env-prod-tests"
+        );
+        // Separators in parts are encoded, so these don't collide.
+        assert_ne!(
+            project_name(&branch("me", "a-b", "c")),
+            project_name(&branch("me", "a", "b-c"))
+        );
+        assert_ne!(
+            project_name(&branch("me", "app", "Master")),
+            project_name(&branch("me", "app", "master"))
+        );
+    }
+
+    #[test]
+    fn workspace_paths() {
+        let base = Path::new("/srv/adm");
+        assert_eq!(
+            workspace_path(base, &branch("me", "app", "feature/x")).unwrap(),
+            Path::new("/srv/adm/me/app/feature%2Fx")
+        );
+        assert_eq!(
+            workspace_path(base, &environment("me", "app", "prod")).unwrap(),
+            Path::new("/srv/adm/me/app/@prod")
+        );
+        assert_ne!(
+            workspace_path(base, &branch("me", "app", "@prod")).unwrap(),
+            workspace_path(base, &environment("me", "app", "prod")).unwrap()
+        );
+        assert_eq!(
+            workspace_path(base, &branch("me", "..", "..")).unwrap(),
+            Path::new("/srv/adm/me/%2E./%2E.")
+        );
+        assert_eq!(
+            mirror_path(Path::new("/srv/adm/.mirrors"), &BranchSpec {
+                owner: "me".to_owned(),
+                repo: "app".to_owned(),
+                branch: "master".to_owned(),
+            })
+            .unwrap(),
+            Path::new("/srv/adm/.mirrors/me/app.git")
+        );
+    }
+
+    #[test]
+    fn join_under_stays_inside() {
+        let base = Path::new("/srv/adm");
+        assert!(join_under(base, &["..".to_owned()]).is_err());
+        assert!(join_under(base, &["a/b".to_owned()]).is_err());
+        assert!(join_under(base, &["/etc".to_owned()]).is_err());
+        assert!(join_under(base, &[]).is_err());
+        assert_eq!(
+            join_under(base, &["a".to_owned(), "b".to_owned()]).unwrap(),
+            Path::new("/srv/adm/a/b")
+        );
+    }
+
+    #[test]
+    fn relative_paths() {
+        let workspace = Path::new("/srv/adm/me/app/master");
+        assert_eq!(
+            join_relative(workspace, "./services/api").unwrap(),
+            Path::new("/srv/adm/me/app/master/services/api")
+        );
+        assert!(join_relative(workspace, "../other").is_err());
+        assert!(join_relative(workspace, "services/../../other").is_err());
+        assert!(join_relative(workspace, "/etc").is_err());
+    }
+
+    #[test]
+    fn old_layout() {
+        let spec = parse_relative(Path::new("me/app/feature/x")).unwrap();
+        assert_eq!(spec.owner, "me");
+        assert_eq!(spec.repo, "app");
+        assert_eq!(spec.branch, "feature/x");
+        assert!(parse_relative(Path::new("me/app")).is_none());
+        assert_eq!(legacy_project_name(&spec), "adm-me-app-featurex");
+    }
+
+    /// Makes a workspace of the old layout, committing `tracked` files.
+    fn workspace(path: &Path, tracked: &[&str]) {
+        let repo = git2::Repository::init(path).unwrap();
+        let mut index = repo.index().unwrap();
+        for file in tracked {
+            let file_path = path.join(file);
+            fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+            fs::write(&file_path, file).unwrap();
+            index.add_path(Path::new(file)).unwrap();
+        }
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("adm", "adm@example.org").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn migrates_old_workspaces() {
+        let base = std::env::temp_dir().join(format!("adm-test-layout-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let feature = base.join("me").join("app").join("feature");
+        workspace(&feature, &[".gitignore", "vendor/lib/README"]);
+        fs::write(feature.join(".gitignore"), "node_modules\n").unwrap();
+        // Repos inside the checkout aren't workspaces.
+        fs::create_dir_all(feature.join("vendor/lib/.git")).unwrap();
+        fs::create_dir_all(feature.join("node_modules/dep/.git")).unwrap();
+        // Workspaces of `feature/x` and `feature/x/y` in the old layout.
+        workspace(&feature.join("x"), &["x"]);
+        workspace(&feature.join("x").join("y"), &["y"]);
+        workspace(&base.join("me").join("app").join("master"), &["master"]);
+        fs::create_dir_all(base.join(".mirrors/me/app.git")).unwrap();
+
+        let mut found = Vec::new();
+        find_workspaces(&base, &base.join("me"), &mut found).unwrap();
+        found.sort();
+        assert_eq!(found, [
+            Path::new("me/app/feature"),
+            Path::new("me/app/feature/x"),
+            Path::new("me/app/feature/x/y"),
+            Path::new("me/app/master"),
+        ]);
+
+        migrate(&base).unwrap();
+        let app = base.join("me").join("app");
+        assert!(app.join("feature/.git").is_dir());
+        assert!(app.join("feature/vendor/lib/.git").is_dir());
+        assert!(!app.join("feature/x").exists());
+        assert!(app.join("feature%2Fx/.git").is_dir());
+        assert!(!app.join("feature%2Fx/y").exists());
+        assert!(app.join("feature%2Fx%2Fy/.git").is_dir());
+        assert!(app.join("master/.git").is_dir());
+        assert!(base.join(".mirrors/me/app.git").is_dir());
+        assert_eq!(
+            legacy_project(&app.join("feature%2Fx")).as_deref(),
+            Some("adm-me-app-featurex")
+        );
+        assert_eq!(legacy_project(&app.join("master")), None);
+        // Already migrated.
+        migrate(&base).unwrap();
+        assert!(app.join("feature%2Fx/.git").is_dir());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
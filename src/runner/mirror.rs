@@ -0,0 +1,166 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::{self, WrapErr as _};
+
+use super::{control::TaskControl, git, layout, BranchSpec};
+use crate::lock_manager::LockManager;
+
+/// Bare mirrors shared by all branch workspaces of a repo. Workspaces don't
+/// fetch by themselves, but borrow objects from the mirror via alternates.
+#[derive(Debug)]
+pub struct Mirrors {
+    root: PathBuf,
+    locks: LockManager<(String, String)>,
+}
+
+/// Context attached to errors coming from the mirror, as opposed to the
+/// workspace.
+#[derive(Debug, Clone, Copy)]
+pub struct MirrorError;
+
+impl std::fmt::Display for MirrorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("failed to update mirror")
+    }
+}
+
+impl Mirrors {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            locks: LockManager::new(),
+        }
+    }
+
+    pub fn path(&self, branch_spec: &BranchSpec) -> eyre::Result<PathBuf> {
+        layout::mirror_path(&self.root, branch_spec)
+    }
+
+    /// Fetches the ref into the mirror, creating it if needed. Returns the
+    /// path to the mirror's object database and the commit the ref points to.
+    pub fn fetch(
+        &self,
+        branch_spec: &BranchSpec,
+        url: &str,
+        reference: &str,
+        auth: &git::RemoteAuth<'_>,
+        control: &TaskControl,
+    ) -> eyre::Result<(PathBuf, git2::Oid)> {
+        let path = self.path(branch_spec)?;
+        let key = (branch_spec.owner.clone(), branch_spec.repo.clone());
+        let commit = self
+            .locks
+            .with_lock(key, || -> eyre::Result<git2::Oid> {
+                std::fs::create_dir_all(&path).wrap_err_with(|| {
+                    format!("failed to create mirror directory {}", path.display())
+                })?;
+                let mut repo =
+                    git::open_or_init(url, &path, true).wrap_err("failed to open or initialize")?;
+                git::ensure_origin_url(&repo, url).wrap_err("failed to update remote URL")?;
+                git::fetch(
+                    &mut repo,
+                    reference,
+                    &git::mirror_refspec(reference),
+                    auth,
+                    control,
+                )
+                .map_err(|err| match control.check() {
+                    // The transfer was aborted because of the interruption.
+                    Err(interrupted) => eyre::Report::new(interrupted),
+                    Ok(()) => eyre::Report::new(err),
+                })?;
+                git::ref_commit(&repo, reference).wrap_err("failed to find the fetched commit")
+            })
+            .wrap_err(MirrorError)?;
+        Ok((path.join("objects"), commit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn branch_spec(branch: &str) -> BranchSpec {
+        BranchSpec {
+            owner: "me".to_owned(),
+            repo: "app".to_owned(),
+            branch: branch.to_owned(),
+        }
+    }
+
+    /// Commits a file on top of `parent` and points the branch to it.
+    fn commit(repo: &git2::Repository, branch: &str, parent: Option<git2::Oid>) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join(branch), branch).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(std::path::Path::new(branch)).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("adm", "adm@example.org").unwrap();
+        let parents: Vec<_> = parent
+            .map(|parent| repo.find_commit(parent).unwrap())
+            .into_iter()
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        repo.commit(
+            Some(&format!("refs/heads/{branch}")),
+            &signature,
+            &signature,
+            branch,
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn branches_share_one_mirror() {
+        let dir = std::env::temp_dir().join(format!("adm-test-mirror-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let origin = git2::Repository::init(dir.join("origin")).unwrap();
+        let main = commit(&origin, "main", None);
+        let feature = commit(&origin, "feature", Some(main));
+        let url = dir.join("origin").to_str().unwrap().to_owned();
+        let auth = git::RemoteAuth::new(&url, None, &dir.join("known_hosts"));
+        let control = TaskControl::default();
+        let mirrors = Mirrors::new(dir.join("mirrors"));
+
+        let (objects, fetched) = mirrors
+            .fetch(
+                &branch_spec("main"),
+                &url,
+                "refs/heads/main",
+                &auth,
+                &control,
+            )
+            .unwrap();
+        assert_eq!(fetched, main);
+        let (feature_objects, fetched) = mirrors
+            .fetch(
+                &branch_spec("feature"),
+                &url,
+                "refs/heads/feature",
+                &auth,
+                &control,
+            )
+            .unwrap();
+        assert_eq!(fetched, feature);
+        assert_eq!(objects, feature_objects);
+
+        let mirror = git2::Repository::open(mirrors.path(&branch_spec("main")).unwrap()).unwrap();
+        assert!(mirror.is_bare());
+        assert_eq!(git::ref_commit(&mirror, "refs/heads/main").unwrap(), main);
+        assert_eq!(
+            git::ref_commit(&mirror, "refs/heads/feature").unwrap(),
+            feature
+        );
+
+        // Workspaces only borrow objects from the mirror.
+        let workspace = git::open_or_init(&url, &dir.join("workspace"), false).unwrap();
+        assert!(git::set_alternates(&workspace, &objects).unwrap());
+        assert!(!git::set_alternates(&workspace, &objects).unwrap());
+        let workspace = git2::Repository::open(dir.join("workspace")).unwrap();
+        git::ensure_commit(&workspace, &feature.to_string()).unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,62 +1,425 @@
+mod command;
+mod control;
+mod env;
 mod git;
+mod known_hosts;
+mod layout;
+mod mirror;
+mod services;
 
-use std::{path::PathBuf, process::Command, sync::Arc};
+use std::{
+    cmp,
+    collections::{BTreeSet, HashMap},
+    fmt, io,
+    ops::AddAssign,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use actix::prelude::*;
 use color_eyre::eyre::{self, WrapErr as _};
 
+pub use self::{
+    control::{Interrupted, TaskControl, Tasks},
+    layout::{dir_name, migrate as migrate_layout},
+    mirror::Mirrors,
+};
+use self::{env::DeployEnv, mirror::MirrorError};
 use crate::{
+    build_log::{BuildLog, BuildLogs},
+    deployments::Deployments,
     lock_manager::LockManager,
-    notifier::{Notification, Notifier},
+    notifier::{Notification, Notifier, Status},
+    repos::{RepoConfig, ReposConfig, Tests},
+    secrets::{SecretStore, Secrets},
+    vault,
 };
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Number of last lines of failed tests' or migrations' output included in
+/// notifications.
+const OUTPUT_TAIL_LINES: usize = 20;
+/// Upper bound on the size of that output, so that notifications fit into a
+/// Telegram message along with everything else.
+const OUTPUT_TAIL_BYTES: usize = 3000;
+
+/// Joins the last lines of output, dropping earlier ones that don't fit into
+/// `max_bytes`. A single line that doesn't fit is cut from the start.
+fn output_tail(lines: &[String], max_bytes: usize) -> String {
+    const CUT: &str = "…";
+
+    let mut kept = Vec::new();
+    let mut size = 0;
+    for line in lines.iter().rev() {
+        let added = line.len() + usize::from(!kept.is_empty());
+        if size + added <= max_bytes {
+            size += added;
+            kept.push(line.as_str());
+            continue;
+        }
+        if kept.is_empty() && max_bytes > CUT.len() {
+            let mut start = line.len() - (max_bytes - CUT.len());
+            while !line.is_char_boundary(start) {
+                start += 1;
+            }
+            return format!("{CUT}{}", &line[start..]);
+        }
+        break;
+    }
+    kept.reverse();
+    kept.join("\n")
+}
+
+/// How long polling waits for the remote to list its refs.
+const REMOTE_REFS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long removing test containers may take.
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Remotes whose refs are being listed, see [`remote_refs`].
+static LISTING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Marks the remote as being listed until dropped.
+struct Listing(String);
+
+impl Listing {
+    fn start(url: &str) -> Option<Self> {
+        LISTING
+            .lock()
+            .unwrap()
+            .insert(url.to_owned())
+            .then(|| Self(url.to_owned()))
+    }
+}
+
+impl Drop for Listing {
+    fn drop(&mut self) {
+        LISTING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Number of corrupted repos kept in `.broken` for investigation.
+const BROKEN_RETENTION: usize = 3;
+
+/// Unique task identifier. IDs are derived from the current time, so they're
+/// unique across restarts and sort in creation order.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    pub fn generate() -> Self {
+        static LAST: AtomicU64 = AtomicU64::new(0);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| {
+            d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+        });
+        let mut last = LAST.load(Ordering::SeqCst);
+        loop {
+            let next = cmp::max(last + 1, now);
+            match LAST.compare_exchange_weak(last, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Self(next),
+                Err(actual) => last = actual,
+            }
+        }
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for TaskId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct BranchSpec {
     pub owner: String,
     pub repo: String,
     pub branch: String,
 }
 
-#[derive(Debug, Clone)]
+/// What a task deploys into. Pushes that match an environment share its
+/// workspace and compose project, other branches get their own.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Target {
+    Branch(BranchSpec),
+    Environment {
+        owner: String,
+        repo: String,
+        name: String,
+    },
+}
+
+impl Target {
+    pub fn owner(&self) -> &str {
+        match self {
+            Self::Branch(branch_spec) => &branch_spec.owner,
+            Self::Environment { owner, .. } => owner,
+        }
+    }
+
+    pub fn repo(&self) -> &str {
+        match self {
+            Self::Branch(branch_spec) => &branch_spec.repo,
+            Self::Environment { repo, .. } => repo,
+        }
+    }
+
+    pub fn environment(&self) -> Option<&str> {
+        match self {
+            Self::Branch(_) => None,
+            Self::Environment { name, .. } => Some(name),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Branch(BranchSpec {
+                owner,
+                repo,
+                branch,
+            }) => write!(f, "{owner}/{repo} ({branch})"),
+            Self::Environment { owner, repo, name } => {
+                write!(f, "{owner}/{repo} (environment {name})")
+            },
+        }
+    }
+}
+
+/// The task's commit is an ancestor of the one that's already deployed, so
+/// deploying it would roll the target back.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("a newer commit {deployed} is already deployed")]
+pub struct Stale {
+    pub deployed: String,
+}
+
+/// Tests failed, so the commit wasn't deployed.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("tests failed ({status})")]
+pub struct TestsFailed {
+    pub status: String,
+    /// Last lines of the tests' output.
+    pub output: String,
+}
+
+/// How long the phases of a deploy took.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    /// Pulling and building images and migrating, while the old containers
+    /// keep running.
+    pub build: Duration,
+    /// Recreating containers.
+    pub switchover: Duration,
+}
+
+impl AddAssign for Timings {
+    fn add_assign(&mut self, other: Self) {
+        self.build += other.build;
+        self.switchover += other.switchover;
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Sub-second precision is noise here.
+        let seconds = |duration: Duration| Duration::from_secs(duration.as_secs());
+        write!(
+            f,
+            "build took {}, switchover took {}",
+            humantime::format_duration(seconds(self.build)),
+            humantime::format_duration(seconds(self.switchover)),
+        )
+    }
+}
+
+/// The migration service failed, so containers weren't recreated.
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("migration failed ({status})")]
+pub struct MigrationFailed {
+    pub status: String,
+    /// Last lines of the migration's output.
+    pub output: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
 pub enum Reason {
-    PushToMaster,
+    Push,
+    /// Commit that is deployed in the `from` environment was promoted.
+    Promotion {
+        from: String,
+    },
+    /// The target was pinned to the commit.
+    Pin,
+    /// Regular rebuild of the branch head, see [`crate::repos::Redeploy`].
+    Scheduled,
 }
 
-#[derive(Debug, Clone, Message)]
+impl Reason {
+    /// Whether someone asked for this exact commit, so it's deployed even if
+    /// it's older than the running one.
+    pub fn is_explicit(&self) -> bool {
+        matches!(self, Self::Promotion { .. } | Self::Pin)
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Push => f.write_str("push"),
+            Self::Promotion { .. } => f.write_str("promotion"),
+            Self::Pin => f.write_str("pin"),
+            Self::Scheduled => f.write_str("schedule"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Message, serde::Serialize, serde::Deserialize)]
 #[rtype(result = "()")]
 pub struct Task {
+    pub id: TaskId,
+    /// For tags, `branch` is the name of the tag.
     pub branch_spec: BranchSpec,
+    /// Full name of the pushed ref, e.g. `refs/heads/master`.
+    pub reference: String,
+    pub environment: Option<String>,
+    /// Who pushed the commit, if it was pushed.
+    pub sender: Option<String>,
+    pub approved_by: Option<String>,
     pub commit_hash: String,
     pub url: String,
+    pub clone_url: String,
     pub reason: Reason,
 }
 
+impl Task {
+    pub fn target(&self) -> Target {
+        match &self.environment {
+            Some(name) => Target::Environment {
+                owner: self.branch_spec.owner.clone(),
+                repo: self.branch_spec.repo.clone(),
+                name: name.clone(),
+            },
+            None => Target::Branch(self.branch_spec.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+pub enum SubmitError {
+    #[error("task queue is full")]
+    QueueFull,
+    #[error("runners are stopped")]
+    Closed,
+}
+
+/// Entry point for queueing tasks: registers them so that they can be
+/// cancelled and sends them to the runners.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    runner: Addr<Runner>,
+    tasks: Arc<Tasks>,
+}
+
+impl Queue {
+    pub fn new(runner: Addr<Runner>, tasks: Arc<Tasks>) -> Self {
+        Self { runner, tasks }
+    }
+
+    pub fn tasks(&self) -> &Tasks {
+        &self.tasks
+    }
+
+    pub fn submit(&self, task: Task) -> Result<TaskId, SubmitError> {
+        let id = task.id;
+        self.tasks.register(id);
+        match self.runner.try_send(task) {
+            Ok(()) => Ok(id),
+            Err(err) => {
+                self.tasks.remove(id);
+                Err(match err {
+                    SendError::Full(_) => SubmitError::QueueFull,
+                    SendError::Closed(_) => SubmitError::Closed,
+                })
+            },
+        }
+    }
+}
+
+/// State shared between all runners.
+#[derive(Debug)]
+pub struct Context {
+    pub base_path: PathBuf,
+    /// Extra variables passed from adm's environment to deploy commands.
+    pub pass_env: Vec<String>,
+    pub lock_manager: LockManager<Target>,
+    pub mirrors: Mirrors,
+    pub secrets: SecretStore,
+    pub vault: Option<vault::Client>,
+    pub deployments: Arc<Deployments>,
+    pub logs: Arc<BuildLogs>,
+    pub tasks: Arc<Tasks>,
+    pub repos: Arc<ReposConfig>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Runner {
-    base_path: PathBuf,
-    lock_manager: Arc<LockManager<BranchSpec>>,
+    context: Arc<Context>,
     notifier: Addr<Notifier>,
 }
 
 impl Runner {
-    pub fn new(
-        base_path: PathBuf,
-        lock_manager: Arc<LockManager<BranchSpec>>,
-        notifier: Addr<Notifier>,
-    ) -> Self {
-        Self {
-            base_path,
-            lock_manager,
-            notifier,
-        }
+    pub fn new(context: Arc<Context>, notifier: Addr<Notifier>) -> Self {
+        Self { context, notifier }
     }
 
-    fn process_task(&self, task: &Task) -> eyre::Result<()> {
-        let lock_key = task.branch_spec.clone();
+    fn process_task(
+        &self,
+        task: &Task,
+        log: &BuildLog,
+        control: &TaskControl,
+    ) -> eyre::Result<Timings> {
+        control.check()?;
+        let repo_config = self.context.repos.get(&task.branch_spec);
+        let target = task.target();
+        let environment = task
+            .environment
+            .as_deref()
+            .map(|name| {
+                repo_config
+                    .environment(name)
+                    .ok_or_else(|| eyre::eyre!("environment {} isn't configured", name))
+            })
+            .transpose()?;
         let Task {
-            url,
+            id: _,
+            url: _,
+            clone_url: _,
             commit_hash,
             reason: _,
+            reference: _,
+            environment: _,
+            sender: _,
+            approved_by: _,
             branch_spec:
                 BranchSpec {
                     owner,
@@ -65,65 +428,815 @@ impl Runner {
                 },
         } = task;
 
-        let path = {
-            let mut p = self.base_path.join(&owner);
-            p.push(&repo_name);
-            p.push(&branch);
-            p
-        };
+        let path = layout::workspace_path(&self.context.base_path, &target)
+            .wrap_err("invalid workspace path")?;
+        let project_name = layout::project_name(&target);
+        let tests_project_name = layout::tests_project_name(&target);
+        let mut env = DeployEnv::new(
+            task,
+            &project_name,
+            &self.context.pass_env,
+            &repo_config,
+            environment,
+        );
         tracing::info!(
-            "Running build for {}/{} on branch {} ({}) in {:?}",
+            "Running build for {}/{} on {} ({}) in {:?}",
             owner,
             repo_name,
-            branch,
+            task.reference,
             commit_hash,
             path,
         );
         std::fs::create_dir_all(&path)
-            .wrap_err_with(|| format!("failed to create build directory {:?}", path))?;
+            .wrap_err_with(|| format!("failed to create build directory {}", path.display()))?;
+
+        log_header(task, log);
 
-        self.lock_manager.with_lock(lock_key, || {
+        self.context.lock_manager.with_lock(target, || {
+            control.check()?;
             tracing::info!("Acquired lock for {}/{}, starting build", owner, repo_name);
+            if let Some(timeout) = repo_config.timeout {
+                control.set_timeout(timeout);
+            }
+
+            let aside = self
+                .context
+                .base_path
+                .join(".broken")
+                .join(task.id.to_string());
+            prepare_workspace(
+                task,
+                &path,
+                &aside,
+                &self.context,
+                &repo_config,
+                log,
+                control,
+            )?;
+            control.check()?;
 
-            let mut repo = git::open_or_clone(&url, &path).map_err(|err| -> eyre::Report {
-                eyre::Report::new(err.0)
-                    .wrap_err(err.1)
-                    .wrap_err("failed to open or clone repo")
+            let secrets = self.load_secrets(task, &repo_config, log)?;
+            env.add_secrets(
+                &secrets,
+                repo_config.secrets,
+                self.context.secrets.env_file_path(task.id),
+            )?;
+
+            if let Some(tests) = &repo_config.tests {
+                run_tests(tests, &path, &env, &tests_project_name, log, control)?;
+            }
+
+            if let Some(legacy) = layout::legacy_project(&path) {
+                stop_legacy_project(&path, &legacy, &env, log, control)?;
+            }
+
+            let timings = self.deploy(task, &path, &repo_config, &mut env, log, control)?;
+            tracing::info!(
+                "Sucessfully deployed {}/{}#{}",
+                owner.as_str(),
+                repo_name.as_str(),
+                branch.as_str(),
+            );
+            Ok(timings)
+        })
+    }
+
+    /// Deploys the repo as one compose project, or its units one by one.
+    fn deploy(
+        &self,
+        task: &Task,
+        path: &Path,
+        repo_config: &RepoConfig,
+        env: &mut DeployEnv,
+        log: &BuildLog,
+        control: &TaskControl,
+    ) -> eyre::Result<Timings> {
+        if !repo_config.units.is_empty() {
+            return self.deploy_units(task, path, repo_config, env, log, control);
+        }
+        let services = self.affected_services(task, path, repo_config, env, log);
+        let rollout = Rollout {
+            pull: matches!(task.reason, Reason::Scheduled),
+            services: services.as_deref(),
+            migration: repo_config.migration.as_deref(),
+            wait: repo_config.wait,
+        };
+        compose_up(path, env, rollout, log, control)
+    }
+
+    /// Deploys monorepo units affected by changes since the deployed commit,
+    /// one after another. A failed unit doesn't stop the others; the
+    /// failures are reported together in the task's notification. Units with
+    /// their own chats are notified separately about successful deploys.
+    /// Timings of all units are added up.
+    fn deploy_units(
+        &self,
+        task: &Task,
+        path: &Path,
+        repo_config: &RepoConfig,
+        env: &mut DeployEnv,
+        log: &BuildLog,
+        control: &TaskControl,
+    ) -> eyre::Result<Timings> {
+        let target = task.target();
+        let mut total = Timings::default();
+        let mut failed = Vec::new();
+        let changed = self.changes_since_deployed(task, path, log);
+        let pull = matches!(task.reason, Reason::Scheduled);
+        for unit in &repo_config.units {
+            if !changed
+                .as_deref()
+                .map_or(true, |changed| unit.is_affected(changed))
+            {
+                log.line(&format!("Unit {} isn't affected, skipping", unit.name));
+                continue;
+            }
+            control.check()?;
+            log.line(&format!("Deploying unit {}", unit.name));
+            let unit_path = layout::join_relative(path, &unit.path)
+                .wrap_err_with(|| format!("invalid path of unit {}", unit.name))?;
+            env.set_unit(&unit.name, &layout::unit_project_name(&target, &unit.name));
+            let rollout = Rollout {
+                pull,
+                wait: repo_config.wait,
+                ..Rollout::default()
+            };
+            let timings = match compose_up(&unit_path, env, rollout, log, control) {
+                Ok(timings) => timings,
+                Err(err) if err.downcast_ref::<Interrupted>().is_some() => {
+                    return Err(err.wrap_err(format!("failed to deploy unit {}", unit.name)));
+                },
+                Err(err) => {
+                    log.line(&format!("Failed to deploy unit {}: {:#}", unit.name, err));
+                    failed.push((unit.name.as_str(), err));
+                    continue;
+                },
+            };
+            if unit.telegram_groups.is_some() {
+                let notification = Notification::unit(task.clone(), Status::Success, unit)
+                    .with_timings(Some(timings));
+                if let Err(err) = self.notifier.try_send(notification) {
+                    tracing::error!("Failed to send notification: {}", err);
+                }
+            }
+            total += timings;
+        }
+        match failed.len() {
+            0 => Ok(total),
+            1 => {
+                let (name, err) = failed.pop().unwrap();
+                Err(err.wrap_err(format!("failed to deploy unit {name}")))
+            },
+            _ => {
+                let failures = failed
+                    .iter()
+                    .map(|(name, err)| format!("{name}: {err:#}"))
+                    .collect::<Vec<_>>();
+                eyre::bail!("failed to deploy units {}", failures.join("; "))
+            },
+        }
+    }
+
+    /// Files changed since the commit that's currently deployed into the
+    /// task's target, or `None` if everything should be deployed.
+    fn changes_since_deployed(
+        &self,
+        task: &Task,
+        path: &Path,
+        log: &BuildLog,
+    ) -> Option<Vec<String>> {
+        // Scheduled rebuilds refresh everything.
+        if let Reason::Scheduled = task.reason {
+            return None;
+        }
+        let deployed = self.context.deployments.get(&task.target())?.current?;
+        changed_paths(path, &deployed.commit_hash, task, log)
+    }
+
+    /// Services to restart according to the repo's service mapping, or
+    /// `None` if all of them should be.
+    fn affected_services(
+        &self,
+        task: &Task,
+        path: &Path,
+        repo_config: &RepoConfig,
+        env: &DeployEnv,
+        log: &BuildLog,
+    ) -> Option<Vec<String>> {
+        if repo_config.services.is_empty() {
+            return None;
+        }
+        let changed = self.changes_since_deployed(task, path, log)?;
+        if changed.is_empty() {
+            log.line("Nothing changed since the deployed commit, restarting all services");
+            return None;
+        }
+        let affected = match services::affected(&repo_config.services, &changed) {
+            Ok(affected) => affected,
+            Err(shared) => {
+                log.line(&format!(
+                    "{shared} isn't mapped to a service, restarting all of them"
+                ));
+                return None;
+            },
+        };
+        match services::with_dependents(affected, path, env) {
+            Ok(services) => {
+                let services: Vec<_> = services.into_iter().collect();
+                log.line(&format!(
+                    "Restarting affected services: {}",
+                    services.join(", ")
+                ));
+                Some(services)
+            },
+            Err(err) => {
+                tracing::warn!("Failed to find dependent services: {:#}", err);
+                log.line(&format!(
+                    "Failed to find dependent services ({err:#}), restarting all of them"
+                ));
+                None
+            },
+        }
+    }
+
+    /// Decrypts locally stored secrets and fetches ones from Vault.
+    /// Environment-specific secrets override the repo's ones.
+    fn load_secrets(
+        &self,
+        task: &Task,
+        repo_config: &RepoConfig,
+        log: &BuildLog,
+    ) -> eyre::Result<Secrets> {
+        let BranchSpec { owner, repo, .. } = &task.branch_spec;
+        let mut secrets = self
+            .context
+            .secrets
+            .load(owner, repo, None)
+            .wrap_err("failed to load secrets")?;
+        if let Some(environment) = &task.environment {
+            secrets.extend(
+                self.context
+                    .secrets
+                    .load(owner, repo, Some(environment))
+                    .wrap_err("failed to load environment secrets")?,
+            );
+        }
+        if let Some(vault_secrets) = &repo_config.vault {
+            let vault = self.context.vault.as_ref().ok_or_else(|| {
+                eyre::eyre!("secrets are configured to be read from Vault, but it isn't set up")
             })?;
-            git::pull_repo(&mut repo).wrap_err("failed to pull repo")?;
-            git::checkout(&mut repo, &commit_hash).wrap_err("failed to checkout repo")?;
-
-            let output = Command::new("docker-compose")
-                .arg("up")
-                .arg("--build")
-                .arg("-d")
-                .env(
-                    "COMPOSE_PROJECT_NAME",
-                    format!("adm-{}-{}-{}", &owner, &repo_name, &branch),
-                )
-                .current_dir(&path)
-                .output()
-                .wrap_err("failed to run `docker-compose`")?;
-
-            if output.status.success() {
-                tracing::info!(
-                    "Sucessfully deployed {}/{}#{}",
-                    owner.as_str(),
-                    repo_name.as_str(),
-                    branch.as_str(),
-                );
-                Ok(())
+            log.line(&format!(
+                "Fetching secrets from Vault at {}",
+                vault_secrets.path
+            ));
+            vault
+                .fetch_into(&mut secrets, vault_secrets)
+                .wrap_err("failed to fetch secrets from Vault")?;
+        }
+        Ok(secrets)
+    }
+
+    /// Runs the task, returning how long the deploy took if it succeeded.
+    fn run_task(&self, task: &Task) -> (Status, Option<Timings>) {
+        let log = match self.context.logs.create(task.id) {
+            Ok(log) => log,
+            Err(err) => {
+                // The task was registered when it was queued.
+                self.context.tasks.remove(task.id);
+                let err = eyre::Report::new(err).wrap_err("failed to create build log");
+                return (Status::Fail(err), None);
+            },
+        };
+        let control = self.context.tasks.register(task.id);
+        let res = self.process_task(task, &log, &control);
+        let timings = res.as_ref().ok().copied();
+        let status = Status::from(res);
+        self.context.tasks.remove(task.id);
+
+        match &status {
+            Status::Success => log.line("Build succeeded"),
+            Status::Fail(err) => log.line(&format!("Build failed: {err:#}")),
+            status => log.line(&format!("Build {status}")),
+        }
+        log.finish(status.to_string());
+
+        if let Err(err) = self.context.logs.prune() {
+            tracing::warn!("Failed to prune build logs: {}", err);
+        }
+        (status, timings)
+    }
+}
+
+/// Brings the workspace to the task's commit. If either the workspace or the
+/// mirror turns out to be corrupted, it's moved to `aside` and cloned from
+/// scratch.
+fn prepare_workspace(
+    task: &Task,
+    path: &Path,
+    aside: &Path,
+    context: &Context,
+    repo_config: &RepoConfig,
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    let mirrors = &context.mirrors;
+    let deployed = if task.reason.is_explicit() {
+        None
+    } else {
+        context
+            .deployments
+            .get(&task.target())
+            .and_then(|deployment| deployment.current)
+    };
+    let deployed = deployed
+        .as_ref()
+        .map(|deployed| deployed.commit_hash.as_str());
+    match sync_workspace(task, path, mirrors, deployed, repo_config, log, control) {
+        Err(err) if git::is_corruption(&err) => {
+            control.check()?;
+            let broken = if err.downcast_ref::<MirrorError>().is_some() {
+                mirrors.path(&task.branch_spec)?
             } else {
-                tracing::error!(
-                    stdout = String::from_utf8_lossy(&output.stdout).as_ref(),
-                    stderr = String::from_utf8_lossy(&output.stderr).as_ref(),
-                    "`docker-compose` returned failure. STDERR: {}",
-                    String::from_utf8_lossy(&output.stderr),
+                path.to_owned()
+            };
+            tracing::warn!(
+                "Repo {:?} seems to be corrupted, moving it to {:?}: {:#}",
+                broken,
+                aside,
+                err,
+            );
+            log.line(&format!(
+                "Repo {} seems to be corrupted ({err:#}), moving it to {} and cloning again",
+                broken.display(),
+                aside.display(),
+            ));
+            if let Some(parent) = aside.parent() {
+                std::fs::create_dir_all(parent)
+                    .wrap_err_with(|| format!("failed to create directory {}", parent.display()))?;
+            }
+            std::fs::rename(&broken, aside)
+                .wrap_err_with(|| format!("failed to move corrupted repo {}", broken.display()))?;
+            if let Some(parent) = aside.parent() {
+                if let Err(err) = prune_broken(parent) {
+                    tracing::warn!("Failed to prune corrupted repos in {:?}: {}", parent, err);
+                }
+            }
+            std::fs::create_dir_all(path)
+                .wrap_err_with(|| format!("failed to create build directory {}", path.display()))?;
+            sync_workspace(task, path, mirrors, deployed, repo_config, log, control)
+                .wrap_err("failed to prepare workspace after cloning again")
+        },
+        res => res,
+    }
+}
+
+/// Removes the oldest corrupted repos, keeping at most `BROKEN_RETENTION` of
+/// them.
+fn prune_broken(dir: &Path) -> io::Result<()> {
+    let mut ids = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(id) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.parse::<TaskId>().ok())
+        {
+            ids.push(id);
+        }
+    }
+
+    if ids.len() <= BROKEN_RETENTION {
+        return Ok(());
+    }
+    ids.sort_unstable();
+    let excess = ids.len() - BROKEN_RETENTION;
+    for id in ids.into_iter().take(excess) {
+        let path = dir.join(id.to_string());
+        if let Err(err) = std::fs::remove_dir_all(&path) {
+            tracing::warn!("Failed to remove corrupted repo {:?}: {}", path, err);
+        }
+    }
+    Ok(())
+}
+
+/// Stops the compose project that was started for the workspace under the old
+/// naming scheme, so that it doesn't clash with the new one.
+fn stop_legacy_project(
+    path: &Path,
+    project_name: &str,
+    env: &DeployEnv,
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    log.line(&format!(
+        "Stopping compose project {project_name} left from the old naming scheme"
+    ));
+    let status = command::run(
+        env.compose()
+            .arg("down")
+            .env("COMPOSE_PROJECT_NAME", project_name)
+            .current_dir(path),
+        log,
+        control,
+    )
+    .wrap_err("failed to run `docker-compose`")?;
+    if !status.success() {
+        eyre::bail!("failed to stop compose project {}", project_name);
+    }
+    layout::clear_legacy_project(path)
+}
+
+/// Describes the task at the start of its build log.
+fn log_header(task: &Task, log: &BuildLog) {
+    let BranchSpec {
+        owner,
+        repo,
+        branch,
+    } = &task.branch_spec;
+    log.line(&format!(
+        "Build for {}/{}#{} ({})",
+        owner, repo, branch, task.commit_hash
+    ));
+    if let Some(environment) = &task.environment {
+        log.line(&format!("Deploying into environment {environment}"));
+    }
+    match &task.reason {
+        Reason::Promotion { from } => {
+            log.line(&format!("Promoting the commit deployed in {from}"));
+        },
+        Reason::Scheduled => log.line("Scheduled rebuild with fresh base images"),
+        Reason::Push | Reason::Pin => {},
+    }
+    if let Some(approver) = &task.approved_by {
+        log.line(&format!("Approved by {approver}"));
+    }
+}
+
+/// Files changed in the workspace since the `deployed` commit, or `None` if
+/// that's unknown, e.g. because the branch was force-pushed.
+fn changed_paths(path: &Path, deployed: &str, task: &Task, log: &BuildLog) -> Option<Vec<String>> {
+    let changed = git2::Repository::open(path)
+        .and_then(|repo| git::changed_paths(&repo, deployed, &task.commit_hash));
+    match changed {
+        Ok(changed) => Some(changed),
+        Err(err) => {
+            tracing::warn!(
+                "Failed to diff with the deployed commit {}: {}",
+                deployed,
+                err
+            );
+            log.line(&format!(
+                "Failed to find changes since the deployed commit {deployed}, deploying everything"
+            ));
+            None
+        },
+    }
+}
+
+/// Runs a `docker-compose` command in the workspace.
+fn run_compose(
+    args: &[&str],
+    path: &Path,
+    env: &DeployEnv,
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    log.line(&format!("Running `docker-compose {}`", args.join(" ")));
+    let status = command::run(env.compose().args(args).current_dir(path), log, control)
+        .wrap_err("failed to run `docker-compose`")?;
+
+    if !status.success() {
+        tracing::error!(
+            log = log.path().to_string_lossy().as_ref(),
+            "`docker-compose` returned failure ({}), see build log at {:?}",
+            status,
+            log.path(),
+        );
+        eyre::bail!("failed to deploy");
+    }
+    Ok(())
+}
+
+/// Runs the repo's tests in a compose project of their own, whose containers
+/// are removed afterwards whether tests pass or not.
+fn run_tests(
+    tests: &Tests,
+    path: &Path,
+    env: &DeployEnv,
+    project_name: &str,
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    let compose = || {
+        let mut command = env.compose_project(&tests.compose_file, project_name);
+        command.current_dir(path);
+        command
+    };
+    log.line(&format!(
+        "Running tests: `docker-compose -f {} run --rm {}`",
+        tests.compose_file, tests.service
+    ));
+    let res = command::run_with_tail(
+        compose().args(["run", "--rm", &tests.service]),
+        log,
+        control,
+        OUTPUT_TAIL_LINES,
+    );
+
+    // Cleanup isn't cancelled with the task, so that nothing is left behind
+    // by cancelled or timed out tests, but a hung cleanup doesn't block the
+    // runner either.
+    log.line("Removing test containers");
+    let cleanup = TaskControl::default();
+    cleanup.set_timeout(CLEANUP_TIMEOUT);
+    match command::run(
+        compose().args(["down", "--volumes", "--remove-orphans"]),
+        log,
+        &cleanup,
+    ) {
+        Ok(status) if status.success() => {},
+        Ok(status) => tracing::warn!("Failed to remove test containers ({})", status),
+        Err(err) => tracing::warn!("Failed to remove test containers: {:#}", err),
+    }
+
+    let (status, output) = res.wrap_err("failed to run tests")?;
+    if !status.success() {
+        return Err(TestsFailed {
+            status: status.to_string(),
+            output: output_tail(&output, OUTPUT_TAIL_BYTES),
+        }
+        .into());
+    }
+    log.line("Tests passed");
+    Ok(())
+}
+
+/// How the compose project is brought up.
+#[derive(Debug, Clone, Copy, Default)]
+struct Rollout<'a> {
+    /// Pull images of services and base images of builds, even if they're
+    /// present.
+    pull: bool,
+    /// Only these services are restarted if set.
+    services: Option<&'a [String]>,
+    /// One-off service that's run after images are built and before
+    /// containers are recreated.
+    migration: Option<&'a str>,
+    /// Wait for services to be running or healthy after recreating them.
+    wait: bool,
+}
+
+/// Builds and starts the compose project in the workspace. Images are pulled
+/// and built before any container is touched, so that a failed or slow build
+/// doesn't take the running deploy down.
+fn compose_up(
+    path: &Path,
+    env: &DeployEnv,
+    rollout: Rollout<'_>,
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<Timings> {
+    let services = rollout.services.unwrap_or_default();
+    let with_services = |args: &[&'static str]| -> Vec<&str> {
+        args.iter()
+            .copied()
+            .chain(services.iter().map(String::as_str))
+            .collect()
+    };
+
+    let started = Instant::now();
+    if rollout.pull {
+        // Services that are only built have nothing to pull.
+        let pull = with_services(&["pull", "--ignore-pull-failures"]);
+        run_compose(&pull, path, env, log, control)?;
+    }
+    let build: &[&str] = if rollout.pull {
+        &["build", "--pull"]
+    } else {
+        &["build"]
+    };
+    run_compose(&with_services(build), path, env, log, control)?;
+    if let Some(migration) = rollout.migration {
+        // The migration service is usually excluded from `up` with a profile,
+        // so it's only built when named.
+        let build_migration: Vec<_> = build.iter().copied().chain([migration]).collect();
+        run_compose(&build_migration, path, env, log, control)?;
+        run_migration(migration, path, env, log, control)?;
+    }
+    let build = started.elapsed();
+
+    let started = Instant::now();
+    let up: &[&str] = if rollout.wait {
+        &["up", "-d", "--wait"]
+    } else {
+        &["up", "-d"]
+    };
+    run_compose(&with_services(up), path, env, log, control)?;
+    let timings = Timings {
+        build,
+        switchover: started.elapsed(),
+    };
+    log.line(&format!("Deployed: {timings}"));
+    Ok(timings)
+}
+
+/// Runs the one-off migration service of the compose project.
+fn run_migration(
+    service: &str,
+    path: &Path,
+    env: &DeployEnv,
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    log.line(&format!(
+        "Running migration: `docker-compose run --rm {service}`"
+    ));
+    let (status, output) = command::run_with_tail(
+        env.compose()
+            .args(["run", "--rm", service])
+            .current_dir(path),
+        log,
+        control,
+        OUTPUT_TAIL_LINES,
+    )
+    .wrap_err("failed to run migration")?;
+    if !status.success() {
+        return Err(MigrationFailed {
+            status: status.to_string(),
+            output: output_tail(&output, OUTPUT_TAIL_BYTES),
+        }
+        .into());
+    }
+    log.line("Migration succeeded");
+    Ok(())
+}
+
+/// Commits that refs of the remote repo currently point to, by full ref
+/// names. libgit2 has no network timeouts, so listing runs in its own thread
+/// and is abandoned after `REMOTE_REFS_TIMEOUT`. A remote that doesn't answer
+/// keeps at most one thread busy: it isn't listed again until that thread
+/// finishes.
+pub fn remote_refs(
+    clone_url: &str,
+    repo_config: &RepoConfig,
+) -> eyre::Result<HashMap<String, String>> {
+    let Some(listing) = Listing::start(clone_url) else {
+        eyre::bail!("previous listing of refs of {} hasn't finished", clone_url);
+    };
+    let control = Arc::new(TaskControl::default());
+    control.set_timeout(REMOTE_REFS_TIMEOUT);
+    let (tx, rx) = mpsc::channel();
+    {
+        let clone_url = clone_url.to_owned();
+        let repo_config = repo_config.clone();
+        let control = control.clone();
+        thread::Builder::new()
+            .name("ls-remote".into())
+            .spawn(move || {
+                let known_hosts = repo_config.known_hosts();
+                let auth = git::RemoteAuth::new(
+                    &clone_url,
+                    repo_config.credentials.as_ref(),
+                    &known_hosts,
                 );
-                eyre::bail!("failed to deploy");
+                let refs = git::ls_remote(&clone_url, &auth, &control);
+                drop(listing);
+                // The receiver is gone if listing timed out.
+                let _ = tx.send(refs);
+            })
+            .wrap_err("failed to start listing thread")?;
+    }
+    let Ok(refs) = rx.recv_timeout(REMOTE_REFS_TIMEOUT) else {
+        // Aborts the transfer if the remote ever gets to it.
+        control.cancel();
+        eyre::bail!(
+            "failed to list refs of {}: {}",
+            clone_url,
+            Interrupted::TimedOut(REMOTE_REFS_TIMEOUT)
+        );
+    };
+    let refs = refs.wrap_err_with(|| format!("failed to list refs of {clone_url}"))?;
+    Ok(refs
+        .into_iter()
+        .map(|(name, oid)| (name, oid.to_string()))
+        .collect())
+}
+
+/// Fetches the task's commit and checks it out, unless it's older than the
+/// `deployed` one.
+fn sync_workspace(
+    task: &Task,
+    path: &Path,
+    mirrors: &Mirrors,
+    deployed: Option<&str>,
+    repo_config: &RepoConfig,
+    log: &BuildLog,
+    control: &TaskControl,
+) -> eyre::Result<()> {
+    let reference = &task.reference;
+    let known_hosts = repo_config.known_hosts();
+    let auth = git::RemoteAuth::new(
+        &task.clone_url,
+        repo_config.credentials.as_ref(),
+        &known_hosts,
+    );
+
+    log.line(&format!(
+        "Opening repo for {} at {}",
+        task.clone_url,
+        path.display()
+    ));
+    let mut repo = git::open_or_init(&task.clone_url, path, false)
+        .wrap_err("failed to open or initialize repo")?;
+    git::ensure_origin_url(&repo, &task.clone_url).wrap_err("failed to update remote URL")?;
+
+    let fetch = &repo_config.fetch;
+    // Shallow and partial fetches don't go through the mirror, see
+    // `FetchConfig`.
+    let (cli_env, fetched) = if fetch.use_cli() {
+        log.line(&format!("Fetching {reference}"));
+        let env = auth
+            .git_env(&known_hosts)
+            .map_err(|err| eyre::eyre!(err))
+            .wrap_err("failed to configure `git`")?;
+        git::fetch_with_cli(path, reference, fetch, &env, log, control)
+            .wrap_err("failed to fetch repo")?;
+        let fetched = git::ref_commit(&repo, &git::tracking_ref(reference))
+            .wrap_err("failed to find the fetched commit")?;
+        (Some(env), fetched)
+    } else {
+        log.line(&format!(
+            "Fetching {} into mirror {}",
+            reference,
+            mirrors.path(&task.branch_spec)?.display()
+        ));
+        let (objects, fetched) = mirrors.fetch(
+            &task.branch_spec,
+            &task.clone_url,
+            reference,
+            &auth,
+            control,
+        )?;
+        if git::set_alternates(&repo, &objects).wrap_err("failed to set alternates")? {
+            repo = git::open_or_init(&task.clone_url, path, false)
+                .wrap_err("failed to reopen repo")?;
+        }
+        (None, fetched)
+    };
+    git::ensure_commit(&repo, &task.commit_hash)
+        .wrap_err_with(|| format!("commit {} isn't on {}", task.commit_hash, reference))?;
+    control.check()?;
+
+    // If the ref moved since the push, the commit it points to now is
+    // deployed by the task of the later push.
+    if let Reason::Push = task.reason {
+        if !git::is_commit(&repo, &task.commit_hash, fetched)
+            .wrap_err("failed to compare with the fetched commit")?
+        {
+            eyre::bail!(
+                "{} points to {} rather than the pushed commit {}, it was pushed to again",
+                reference,
+                fetched,
+                task.commit_hash
+            );
+        }
+    }
+
+    if let Some(deployed) = deployed {
+        if git::is_ancestor(&repo, &task.commit_hash, deployed)
+            .wrap_err("failed to compare with the deployed commit")?
+        {
+            log.line(&format!(
+                "Commit {} is older than the deployed {}, skipping",
+                task.commit_hash, deployed
+            ));
+            return Err(Stale {
+                deployed: deployed.to_owned(),
             }
-        })
+            .into());
+        }
     }
+
+    log.line(&format!("Checking out {}", task.commit_hash));
+    match (&cli_env, &fetch.filter) {
+        (Some(env), Some(_)) => {
+            git::checkout_with_cli(path, &task.commit_hash, env, log, control)
+                .wrap_err("failed to checkout repo")?;
+        },
+        _ => git::checkout(&mut repo, &task.commit_hash).wrap_err("failed to checkout repo")?,
+    }
+
+    log.line("Updating submodules");
+    match &cli_env {
+        Some(env) => git::update_submodules_with_cli(path, env, log, control)?,
+        None => git::update_submodules(
+            &repo,
+            repo_config.credentials.as_ref(),
+            &known_hosts,
+            control,
+        )?,
+    }
+    Ok(())
 }
 
 impl Actor for Runner {
@@ -139,19 +1252,384 @@ impl Handler<Task> for Runner {
             repo.owner = task.branch_spec.owner.as_str(),
             repo.name = task.branch_spec.repo.as_str(),
             branch = task.branch_spec.branch.as_str(),
+            environment = task.environment.as_deref().unwrap_or_default(),
+            task_id = task.id.to_string().as_str(),
             url = task.url.as_str(),
             commit_hash = task.commit_hash.as_str(),
         );
         let _guard = span.enter();
-        let res = self.process_task(&task);
-        if let Err(err) = &res {
-            tracing::error!("{}", err);
+        let (status, timings) = self.run_task(&task);
+        match &status {
+            Status::Success => {},
+            Status::Fail(err) => tracing::error!("{}", err),
+            status => tracing::warn!("Build {}", status),
+        }
+        // Skipped tasks didn't change what's running.
+        if !matches!(status, Status::Stale(_)) {
+            if let Err(err) = self.context.deployments.record(&task, &status) {
+                tracing::error!("Failed to record deploy: {:#}", err);
+            }
         }
 
-        let task = Arc::new(task);
-        let status = Arc::new(res.into());
-        if let Err(err) = self.notifier.try_send(Notification { task, status }) {
+        let notification =
+            Notification::new(task, status, &self.context.repos).with_timings(timings);
+        if let Err(err) = self.notifier.try_send(notification) {
             tracing::error!("Failed to send notification: {}", err);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repos::Unit;
+
+    /// Directory for the test's files, emptied when the test starts.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("adm-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn context(dir: &Path) -> Context {
+        Context {
+            base_path: dir.join("repos"),
+            pass_env: Vec::new(),
+            lock_manager: LockManager::new(),
+            mirrors: Mirrors::new(dir.join("mirrors")),
+            secrets: SecretStore::new(dir),
+            vault: None,
+            deployments: Arc::new(Deployments::load(dir.join("deployments.json")).unwrap()),
+            logs: Arc::new(BuildLogs::new(dir.join("logs"), 10).unwrap()),
+            tasks: Arc::new(Tasks::new()),
+            repos: Arc::new(ReposConfig::default()),
+        }
+    }
+
+    /// Creates a repo with a single commit and returns a task deploying it.
+    fn origin_task(path: &Path) -> Task {
+        let repo = git2::Repository::init(path).unwrap();
+        std::fs::write(path.join("docker-compose.yml"), "services: {}\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("docker-compose.yml")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("adm", "adm@example.org").unwrap();
+        let commit = repo
+            .commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        let reference = repo.head().unwrap().name().unwrap().to_owned();
+        Task {
+            id: TaskId::generate(),
+            branch_spec: BranchSpec {
+                owner: "me".to_owned(),
+                repo: "app".to_owned(),
+                branch: reference.trim_start_matches("refs/heads/").to_owned(),
+            },
+            reference,
+            environment: None,
+            sender: None,
+            approved_by: None,
+            commit_hash: commit.to_string(),
+            url: String::new(),
+            clone_url: path.to_str().unwrap().to_owned(),
+            reason: Reason::Push,
+        }
+    }
+
+    #[test]
+    fn corrupted_workspace_is_recloned() {
+        let dir = test_dir("reclone");
+        let task = origin_task(&dir.join("origin"));
+        let context = context(&dir);
+        let log = context.logs.create(task.id).unwrap();
+        let path = dir.join("workspace");
+        let aside = dir.join(".broken").join(task.id.to_string());
+        std::fs::create_dir_all(&path).unwrap();
+        let prepare = || {
+            prepare_workspace(
+                &task,
+                &path,
+                &aside,
+                &context,
+                &RepoConfig::default(),
+                &log,
+                &TaskControl::default(),
+            )
+        };
+
+        prepare().unwrap();
+        assert!(path.join("docker-compose.yml").exists());
+        assert!(!aside.exists());
+
+        std::fs::write(path.join(".git").join("index"), "garbage").unwrap();
+        prepare().unwrap();
+        assert!(path.join("docker-compose.yml").exists());
+        assert_eq!(
+            std::fs::read(aside.join(".git").join("index")).unwrap(),
+            b"garbage"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_strict_ancestors_are_stale() {
+        let dir = test_dir("stale");
+        let origin = dir.join("origin");
+        let old = origin_task(&origin);
+        let repo = git2::Repository::open(&origin).unwrap();
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        let signature = git2::Signature::now("adm", "adm@example.org").unwrap();
+        let new = repo
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                "next",
+                &parent.tree().unwrap(),
+                &[&parent],
+            )
+            .unwrap()
+            .to_string();
+        let context = context(&dir);
+        let log = context.logs.create(old.id).unwrap();
+        let sync = |commit_hash: &str, deployed: &str| {
+            let task = Task {
+                commit_hash: commit_hash.to_owned(),
+                reason: Reason::Scheduled,
+                ..old.clone()
+            };
+            sync_workspace(
+                &task,
+                &dir.join("workspace"),
+                &context.mirrors,
+                Some(deployed),
+                &RepoConfig::default(),
+                &log,
+                &TaskControl::default(),
+            )
+        };
+
+        let err = sync(&old.commit_hash, &new).unwrap_err();
+        assert_eq!(err.downcast::<Stale>().unwrap().deployed, new);
+        // Redeploying the running commit isn't skipped.
+        sync(&new, &new).unwrap();
+        sync(&old.commit_hash, &old.commit_hash).unwrap();
+        sync(&new, &old.commit_hash).unwrap();
+        // Neither are deploys over a commit that's gone after a force-push.
+        sync(&old.commit_hash, "0123456789abcdef0123456789abcdef01234567").unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remotes_are_listed_one_at_a_time() {
+        let dir = test_dir("listing");
+        let task = origin_task(&dir.join("origin"));
+        let config = RepoConfig::default();
+
+        let listing = Listing::start(&task.clone_url).unwrap();
+        let err = remote_refs(&task.clone_url, &config).unwrap_err();
+        assert!(err.to_string().contains("hasn't finished"), "{}", err);
+        drop(listing);
+        let refs = remote_refs(&task.clone_url, &config).unwrap();
+        assert_eq!(refs.get(&task.reference), Some(&task.commit_hash));
+        // The listing thread is done with the remote.
+        assert!(Listing::start(&task.clone_url).is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broken_repos_are_pruned() {
+        let dir = test_dir("prune-broken");
+        let ids = ["1", "2", "3", "4", "5"];
+        for id in &ids {
+            std::fs::create_dir_all(dir.join(id).join(".git")).unwrap();
+        }
+        std::fs::create_dir(dir.join("other")).unwrap();
+
+        prune_broken(&dir).unwrap();
+        let mut left: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["3", "4", "5", "other"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|&line| line.to_owned()).collect()
+    }
+
+    #[test]
+    fn output_tail_fits_budget() {
+        let output = lines(&["first", "second", "third"]);
+        assert_eq!(output_tail(&output, 100), "first\nsecond\nthird");
+        assert_eq!(output_tail(&output, 12), "second\nthird");
+        assert_eq!(output_tail(&output, 11), "third");
+        assert_eq!(output_tail(&[], 10), "");
+
+        // Long lines are cut from the start on a character boundary.
+        let output = lines(&["short", "ééééé"]);
+        let tail = output_tail(&output, 8);
+        assert_eq!(tail, "…éé");
+        assert!(tail.len() <= 8);
+        assert_eq!(output_tail(&output, 9), "…ééé");
+    }
+
+    #[test]
+    fn timings_add_up() {
+        let mut total = Timings::default();
+        total += Timings {
+            build: Duration::from_secs(90),
+            switchover: Duration::from_millis(1500),
+        };
+        total += Timings {
+            build: Duration::from_secs(30),
+            switchover: Duration::from_millis(700),
+        };
+        assert_eq!(total.build, Duration::from_secs(120));
+        assert_eq!(total.switchover, Duration::from_millis(2200));
+    }
+
+    #[test]
+    fn timings_display_whole_seconds() {
+        let timings = Timings {
+            build: Duration::from_millis(125_900),
+            switchover: Duration::from_millis(2200),
+        };
+        assert_eq!(timings.to_string(), "build took 2m 5s, switchover took 2s");
+        assert_eq!(
+            Timings::default().to_string(),
+            "build took 0s, switchover took 0s"
+        );
+    }
+
+    /// Environment with a fake `docker-compose` that records its arguments
+    /// in `dir/calls` and fails for units listed in `failing`.
+    fn fake_compose(dir: &Path, failing: &str) -> DeployEnv {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bin = dir.join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        let script = bin.join("docker-compose");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\necho \"$ADM_UNIT $*\" >> \"$CALLS\"\ncase \" $FAILING \" in *\" $ADM_UNIT \
+             \"*) exit 1 ;; esac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut env = DeployEnv::default();
+        env.set("PATH", format!("{}:/usr/bin:/bin", bin.display()));
+        env.set("CALLS", dir.join("calls"));
+        env.set("FAILING", failing);
+        env
+    }
+
+    fn compose_calls(dir: &Path) -> Vec<String> {
+        std::fs::read_to_string(dir.join("calls"))
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn failed_units_are_reported_together() {
+        let dir = test_dir("units");
+        let task = origin_task(&dir.join("origin"));
+        let path = dir.join("workspace");
+        let repo_config = RepoConfig {
+            units: ["api", "web", "worker"]
+                .iter()
+                .map(|&name| {
+                    std::fs::create_dir_all(path.join(name)).unwrap();
+                    Unit {
+                        name: name.to_owned(),
+                        path: name.to_owned(),
+                        paths: None,
+                        telegram_groups: None,
+                    }
+                })
+                .collect(),
+            ..RepoConfig::default()
+        };
+        let context = Arc::new(context(&dir));
+        let log = context.logs.create(task.id).unwrap();
+        let mut env = fake_compose(&dir, "api worker");
+
+        let err = System::new("test")
+            .block_on(async move {
+                let notifier = Notifier::new(crate::notifier::Config {
+                    telegram_token: None,
+                    telegram_groups: None,
+                })
+                .start();
+                Runner::new(context, notifier).deploy_units(
+                    &task,
+                    &path,
+                    &repo_config,
+                    &mut env,
+                    &log,
+                    &TaskControl::default(),
+                )
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to deploy units api: failed to deploy; worker: failed to deploy"
+        );
+        // The unit in between is still deployed.
+        assert_eq!(compose_calls(&dir), [
+            "api build",
+            "web build",
+            "web up -d",
+            "worker build",
+        ]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compose_up_runs_phases_in_order() {
+        let dir = test_dir("compose-up");
+        let context = context(&dir);
+        let log = context.logs.create(TaskId::generate()).unwrap();
+        let mut env = fake_compose(&dir, "");
+        env.set("ADM_UNIT", "app");
+        let services = ["web".to_owned()];
+
+        compose_up(
+            &dir,
+            &env,
+            Rollout::default(),
+            &log,
+            &TaskControl::default(),
+        )
+        .unwrap();
+        assert_eq!(compose_calls(&dir), ["app build", "app up -d"]);
+
+        std::fs::remove_file(dir.join("calls")).unwrap();
+        let rollout = Rollout {
+            pull: true,
+            services: Some(&services),
+            migration: Some("migrate"),
+            wait: true,
+        };
+        compose_up(&dir, &env, rollout, &log, &TaskControl::default()).unwrap();
+        assert_eq!(compose_calls(&dir), [
+            "app pull --ignore-pull-failures web",
+            "app build --pull web",
+            "app build --pull migrate",
+            "app run --rm migrate",
+            "app up -d --wait web",
+        ]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+    process::Stdio,
+};
+
+use color_eyre::eyre::{self, WrapErr as _};
+use serde::Deserialize;
+
+use super::env::DeployEnv;
+use crate::repos::ServicePaths;
+
+/// Part of `docker-compose config` output that's needed to find dependents.
+#[derive(Debug, Deserialize)]
+struct ComposeConfig {
+    #[serde(default)]
+    services: HashMap<String, ComposeService>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComposeService {
+    #[serde(default)]
+    depends_on: DependsOn,
+}
+
+/// Dependencies are either listed or mapped to conditions they wait for.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DependsOn {
+    List(Vec<String>),
+    Map(HashMap<String, serde_json::Value>),
+}
+
+impl Default for DependsOn {
+    fn default() -> Self {
+        Self::List(Vec::new())
+    }
+}
+
+impl DependsOn {
+    fn any_of(&self, services: &BTreeSet<String>) -> bool {
+        match self {
+            Self::List(list) => list.iter().any(|name| services.contains(name)),
+            Self::Map(map) => map.keys().any(|name| services.contains(name)),
+        }
+    }
+}
+
+/// Services affected by the `changed` files, or the first changed file that
+/// isn't mapped to any service.
+pub fn affected<'a>(
+    services: &[ServicePaths],
+    changed: &'a [String],
+) -> Result<BTreeSet<String>, &'a str> {
+    let mut affected = BTreeSet::new();
+    for path in changed {
+        let mut mapped = false;
+        for service in services.iter().filter(|service| service.is_affected(path)) {
+            affected.insert(service.service.clone());
+            mapped = true;
+        }
+        if !mapped {
+            return Err(path);
+        }
+    }
+    Ok(affected)
+}
+
+/// Whether `docker-compose version --short` output is of Compose v2 or
+/// newer. v1 can't print the config as JSON.
+fn supports_json_config(version: &str) -> bool {
+    version
+        .trim()
+        .trim_start_matches('v')
+        .split('.')
+        .next()
+        .and_then(|major| major.parse::<u32>().ok())
+        .is_some_and(|major| major >= 2)
+}
+
+fn compose_version(path: &Path, env: &DeployEnv) -> eyre::Result<String> {
+    let output = env
+        .compose()
+        .args(["version", "--short"])
+        .current_dir(path)
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err("failed to run `docker-compose version`")?;
+    if !output.status.success() {
+        eyre::bail!(
+            "`docker-compose version` returned failure ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Adds services that depend on the given ones, directly or not, according
+/// to the compose project in `path`. Needs Compose v2.
+pub fn with_dependents(
+    services: BTreeSet<String>,
+    path: &Path,
+    env: &DeployEnv,
+) -> eyre::Result<BTreeSet<String>> {
+    let version = compose_version(path, env)?;
+    if !supports_json_config(&version) {
+        eyre::bail!("restarting only affected services needs Docker Compose v2, found {version}");
+    }
+    let output = env
+        .compose()
+        .args(["config", "--format", "json"])
+        .current_dir(path)
+        .stdin(Stdio::null())
+        .output()
+        .wrap_err("failed to run `docker-compose config`")?;
+    if !output.status.success() {
+        eyre::bail!(
+            "`docker-compose config` returned failure ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim(),
+        );
+    }
+    let config: ComposeConfig = serde_json::from_slice(&output.stdout)
+        .wrap_err("failed to parse `docker-compose config` output")?;
+    add_dependents(&config, services)
+}
+
+fn add_dependents(
+    config: &ComposeConfig,
+    mut services: BTreeSet<String>,
+) -> eyre::Result<BTreeSet<String>> {
+    if let Some(unknown) = services
+        .iter()
+        .find(|name| !config.services.contains_key(*name))
+    {
+        eyre::bail!("service {} isn't defined in the compose file", unknown);
+    }
+
+    loop {
+        let dependents: Vec<_> = config
+            .services
+            .iter()
+            .filter(|(name, service)| {
+                !services.contains(*name) && service.depends_on.any_of(&services)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        if dependents.is_empty() {
+            return Ok(services);
+        }
+        services.extend(dependents);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service_paths(config: &str) -> Vec<ServicePaths> {
+        #[derive(Deserialize)]
+        struct Config {
+            services: Vec<ServicePaths>,
+        }
+        toml::from_str::<Config>(config).unwrap().services
+    }
+
+    fn names(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    fn changed(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|&path| path.to_owned()).collect()
+    }
+
+    #[test]
+    fn affected_services() {
+        let services = service_paths(
+            r#"
+            [[services]]
+            service = "api"
+            paths = ["api/**", "shared/**"]
+
+            [[services]]
+            service = "web"
+            paths = ["web/**", "shared/**"]
+            "#,
+        );
+        assert_eq!(
+            affected(&services, &changed(&["api/main.rs"])),
+            Ok(names(&["api"]))
+        );
+        assert_eq!(
+            affected(&services, &changed(&["api/main.rs", "web/index.html"])),
+            Ok(names(&["api", "web"]))
+        );
+        assert_eq!(
+            affected(&services, &changed(&["shared/lib.rs"])),
+            Ok(names(&["api", "web"]))
+        );
+        assert_eq!(
+            affected(&services, &changed(&["api/main.rs", "docker-compose.yml"])),
+            Err("docker-compose.yml")
+        );
+        assert_eq!(affected(&services, &[]), Ok(BTreeSet::new()));
+    }
+
+    #[test]
+    fn dependents() {
+        let config: ComposeConfig = serde_json::from_value(serde_json::json!({
+            "services": {
+                "db": {},
+                "api": {"depends_on": ["db"]},
+                "worker": {"depends_on": {"api": {"condition": "service_started"}}},
+                "web": {"depends_on": ["api"]},
+                "docs": {},
+            },
+        }))
+        .unwrap();
+        assert_eq!(
+            add_dependents(&config, names(&["db"])).unwrap(),
+            names(&["api", "db", "web", "worker"])
+        );
+        assert_eq!(
+            add_dependents(&config, names(&["web"])).unwrap(),
+            names(&["web"])
+        );
+        assert_eq!(
+            add_dependents(&config, names(&["docs"])).unwrap(),
+            names(&["docs"])
+        );
+        assert!(add_dependents(&config, names(&["cache"])).is_err());
+    }
+
+    #[test]
+    fn compose_versions() {
+        assert!(supports_json_config("2.20.2\n"));
+        assert!(supports_json_config("v2.5.0"));
+        assert!(!supports_json_config("1.29.2"));
+        assert!(!supports_json_config(""));
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{Read as _, Write as _},
+    os::unix::fs::{DirBuilderExt as _, OpenOptionsExt as _},
+    path::{Path, PathBuf},
+    str::FromStr as _,
+};
+
+use age::x25519::Identity;
+use color_eyre::eyre::{self, WrapErr as _};
+use secrecy::ExposeSecret as _;
+use secstr::SecUtf8;
+
+use crate::runner::{dir_name, TaskId};
+
+pub type Secrets = BTreeMap<String, SecUtf8>;
+
+/// Deploy secrets of every repo and environment, encrypted at rest with an
+/// age X25519 key that's generated on first use and never leaves the host.
+#[derive(Debug)]
+pub struct SecretStore {
+    key_path: PathBuf,
+    root: PathBuf,
+}
+
+impl SecretStore {
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            key_path: state_dir.join("secrets.key"),
+            root: state_dir.join("secrets"),
+        }
+    }
+
+    /// Where the env file with secrets of a running task is written.
+    pub fn env_file_path(&self, id: TaskId) -> PathBuf {
+        self.root.join(".tmp").join(format!("{id}.env"))
+    }
+
+    /// Secrets of the whole repo live in `owner/repo/secrets.age`, ones that
+    /// are specific to an environment in `owner/repo/env/<name>.age`.
+    fn path(&self, owner: &str, repo: &str, environment: Option<&str>) -> PathBuf {
+        let mut path = self.root.join(dir_name(owner));
+        path.push(dir_name(repo));
+        match environment {
+            Some(environment) => {
+                path.push("env");
+                path.push(format!("{}.age", dir_name(environment)));
+            },
+            None => path.push("secrets.age"),
+        }
+        path
+    }
+
+    /// The key that's being rotated to is also tried, so that files that
+    /// were already re-encrypted by an interrupted rotation can be read.
+    fn identities(&self) -> eyre::Result<Vec<Identity>> {
+        let mut identities = vec![read_identity(&self.key_path)?];
+        let next = next_key_path(&self.key_path);
+        if next.exists() {
+            identities.push(read_identity(&next)?);
+        }
+        Ok(identities)
+    }
+
+    fn identity_or_generate(&self) -> eyre::Result<Identity> {
+        if self.key_path.exists() {
+            return read_identity(&self.key_path);
+        }
+        tracing::info!("Generating secrets key at {:?}", self.key_path);
+        let identity = Identity::generate();
+        write_identity(&self.key_path, &identity)?;
+        Ok(identity)
+    }
+
+    /// Returns an empty set if there are no secrets for the repo, without
+    /// touching the key.
+    pub fn load(
+        &self,
+        owner: &str,
+        repo: &str,
+        environment: Option<&str>,
+    ) -> eyre::Result<Secrets> {
+        let path = self.path(owner, repo, environment);
+        if !path.exists() {
+            return Ok(Secrets::new());
+        }
+        decrypt(&path, &self.identities()?)
+    }
+
+    pub fn save(
+        &self,
+        owner: &str,
+        repo: &str,
+        environment: Option<&str>,
+        secrets: &Secrets,
+    ) -> eyre::Result<()> {
+        let path = self.path(owner, repo, environment);
+        if secrets.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(err).wrap_err_with(|| format!("failed to remove {}", path.display()))
+                },
+                _ => Ok(()),
+            };
+        }
+        encrypt(&path, &self.identity_or_generate()?, secrets)
+    }
+
+    /// Re-encrypts all secrets with a freshly generated key. Returns the
+    /// number of re-encrypted files.
+    pub fn rotate_key(&self) -> eyre::Result<usize> {
+        let identities = self.identities()?;
+        let mut files = Vec::new();
+        if self.root.exists() {
+            find_files(&self.root, &mut files)?;
+        }
+
+        let next_path = next_key_path(&self.key_path);
+        let next = Identity::generate();
+        write_identity(&next_path, &next)?;
+        for path in &files {
+            let secrets = decrypt(path, &identities)?;
+            encrypt(path, &next, &secrets)?;
+        }
+        fs::rename(&next_path, &self.key_path)
+            .wrap_err_with(|| format!("failed to replace {}", self.key_path.display()))?;
+        Ok(files.len())
+    }
+}
+
+/// Secrets are passed to deploy commands as environment variables, so their
+/// names have to be valid ones.
+pub fn validate_name(name: &str) -> eyre::Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    eyre::ensure!(valid, "{:?} isn't a valid environment variable name", name);
+    eyre::ensure!(
+        !name.starts_with("ADM_") && name != "COMPOSE_PROJECT_NAME",
+        "{} is reserved for deploy metadata",
+        name,
+    );
+    Ok(())
+}
+
+fn next_key_path(key_path: &Path) -> PathBuf {
+    let mut name = key_path.file_name().unwrap_or_default().to_owned();
+    name.push(".next");
+    key_path.with_file_name(name)
+}
+
+fn create_private(path: &Path) -> eyre::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)
+            .wrap_err_with(|| format!("failed to create directory {}", parent.display()))?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .wrap_err_with(|| format!("failed to create {}", path.display()))
+}
+
+/// Writes the file next to `path` and moves it into place, so that readers
+/// never see a partially written file.
+fn write_atomically(path: &Path, contents: &[u8]) -> eyre::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let mut file = create_private(&tmp)?;
+    file.write_all(contents)
+        .and_then(|()| file.sync_all())
+        .wrap_err_with(|| format!("failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path)
+        .wrap_err_with(|| format!("failed to move {} to {}", tmp.display(), path.display()))
+}
+
+/// Reads a key in the format of `age-keygen`.
+fn read_identity(path: &Path) -> eyre::Result<Identity> {
+    let content = fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read secrets key {}", path.display()))?;
+    let line = content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| eyre::eyre!("secrets key {:?} is empty", path))?;
+    Identity::from_str(line)
+        .map_err(|err| eyre::eyre!("failed to parse secrets key {:?}: {}", path, err))
+}
+
+fn write_identity(path: &Path, identity: &Identity) -> eyre::Result<()> {
+    let content = format!(
+        "# public key: {}\n{}\n",
+        identity.to_public(),
+        identity.to_string().expose_secret(),
+    );
+    write_atomically(path, content.as_bytes())
+}
+
+fn decrypt(path: &Path, identities: &[Identity]) -> eyre::Result<Secrets> {
+    let file = File::open(path).wrap_err_with(|| format!("failed to open {}", path.display()))?;
+    let decryptor = match age::Decryptor::new(file)
+        .wrap_err_with(|| format!("failed to read {}", path.display()))?
+    {
+        age::Decryptor::Recipients(decryptor) => decryptor,
+        age::Decryptor::Passphrase(_) => eyre::bail!("{:?} is encrypted with a passphrase", path),
+    };
+    let mut plaintext = String::new();
+    decryptor
+        .decrypt(identities.iter().map(|i| -> &dyn age::Identity { i }))
+        .wrap_err_with(|| format!("failed to decrypt {}", path.display()))?
+        .read_to_string(&mut plaintext)
+        .wrap_err_with(|| format!("failed to decrypt {}", path.display()))?;
+    let secrets: BTreeMap<String, String> =
+        serde_json::from_str(SecUtf8::from(plaintext).unsecure())
+            .wrap_err_with(|| format!("failed to parse {}", path.display()))?;
+    Ok(secrets
+        .into_iter()
+        .map(|(name, value)| (name, SecUtf8::from(value)))
+        .collect())
+}
+
+fn encrypt(path: &Path, identity: &Identity, secrets: &Secrets) -> eyre::Result<()> {
+    let plaintext = SecUtf8::from(serde_json::to_string(
+        &secrets
+            .iter()
+            .map(|(name, value)| (name, value.unsecure()))
+            .collect::<BTreeMap<_, _>>(),
+    )?);
+    let encryptor = age::Encryptor::with_recipients(vec![Box::new(identity.to_public())]);
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut ciphertext)
+        .wrap_err("failed to encrypt secrets")?;
+    writer
+        .write_all(plaintext.unsecure().as_bytes())
+        .and_then(|()| writer.finish().map(drop))
+        .wrap_err("failed to encrypt secrets")?;
+    write_atomically(path, &ciphertext)
+}
+
+fn find_files(dir: &Path, files: &mut Vec<PathBuf>) -> eyre::Result<()> {
+    for entry in fs::read_dir(dir).wrap_err_with(|| format!("failed to read {}", dir.display()))? {
+        let path = entry
+            .wrap_err_with(|| format!("failed to read {}", dir.display()))?
+            .path();
+        if path.is_dir() {
+            find_files(&path, files)?;
+        } else if path.extension() == Some("age".as_ref()) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+
+    fn store(name: &str) -> (PathBuf, SecretStore) {
+        let dir = std::env::temp_dir().join(format!("adm-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = SecretStore::new(&dir);
+        (dir, store)
+    }
+
+    fn secrets(pairs: &[(&str, &str)]) -> Secrets {
+        pairs
+            .iter()
+            .map(|(name, value)| ((*name).to_owned(), SecUtf8::from(*value)))
+            .collect()
+    }
+
+    fn plain(secrets: &Secrets) -> Vec<(&str, &str)> {
+        secrets
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.unsecure()))
+            .collect()
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let (dir, store) = store("secrets");
+        assert!(store.load("me", "app", None).unwrap().is_empty());
+        assert!(!store.key_path.exists());
+
+        let mut repo = secrets(&[("TOKEN", "t0ken")]);
+        store.save("me", "app", None, &repo).unwrap();
+        store
+            .save(
+                "me",
+                "app",
+                Some("prod"),
+                &secrets(&[("DB_PASSWORD", "hunter2")]),
+            )
+            .unwrap();
+        let path = store.path("me", "app", None);
+        assert!(!fs::read(&path).unwrap().windows(5).any(|w| w == b"t0ken"));
+        assert_eq!(plain(&store.load("me", "app", None).unwrap()), [(
+            "TOKEN", "t0ken"
+        )]);
+        assert_eq!(plain(&store.load("me", "app", Some("prod")).unwrap()), [(
+            "DB_PASSWORD",
+            "hunter2"
+        )]);
+        assert!(store.load("me", "app", Some("staging")).unwrap().is_empty());
+
+        repo.insert("OTHER".to_owned(), "other".into());
+        store.save("me", "app", None, &repo).unwrap();
+        assert_eq!(plain(&store.load("me", "app", None).unwrap()), [
+            ("OTHER", "other"),
+            ("TOKEN", "t0ken")
+        ]);
+
+        store.save("me", "app", None, &Secrets::new()).unwrap();
+        assert!(!path.exists());
+        assert!(store.load("me", "app", None).unwrap().is_empty());
+        // Removing what isn't there is fine.
+        store.save("me", "app", None, &Secrets::new()).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_private() {
+        let (dir, store) = store("secrets-private");
+        store
+            .save("me", "app", None, &secrets(&[("TOKEN", "t0ken")]))
+            .unwrap();
+        assert_eq!(mode(&store.key_path), 0o600);
+        let path = store.path("me", "app", None);
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        store.rotate_key().unwrap();
+        assert_eq!(mode(&store.key_path), 0o600);
+        assert_eq!(mode(&path), 0o600);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_replaces_the_key() {
+        let (dir, store) = store("secrets-rotate");
+        store
+            .save("me", "app", None, &secrets(&[("TOKEN", "t0ken")]))
+            .unwrap();
+        store
+            .save(
+                "me",
+                "app",
+                Some("prod"),
+                &secrets(&[("DB_PASSWORD", "hunter2")]),
+            )
+            .unwrap();
+        let old = [read_identity(&store.key_path).unwrap()];
+
+        assert_eq!(store.rotate_key().unwrap(), 2);
+        assert!(!next_key_path(&store.key_path).exists());
+        let new = [read_identity(&store.key_path).unwrap()];
+        for environment in [None, Some("prod")] {
+            let path = store.path("me", "app", environment);
+            assert!(decrypt(&path, &old).is_err());
+            assert!(!decrypt(&path, &new).unwrap().is_empty());
+        }
+        assert_eq!(plain(&store.load("me", "app", None).unwrap()), [(
+            "TOKEN", "t0ken"
+        )]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_rotation_can_be_resumed() {
+        let (dir, store) = store("secrets-interrupted");
+        store
+            .save("me", "app", None, &secrets(&[("TOKEN", "t0ken")]))
+            .unwrap();
+        store
+            .save(
+                "me",
+                "app",
+                Some("prod"),
+                &secrets(&[("DB_PASSWORD", "hunter2")]),
+            )
+            .unwrap();
+
+        // Stop after re-encrypting one of the files.
+        let next = Identity::generate();
+        write_identity(&next_key_path(&store.key_path), &next).unwrap();
+        let path = store.path("me", "app", None);
+        let repo = decrypt(&path, &store.identities().unwrap()).unwrap();
+        encrypt(&path, &next, &repo).unwrap();
+
+        assert_eq!(plain(&store.load("me", "app", None).unwrap()), [(
+            "TOKEN", "t0ken"
+        )]);
+        assert_eq!(plain(&store.load("me", "app", Some("prod")).unwrap()), [(
+            "DB_PASSWORD",
+            "hunter2"
+        )]);
+
+        assert_eq!(store.rotate_key().unwrap(), 2);
+        assert!(!next_key_path(&store.key_path).exists());
+        let new = [read_identity(&store.key_path).unwrap()];
+        for environment in [None, Some("prod")] {
+            let path = store.path("me", "app", environment);
+            assert!(!decrypt(&path, &new).unwrap().is_empty());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use actix_web::web::Bytes;
+use awc::error::PayloadError;
+use color_eyre::eyre::{self, WrapErr as _};
+use secstr::SecUtf8;
+use serde::Deserialize;
+
+use crate::{
+    config::deserialize_opt_secutf8,
+    repos::{deserialize_opt_duration, VaultSecrets},
+    secrets::{self, Secrets},
+};
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Settings of a Vault-compatible server, read from `ADM_VAULT_*` variables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// Base URL of the server, e.g. `https://vault.example.com:8200`.
+    /// Vault is disabled if it isn't set.
+    pub addr: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_opt_secutf8")]
+    pub token: Option<SecUtf8>,
+    pub role_id: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_opt_secutf8")]
+    pub secret_id: Option<SecUtf8>,
+    /// Mount point of the KV v2 engine.
+    #[serde(default = "default_mount")]
+    pub mount: String,
+    #[serde(default, deserialize_with = "deserialize_opt_duration")]
+    pub cache_ttl: Option<Duration>,
+}
+
+fn default_mount() -> String {
+    "secret".into()
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum VaultError {
+    #[error("failed to send request to Vault: {0}")]
+    Request(String),
+    #[error("Vault returned {status} for {path}: {body}")]
+    Status {
+        status: u16,
+        path: String,
+        body: String,
+    },
+    #[error("failed to parse Vault response for {path}: {error}")]
+    Parse { path: String, error: String },
+    #[error("Vault secret {path} has key {key:?}, which isn't a valid variable name")]
+    InvalidKey { path: String, key: String },
+}
+
+enum Auth {
+    Token(SecUtf8),
+    AppRole { role_id: String, secret_id: SecUtf8 },
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Token(_) => f.write_str("Token"),
+            Self::AppRole { role_id, .. } => {
+                f.debug_struct("AppRole").field("role_id", role_id).finish()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    token: SecUtf8,
+    expires: Option<Instant>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginAuth {
+    client_token: String,
+    lease_duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvResponse {
+    data: KvData,
+}
+
+#[derive(Debug, Deserialize)]
+struct KvData {
+    data: BTreeMap<String, serde_json::Value>,
+}
+
+async fn response_error<S>(path: &str, resp: &mut awc::ClientResponse<S>) -> VaultError
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    let body = resp
+        .body()
+        .await
+        .map(|body| String::from_utf8_lossy(&body).into_owned())
+        .unwrap_or_default();
+    VaultError::Status {
+        status: resp.status().as_u16(),
+        path: path.to_owned(),
+        body,
+    }
+}
+
+/// Pooled connections that were closed by the server aren't always noticed by
+/// `awc`, and there are only a few requests per deploy, so every request gets
+/// a fresh client.
+fn client() -> awc::Client {
+    awc::Client::new()
+}
+
+struct Inner {
+    addr: String,
+    mount: String,
+    auth: Auth,
+    cache_ttl: Duration,
+    token: RefCell<Option<Token>>,
+    cache: RefCell<HashMap<String, (Instant, Secrets)>>,
+}
+
+impl fmt::Debug for Inner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Inner")
+            .field("addr", &self.addr)
+            .field("mount", &self.mount)
+            .field("auth", &self.auth)
+            .field("cache_ttl", &self.cache_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Inner {
+    async fn token(&self) -> Result<SecUtf8, VaultError> {
+        let (role_id, secret_id) = match &self.auth {
+            Auth::Token(token) => return Ok(token.clone()),
+            Auth::AppRole { role_id, secret_id } => (role_id, secret_id),
+        };
+        if let Some(token) = &*self.token.borrow() {
+            if token
+                .expires
+                .map_or(true, |expires| Instant::now() < expires)
+            {
+                return Ok(token.token.clone());
+            }
+        }
+
+        tracing::info!("Logging in to Vault with AppRole {}", role_id);
+        let path = "auth/approle/login";
+        let mut resp = client()
+            .post(format!("{}/v1/{}", self.addr, path))
+            .send_json(&serde_json::json!({
+                "role_id": role_id,
+                "secret_id": secret_id.unsecure(),
+            }))
+            .await
+            .map_err(|err| VaultError::Request(err.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(response_error(path, &mut resp).await);
+        }
+        let login: LoginResponse = resp.json().await.map_err(|err| VaultError::Parse {
+            path: path.to_owned(),
+            error: err.to_string(),
+        })?;
+        let LoginAuth {
+            client_token,
+            lease_duration,
+        } = login.auth;
+        let token = SecUtf8::from(client_token);
+        // Renew a bit before the lease actually ends.
+        let expires = (lease_duration > 0)
+            .then(|| Instant::now() + Duration::from_secs(lease_duration) * 9 / 10);
+        *self.token.borrow_mut() = Some(Token {
+            token: token.clone(),
+            expires,
+        });
+        Ok(token)
+    }
+
+    async fn read(&self, path: &str) -> Result<Secrets, VaultError> {
+        let url = format!(
+            "{}/v1/{}/data/{}",
+            self.addr,
+            self.mount,
+            path.trim_start_matches('/')
+        );
+        let mut retried = false;
+        let mut resp = loop {
+            let token = self.token().await?;
+            let resp = client()
+                .get(&url)
+                .header("X-Vault-Token", token.unsecure())
+                .send()
+                .await
+                .map_err(|err| VaultError::Request(err.to_string()))?;
+            // The AppRole token could have been revoked before its lease ended.
+            if resp.status().as_u16() == 403
+                && !retried
+                && matches!(self.auth, Auth::AppRole { .. })
+            {
+                retried = true;
+                self.token.borrow_mut().take();
+                continue;
+            }
+            break resp;
+        };
+
+        match resp.status().as_u16() {
+            404 => return Ok(Secrets::new()),
+            status if status >= 400 => return Err(response_error(path, &mut resp).await),
+            _ => {},
+        }
+        let kv: KvResponse = resp.json().await.map_err(|err| VaultError::Parse {
+            path: path.to_owned(),
+            error: err.to_string(),
+        })?;
+        kv.data
+            .data
+            .into_iter()
+            .map(|(key, value)| {
+                if secrets::validate_name(&key).is_err() {
+                    return Err(VaultError::InvalidKey {
+                        path: path.to_owned(),
+                        key,
+                    });
+                }
+                let value = match value {
+                    serde_json::Value::String(value) => value,
+                    value => value.to_string(),
+                };
+                Ok((key, SecUtf8::from(value)))
+            })
+            .collect()
+    }
+
+    async fn fetch(&self, path: String) -> Result<Secrets, VaultError> {
+        if let Some((fetched, secrets)) = self.cache.borrow().get(&path) {
+            if fetched.elapsed() < self.cache_ttl {
+                return Ok(secrets.clone());
+            }
+        }
+        let secrets = self.read(&path).await?;
+        self.cache
+            .borrow_mut()
+            .insert(path, (Instant::now(), secrets.clone()));
+        Ok(secrets)
+    }
+}
+
+/// Fetches secrets from the KV v2 engine of a Vault-compatible server.
+/// Values are cached for `cache_ttl`.
+#[derive(Debug)]
+pub struct Vault {
+    inner: Rc<Inner>,
+}
+
+impl Vault {
+    /// Returns `None` if Vault isn't configured.
+    pub fn new(config: Config) -> eyre::Result<Option<Self>> {
+        let Config {
+            addr,
+            token,
+            role_id,
+            secret_id,
+            mount,
+            cache_ttl,
+        } = config;
+        let addr = match addr {
+            Some(addr) => addr.trim_end_matches('/').to_owned(),
+            None => return Ok(None),
+        };
+        let auth = match (token, role_id, secret_id) {
+            (Some(token), None, None) => Auth::Token(token),
+            (None, Some(role_id), Some(secret_id)) => Auth::AppRole { role_id, secret_id },
+            _ => eyre::bail!(
+                "Vault needs either ADM_VAULT_TOKEN or both ADM_VAULT_ROLE_ID and \
+                 ADM_VAULT_SECRET_ID"
+            ),
+        };
+        Ok(Some(Self {
+            inner: Rc::new(Inner {
+                addr,
+                mount: mount.trim_matches('/').to_owned(),
+                auth,
+                cache_ttl: cache_ttl.unwrap_or(DEFAULT_CACHE_TTL),
+                token: RefCell::new(None),
+                cache: RefCell::new(HashMap::new()),
+            }),
+        }))
+    }
+}
+
+impl Actor for Vault {
+    type Context = Context<Self>;
+}
+
+#[derive(Debug, Message)]
+#[rtype(result = "Result<Secrets, VaultError>")]
+pub struct Fetch {
+    pub path: String,
+}
+
+impl Handler<Fetch> for Vault {
+    type Result = ResponseFuture<Result<Secrets, VaultError>>;
+
+    fn handle(&mut self, msg: Fetch, _ctx: &mut Self::Context) -> Self::Result {
+        let inner = self.inner.clone();
+        Box::pin(async move { inner.fetch(msg.path).await })
+    }
+}
+
+/// Handle for fetching secrets from synchronous runners.
+#[derive(Debug, Clone)]
+pub struct Client {
+    vault: Addr<Vault>,
+}
+
+impl Client {
+    pub fn new(vault: Addr<Vault>) -> Self {
+        Self { vault }
+    }
+
+    /// Blocks until the secrets are fetched. Mustn't be called from the
+    /// actor system's own thread.
+    pub fn fetch(&self, path: &str) -> eyre::Result<Secrets> {
+        futures::executor::block_on(self.vault.send(Fetch {
+            path: path.to_owned(),
+        }))
+        .wrap_err("Vault client is stopped")?
+        .map_err(Into::into)
+    }
+
+    /// Adds the secrets read from Vault to `secrets`, unless they're already
+    /// there: locally stored secrets take precedence. Fails if any of the
+    /// required keys is missing.
+    pub fn fetch_into(
+        &self,
+        secrets: &mut Secrets,
+        vault_secrets: &VaultSecrets,
+    ) -> eyre::Result<()> {
+        for (name, value) in self.fetch(&vault_secrets.path)? {
+            secrets.entry(name).or_insert(value);
+        }
+        for name in &vault_secrets.required {
+            eyre::ensure!(
+                secrets.contains_key(name),
+                "required secret {} is missing from Vault path {}",
+                name,
+                vault_secrets.path,
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+    };
+
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+    use super::*;
+
+    /// Number of logins and secret reads the stub server has handled.
+    #[derive(Debug, Default)]
+    struct Counters {
+        logins: AtomicUsize,
+        reads: AtomicUsize,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Login {
+        role_id: String,
+        secret_id: String,
+    }
+
+    async fn login(counters: web::Data<Counters>, body: web::Json<Login>) -> HttpResponse {
+        if body.role_id != "adm" || body.secret_id != "s3cret" {
+            return HttpResponse::BadRequest().finish();
+        }
+        let logins = counters.logins.fetch_add(1, Ordering::SeqCst) + 1;
+        HttpResponse::Ok().json(serde_json::json!({
+            "auth": {
+                "client_token": format!("token-{logins}"),
+                "lease_duration": 1,
+            },
+        }))
+    }
+
+    async fn read(counters: web::Data<Counters>, req: HttpRequest) -> HttpResponse {
+        // Only the latest token is valid.
+        let expected = format!("token-{}", counters.logins.load(Ordering::SeqCst));
+        let token = req.headers().get("X-Vault-Token");
+        if token.and_then(|token| token.to_str().ok()) != Some(expected.as_str()) {
+            return HttpResponse::Forbidden().finish();
+        }
+        counters.reads.fetch_add(1, Ordering::SeqCst);
+        HttpResponse::Ok().json(serde_json::json!({
+            "data": {
+                "data": {"DATABASE_URL": "postgres://db", "PORT": 8080, "LOCAL": "vault"},
+                "metadata": {"version": 3},
+            },
+        }))
+    }
+
+    /// Starts the stub server and the Vault actor using it in their own
+    /// system, like the server does.
+    fn start(counters: &Arc<Counters>, cache_ttl: Duration) -> (Client, System) {
+        let counters = counters.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let system = System::new("vault");
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::from(counters.clone()))
+                    .route("/v1/auth/approle/login", web::post().to(login))
+                    .route("/v1/secret/data/app", web::get().to(read))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind("127.0.0.1:0")
+            .unwrap();
+            let addr = server.addrs()[0];
+            server.run();
+            let vault = Vault::new(Config {
+                addr: Some(format!("http://{addr}")),
+                token: None,
+                role_id: Some("adm".to_owned()),
+                secret_id: Some("s3cret".into()),
+                mount: default_mount(),
+                cache_ttl: Some(cache_ttl),
+            })
+            .unwrap()
+            .unwrap()
+            .start();
+            tx.send((vault, System::current())).unwrap();
+            system.run()
+        });
+        let (vault, system) = rx.recv().unwrap();
+        (Client::new(vault), system)
+    }
+
+    fn vault_secrets(required: &[&str]) -> VaultSecrets {
+        VaultSecrets {
+            path: "app".to_owned(),
+            required: required.iter().map(|&name| name.to_owned()).collect(),
+        }
+    }
+
+    #[test]
+    fn approle_token_is_cached_until_lease_ends() {
+        let counters = Arc::new(Counters::default());
+        let (client, system) = start(&counters, Duration::from_secs(0));
+
+        let secrets = client.fetch("app").unwrap();
+        assert_eq!(secrets["DATABASE_URL"].unsecure(), "postgres://db");
+        assert_eq!(secrets["PORT"].unsecure(), "8080");
+        client.fetch("app").unwrap();
+        assert_eq!(counters.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(counters.reads.load(Ordering::SeqCst), 2);
+
+        // The token is renewed once 90% of its lease is over.
+        thread::sleep(Duration::from_secs(1));
+        client.fetch("app").unwrap();
+        assert_eq!(counters.logins.load(Ordering::SeqCst), 2);
+        assert_eq!(counters.reads.load(Ordering::SeqCst), 3);
+        system.stop();
+    }
+
+    #[test]
+    fn secrets_are_cached() {
+        let counters = Arc::new(Counters::default());
+        let (client, system) = start(&counters, Duration::from_secs(60));
+
+        client.fetch("app").unwrap();
+        client.fetch("app").unwrap();
+        assert_eq!(counters.reads.load(Ordering::SeqCst), 1);
+        system.stop();
+    }
+
+    #[test]
+    fn required_keys() {
+        let counters = Arc::new(Counters::default());
+        let (client, system) = start(&counters, Duration::from_secs(60));
+
+        let mut secrets = Secrets::new();
+        secrets.insert("LOCAL".to_owned(), "local".into());
+        client
+            .fetch_into(&mut secrets, &vault_secrets(&["DATABASE_URL", "LOCAL"]))
+            .unwrap();
+        assert_eq!(secrets["DATABASE_URL"].unsecure(), "postgres://db");
+        // Locally stored secrets take precedence.
+        assert_eq!(secrets["LOCAL"].unsecure(), "local");
+
+        let err = client
+            .fetch_into(
+                &mut Secrets::new(),
+                &vault_secrets(&["DATABASE_URL", "API_KEY"]),
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "required secret API_KEY is missing from Vault path app"
+        );
+        system.stop();
+    }
+}